@@ -0,0 +1,73 @@
+use std::process::ExitCode;
+
+use bark_bot::bt::{
+    ingest::{default_batch_size, ingest_path, ChunkStrategy},
+    BarkModel, BarkModelConfig,
+};
+
+const USAGE: &str = "Usage: ingest <db[#collection]> <path>... [--chunking paragraph|heading|window:SIZE:OVERLAP] [--batch-size N] [--embedding-model NAME] [--config FILE]";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1);
+    let mut positional = vec![];
+    let mut chunking = ChunkStrategy::default();
+    let mut batch_size = default_batch_size();
+    let mut embedding_model = None;
+    let mut config_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--chunking" => {
+                chunking = args
+                    .next()
+                    .expect(USAGE)
+                    .parse()
+                    .unwrap_or_else(|err| panic!("{}", err))
+            }
+            "--batch-size" => {
+                batch_size = args
+                    .next()
+                    .expect(USAGE)
+                    .parse()
+                    .expect("Failed to parse batch size")
+            }
+            "--embedding-model" => embedding_model = Some(args.next().expect(USAGE)),
+            "--config" => config_path = Some(args.next().expect(USAGE)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.len() < 2 {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+    let db = positional.remove(0);
+    let model_config = config_path
+        .map(|s| {
+            let config_str = std::fs::read_to_string(s).expect("Failed to read model config file");
+            serde_json::from_str(&config_str).expect("Failed to parse model config")
+        })
+        .unwrap_or_else(|| BarkModelConfig::get_from_env());
+    let model = BarkModel::new(model_config, ".".to_string()).await;
+    for path in positional {
+        match ingest_path(
+            model.clone(),
+            embedding_model.clone(),
+            db.clone(),
+            path.clone(),
+            chunking.clone(),
+            batch_size,
+            None,
+        )
+        .await
+        {
+            Ok((pushed, _)) => println!("{}: {} chunks", path, pushed),
+            Err(err) => {
+                eprintln!("{}", err);
+                return ExitCode::FAILURE;
+            }
+        }
+    }
+    ExitCode::SUCCESS
+}
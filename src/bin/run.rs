@@ -42,6 +42,7 @@ async fn main() -> ExitCode {
     // let mut audit = Some(Default::default());
     let mut audit = Some(BehaviorTreeAudit::data_only(PrintlnDataLogger)); // Disable audit for now, can be enabled later if needed
     let model = bark_bot::bt::BarkModel::new(model_config, tree_root).await;
+    controller.attach_mcp(&model);
     let mut state = tree.resume_with(&model, &mut controller, &mut gas, &mut audit);
     while state == BarkState::Waiting {
         state = tree.resume_with(&model, &mut controller, &mut gas, &mut audit);
@@ -0,0 +1,162 @@
+use std::process::ExitCode;
+
+use bark_bot::bt::{
+    ingest::{default_batch_size, export_entries, import_path},
+    vector::{
+        count_entries, list_collections, list_entries, open_collection, open_read_only,
+        parse_vector_path, register_vec_extension, VectorError,
+    },
+    BarkModel, BarkModelConfig,
+};
+
+const USAGE: &str = "Usage:
+  vectordb collections <db[#collection]>
+  vectordb count <db[#collection]>
+  vectordb list <db[#collection]> [--offset N] [--limit N]
+  vectordb export <db[#collection]> [out.jsonl] [--vectors]
+  vectordb import <db[#collection]> <in.jsonl> [--batch-size N] [--embedding-model NAME] [--config FILE]";
+
+#[tokio::main]
+async fn main() -> ExitCode {
+    env_logger::init();
+    register_vec_extension();
+
+    let mut args = std::env::args().skip(1);
+    let Some(command) = args.next() else {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    };
+    let mut positional = vec![];
+    let mut offset = 0;
+    let mut limit = None;
+    let mut vectors = false;
+    let mut batch_size = default_batch_size();
+    let mut embedding_model = None;
+    let mut config_path = None;
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--offset" => {
+                offset = args
+                    .next()
+                    .expect(USAGE)
+                    .parse()
+                    .expect("Failed to parse offset")
+            }
+            "--limit" => {
+                limit = Some(
+                    args.next()
+                        .expect(USAGE)
+                        .parse()
+                        .expect("Failed to parse limit"),
+                )
+            }
+            "--vectors" => vectors = true,
+            "--batch-size" => {
+                batch_size = args
+                    .next()
+                    .expect(USAGE)
+                    .parse()
+                    .expect("Failed to parse batch size")
+            }
+            "--embedding-model" => embedding_model = Some(args.next().expect(USAGE)),
+            "--config" => config_path = Some(args.next().expect(USAGE)),
+            _ => positional.push(arg),
+        }
+    }
+    if positional.is_empty() {
+        eprintln!("{}", USAGE);
+        return ExitCode::FAILURE;
+    }
+    let db = positional.remove(0);
+    let result = match command.as_str() {
+        "collections" => parse_vector_path(&db)
+            .and_then(|(path, collection)| {
+                let connection = open_read_only(&path)?;
+                let mut collections = list_collections(&connection)?;
+                if db.contains('#') {
+                    collections.retain(|(name, _)| *name == collection.name);
+                    if collections.is_empty() {
+                        return Err(VectorError::MissingCollection(collection.name));
+                    }
+                }
+                Ok(collections)
+            })
+            .map_err(|err| err.to_string())
+            .map(|collections| {
+                for (name, metadata) in collections {
+                    println!(
+                        "{}\t{}\t{}\t{}",
+                        name,
+                        metadata.model.as_deref().unwrap_or("?"),
+                        metadata.dimension,
+                        metadata.metric.name()
+                    );
+                }
+            }),
+        "count" => open_collection(&db)
+            .map_err(|err| err.to_string())
+            .and_then(|(connection, collection)| {
+                count_entries(&connection, &collection).map_err(|err| err.to_string())
+            })
+            .map(|count| println!("{}", count)),
+        "list" => open_collection(&db)
+            .map_err(|err| err.to_string())
+            .and_then(|(connection, collection)| {
+                list_entries(&connection, &collection, offset, limit, false)
+                    .map_err(|err| err.to_string())
+            })
+            .map(|entries| {
+                for entry in entries {
+                    let key_values = entry
+                        .key_values
+                        .iter()
+                        .map(|(key, value)| format!("{}={}", key, value))
+                        .collect::<Vec<_>>()
+                        .join(", ");
+                    println!(
+                        "{}\t{}\t{}\t{:?}",
+                        entry.id.unwrap_or_default(),
+                        entry.updated_at.unwrap_or_default(),
+                        key_values,
+                        entry.text
+                    );
+                }
+            }),
+        "export" => open_collection(&db)
+            .map_err(|err| err.to_string())
+            .and_then(|(connection, collection)| match positional.first() {
+                Some(out) => std::fs::File::create(out)
+                    .map_err(|err| format!("Failed to create {}: {}", out, err))
+                    .and_then(|mut file| {
+                        export_entries(&connection, &collection, &mut file, vectors)
+                    }),
+                None => export_entries(&connection, &collection, &mut std::io::stdout(), vectors),
+            })
+            .map(|exported| eprintln!("{} entries", exported)),
+        "import" => {
+            let Some(path) = positional.first().cloned() else {
+                eprintln!("{}", USAGE);
+                return ExitCode::FAILURE;
+            };
+            let model_config = config_path
+                .map(|s| {
+                    let config_str =
+                        std::fs::read_to_string(s).expect("Failed to read model config file");
+                    serde_json::from_str(&config_str).expect("Failed to parse model config")
+                })
+                .unwrap_or_else(|| BarkModelConfig::get_from_env());
+            let model = BarkModel::new(model_config, ".".to_string()).await;
+            import_path(model, embedding_model, db, path.clone(), batch_size, None)
+                .await
+                .map(|(imported, _)| println!("{}: {} entries", path, imported))
+        }
+        _ => Err(USAGE.to_string()),
+    };
+    match result {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("{}", err);
+            ExitCode::FAILURE
+        }
+    }
+}
@@ -1,3 +1,10 @@
+use std::sync::Arc;
+
+use tokio::task::JoinHandle;
+
+use crate::bt::vector::{EntrySelector, KeyValuePredicate, MemoryScope, VectorMatch};
+use crate::bt::McpRunSource;
+
 use crate::prelude::*;
 
 #[derive(Default, Debug, Clone, Serialize)]
@@ -6,6 +13,11 @@ pub struct BarkController {
     pub embedding_variables: HashMap<VariableId, Vec<f32>>,
     pub prompts: HashMap<VariableId, Vec<BarkMessage>>,
     pub templates: HashMap<VariableId, Vec<MessageValue>>,
+    #[serde(skip)]
+    pub mcp: Option<Arc<dyn McpSource>>,
+    /// The `mem:` vector databases of this run, dropped with its last controller.
+    #[serde(skip)]
+    pub memory: MemoryScope,
 }
 
 impl BarkController {
@@ -20,7 +32,86 @@ impl BarkController {
             prompts: HashMap::new(),
             embedding_variables: HashMap::new(),
             templates: HashMap::new(),
+            mcp: None,
+            memory: MemoryScope::default(),
+        }
+    }
+
+    /// Lets `McpPrompt` and `McpResource` values fetch through the model's MCP services.
+    /// What they fetch is cached for as long as this controller lives.
+    pub fn attach_mcp<TC: ToolCaller>(&mut self, model: &BarkModel<TC>) {
+        self.mcp = Some(Arc::new(McpRunSource::new(model.clone())));
+    }
+
+    /// Resolves a value without waiting on MCP. While prompts or resources it needs are
+    /// fetched in the background through `fetch`, this returns `None`, and the caller should
+    /// wait and resolve it again.
+    pub fn resolve_mcp<T>(
+        &self,
+        fetch: &mut Option<JoinHandle<Result<(), String>>>,
+        resolve: impl Fn(&Self) -> Result<T, String>,
+    ) -> Option<Result<T, String>> {
+        if let Some(join_handle) = fetch {
+            match try_join(join_handle) {
+                Ok(fetched) => {
+                    *fetch = None;
+                    if let Err(err) = fetched {
+                        return Some(Err(format!("Error fetching from MCP: {}", err)));
+                    }
+                }
+                Err(false) => return None,
+                Err(true) => {
+                    *fetch = None;
+                    return Some(Err("Fetching from MCP failed to join".to_string()));
+                }
+            }
+        }
+        let resolved = resolve(self);
+        if resolved.is_err() {
+            if let Some(join_handle) = self.mcp.as_ref().and_then(|mcp| mcp.fetch_pending()) {
+                *fetch = Some(join_handle);
+                return None;
+            }
         }
+        Some(resolved)
+    }
+
+    /// Resolves a value, blocking on whatever it needs fetched from MCP.
+    fn resolve_blocking<T>(
+        &self,
+        resolve: impl Fn(&Self) -> Result<T, String>,
+    ) -> Result<T, String> {
+        loop {
+            let resolved = resolve(self);
+            let Some(join_handle) = resolved
+                .is_err()
+                .then(|| self.mcp.as_ref().and_then(|mcp| mcp.fetch_pending()))
+                .flatten()
+            else {
+                return resolved;
+            };
+            block_on_runtime(join_handle)?
+                .map_err(|err| format!("Fetching from MCP failed to join: {}", err))?
+                .map_err(|err| format!("Error fetching from MCP: {}", err))?;
+        }
+    }
+
+    /// The path the model knows a vector database by. `mem:` databases are scoped to this run.
+    pub fn scoped_db_path<TC: ToolCaller>(&self, model: &BarkModel<TC>, path: &str) -> String {
+        self.memory.scoped_path(model.vector_stores(), path)
+    }
+
+    /// Snapshots the user variables for native tools to read and write.
+    pub fn native_tool_context(&self) -> NativeToolContext {
+        NativeToolContext::new(
+            self.text_variables
+                .iter()
+                .filter_map(|(id, value)| match id {
+                    VariableId::User(name) => Some((name.clone(), value.clone())),
+                    _ => None,
+                })
+                .collect(),
+        )
     }
 
     /// Replaces template variables in the given line.
@@ -156,21 +247,35 @@ impl BarkController {
             prompts: HashMap::new(),
             templates,
             embedding_variables: HashMap::new(),
+            mcp: None,
+            memory: MemoryScope::default(),
         }
     }
 
+    /// Resolves a prompt, blocking on any MCP prompts and resources it needs which were not
+    /// fetched yet. Nodes which can wait should use [`Self::resolve_mcp`] instead.
     pub fn get_prompt(&self, prompt: &PromptValue) -> Vec<BarkMessage> {
-        match prompt {
+        self.resolve_blocking(|controller| controller.try_get_prompt(prompt))
+            .unwrap_or_else(|err| {
+                log::warn!("{}", err);
+                vec![]
+            })
+    }
+
+    /// Like [`Self::get_prompt`], but fails instead of blocking when an MCP prompt or resource
+    /// is not fetched yet, recording it for [`McpSource::fetch_pending`].
+    pub fn try_get_prompt(&self, prompt: &PromptValue) -> Result<Vec<BarkMessage>, String> {
+        Ok(match prompt {
             PromptValue::Variable(id) => self.prompts.get(id).cloned().unwrap_or(vec![]),
             PromptValue::Quick(s) => vec![user(s)],
             PromptValue::TemplateFile(text_value) => {
-                let text = self.get_text(text_value);
+                let text = self.try_get_text(text_value)?;
                 if text.ends_with(".json") {
                     // Assuming the file contains a JSON array of MessageValue
                     match std::fs::read_to_string(&text)
                         .map(|s| serde_json::from_str::<Vec<MessageValue>>(&s))
                     {
-                        Ok(Ok(messages)) => self.get_prompt(&PromptValue::Chat(messages)),
+                        Ok(Ok(messages)) => self.try_get_prompt(&PromptValue::Chat(messages))?,
                         Ok(Err(e)) => {
                             // eprintln!("Error parsing template file '{}': {}", text, e);
                             vec![]
@@ -182,7 +287,7 @@ impl BarkController {
                     }
                 } else {
                     match std::fs::read_to_string(&text).map(|s| self.template_from_str(&s)) {
-                        Ok(template) => self.get_prompt(&PromptValue::Chat(template)),
+                        Ok(template) => self.try_get_prompt(&PromptValue::Chat(template))?,
                         Err(e) => {
                             // eprintln!("Error reading template file '{}': {}", text, e);
                             vec![]
@@ -192,7 +297,7 @@ impl BarkController {
             }
             PromptValue::Template(var) => {
                 if let Some(template) = self.templates.get(var) {
-                    self.get_prompt(&PromptValue::Chat(template.clone()))
+                    self.try_get_prompt(&PromptValue::Chat(template.clone()))?
                 } else {
                     // eprintln!("Template not found: {:?}", var);
                     vec![]
@@ -223,10 +328,12 @@ impl BarkController {
                                 String::new()
                             }),
                         )),
-                        MessageValue::UserVal(text) => chat.push(user(&self.get_text(text))),
-                        MessageValue::SystemVal(text) => chat.push(system(&self.get_text(text))),
+                        MessageValue::UserVal(text) => chat.push(user(&self.try_get_text(text)?)),
+                        MessageValue::SystemVal(text) => {
+                            chat.push(system(&self.try_get_text(text)?))
+                        }
                         MessageValue::AssistantVal(text) => {
-                            chat.push(assistant(&self.get_text(text)))
+                            chat.push(assistant(&self.try_get_text(text)?))
                         }
                         MessageValue::SubPrompt(id) => {
                             if let Some(sub_prompt) = self.prompts.get(id) {
@@ -236,7 +343,7 @@ impl BarkController {
                         MessageValue::Template(id) => {
                             if let Some(template) = self.templates.get(id) {
                                 let mut sub_prompt =
-                                    self.get_prompt(&PromptValue::Chat(template.clone()));
+                                    self.try_get_prompt(&PromptValue::Chat(template.clone()))?;
                                 chat.append(&mut sub_prompt);
                             } else {
                                 // eprintln!("Template not found: {:?}", id);
@@ -249,15 +356,42 @@ impl BarkController {
             PromptValue::Joined(prompts) => {
                 let mut chat = vec![];
                 for prompt in prompts {
-                    chat.extend(self.get_prompt(prompt));
+                    chat.extend(self.try_get_prompt(prompt)?);
                 }
                 chat
             }
-        }
+            PromptValue::McpPrompt {
+                service,
+                name,
+                arguments,
+            } => {
+                let Some(mcp) = &self.mcp else {
+                    return Err(format!("No MCP source attached for prompt: {}", name));
+                };
+                let arguments = arguments
+                    .iter()
+                    .map(|(key, value)| self.try_get_text(value).map(|value| (key.clone(), value)))
+                    .collect::<Result<_, String>>()?;
+                mcp.fetch_prompt(service, name, arguments)
+                    .map_err(|e| format!("Error fetching MCP prompt '{}': {}", name, e))?
+            }
+        })
     }
 
+    /// Resolves a text, blocking on any MCP resources it needs which were not fetched yet.
+    /// Nodes which can wait should use [`Self::resolve_mcp`] instead.
     pub fn get_text(&self, text: &TextValue) -> String {
-        match text {
+        self.resolve_blocking(|controller| controller.try_get_text(text))
+            .unwrap_or_else(|err| {
+                log::warn!("{}", err);
+                String::new()
+            })
+    }
+
+    /// Like [`Self::get_text`], but fails instead of blocking when an MCP resource is not
+    /// fetched yet, recording it for [`McpSource::fetch_pending`].
+    pub fn try_get_text(&self, text: &TextValue) -> Result<String, String> {
+        Ok(match text {
             TextValue::Variable(id) => self.text_variables.get(id).cloned().unwrap_or_else(|| {
                 // eprintln!("User variable not found: {:?}", id);
                 String::new()
@@ -275,9 +409,9 @@ impl BarkController {
                 if text.contains("<think>") && text.contains("</think>") {
                     let start = text.find("<think>").unwrap() + 7;
                     let end = text.find("</think>").unwrap();
-                    return text[start..end].to_string();
+                    text[start..end].to_string()
                 } else {
-                    return String::new();
+                    String::new()
                 }
             }
             TextValue::WithoutThoughts(id) => {
@@ -288,15 +422,79 @@ impl BarkController {
                 strip_thoughts(&text)
             }
             TextValue::Simple(s) => s.clone(),
-            TextValue::Multi(texts) => texts.iter().map(|t| self.get_text(t)).collect(),
+            TextValue::Multi(texts) => texts
+                .iter()
+                .map(|t| self.try_get_text(t))
+                .collect::<Result<String, String>>()?,
             TextValue::Structured(s) => {
                 let mut output = HashMap::new();
                 for (key, value) in s {
-                    output.insert(key.clone(), self.get_text(value));
+                    output.insert(key.clone(), self.try_get_text(value)?);
                 }
                 serde_json::to_string(&output).unwrap()
             }
+            TextValue::McpResource { service, uri } => {
+                let Some(mcp) = &self.mcp else {
+                    return Err(format!("No MCP source attached for resource: {:?}", uri));
+                };
+                let uri = self.try_get_text(uri)?;
+                mcp.fetch_resource(service, &uri)
+                    .map_err(|e| format!("Error reading MCP resource '{}': {}", uri, e))?
+            }
+        })
+    }
+
+    pub fn get_key_value_predicates(&self, filters: &[KeyValueFilter]) -> Vec<KeyValuePredicate> {
+        filters
+            .iter()
+            .map(|filter| match filter {
+                KeyValueFilter::Equals(key, value) => KeyValuePredicate::Equals {
+                    key: self.get_text(key),
+                    value: self.get_text(value),
+                },
+                KeyValueFilter::In(key, values) => KeyValuePredicate::In {
+                    key: self.get_text(key),
+                    values: values.iter().map(|value| self.get_text(value)).collect(),
+                },
+                KeyValueFilter::Prefix(key, prefix) => KeyValuePredicate::Prefix {
+                    key: self.get_text(key),
+                    prefix: self.get_text(prefix),
+                },
+            })
+            .collect()
+    }
+
+    /// Resolves an entry filter, or `None` if its id is not a number.
+    pub fn get_entry_selector(&self, filter: &EntryFilter) -> Option<EntrySelector> {
+        Some(match filter {
+            EntryFilter::Text(text) => EntrySelector::Text(self.get_text(text)),
+            EntryFilter::Id(id) => EntrySelector::Id(self.get_text(id).trim().parse().ok()?),
+            EntryFilter::KeyValues(filters) => {
+                EntrySelector::KeyValues(self.get_key_value_predicates(filters))
+            }
+            EntryFilter::OlderThan(seconds) => EntrySelector::OlderThan(*seconds),
+        })
+    }
+
+    /// Binds the key/values of a vector match as user variables, clearing those of `previous`.
+    /// The match's `distance` and `similarity` are bound as well.
+    pub fn bind_vector_match(&mut self, previous: Option<&VectorMatch>, current: &VectorMatch) {
+        if let Some(previous) = previous {
+            for (key, _) in &previous.key_values {
+                self.text_variables.remove(&VariableId::User(key.clone()));
+            }
         }
+        for (key, value) in current.variables() {
+            self.text_variables.insert(VariableId::User(key), value);
+        }
+        self.text_variables.insert(
+            VariableId::User("distance".to_string()),
+            current.distance.to_string(),
+        );
+        self.text_variables.insert(
+            VariableId::User("similarity".to_string()),
+            current.similarity().to_string(),
+        );
     }
 
     pub fn text_matches(&self, text: &TextValue, matcher: &TextMatcher) -> bool {
@@ -357,6 +555,17 @@ pub fn strip_thoughts(text: &String) -> String {
     }
 }
 
+/// Blocks the current thread on a future, without stalling the other tasks of the runtime.
+/// This needs a multi-threaded runtime, since a current-thread one cannot block in place.
+fn block_on_runtime<F: std::future::Future>(future: F) -> Result<F::Output, String> {
+    let handle = tokio::runtime::Handle::try_current()
+        .map_err(|e| format!("No runtime to fetch from MCP: {}", e))?;
+    if handle.runtime_flavor() != tokio::runtime::RuntimeFlavor::MultiThread {
+        return Err("Fetching from MCP needs a multi-threaded runtime".to_string());
+    }
+    Ok(tokio::task::block_in_place(|| handle.block_on(future)))
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -619,4 +828,95 @@ mod tests {
         let replaced = controller.replace_template_variables(line);
         assert_eq!(replaced, "<<WARNING:LOOP>>");
     }
+
+    #[test]
+    fn test_mcp_values_without_source() {
+        let controller = BarkController::new();
+        let resource = TextValue::Multi(vec![
+            TextValue::Simple("Readme: ".to_string()),
+            TextValue::McpResource {
+                service: "filesystem".to_string(),
+                uri: Box::new(TextValue::Simple("file:///README.md".to_string())),
+            },
+        ]);
+        assert!(controller.try_get_text(&resource).is_err());
+        assert_eq!(controller.get_text(&resource), "");
+        let prompt = PromptValue::Joined(vec![
+            PromptValue::Quick("Hello".to_string()),
+            PromptValue::McpPrompt {
+                service: "everything".to_string(),
+                name: "simple_prompt".to_string(),
+                arguments: HashMap::new(),
+            },
+        ]);
+        assert!(controller.try_get_prompt(&prompt).is_err());
+        assert_eq!(
+            controller
+                .try_get_prompt(&PromptValue::Quick("Hello".to_string()))
+                .map(|prompt| prompt.len()),
+            Ok(1)
+        );
+    }
+
+    /// Fetches any resource it is asked for, as `contents of <uri>`.
+    #[derive(Debug, Default)]
+    struct FetchingMcp {
+        resources: std::sync::Mutex<HashMap<String, String>>,
+        pending: std::sync::Mutex<Vec<String>>,
+    }
+
+    impl McpSource for FetchingMcp {
+        fn fetch_prompt(
+            &self,
+            service: &str,
+            prompt_name: &str,
+            _arguments: HashMap<String, String>,
+        ) -> Result<Vec<BarkMessage>, String> {
+            Err(format!(
+                "Prompt {}/{} is not available",
+                service, prompt_name
+            ))
+        }
+
+        fn fetch_resource(&self, _service: &str, uri: &str) -> Result<String, String> {
+            if let Some(text) = self.resources.lock().unwrap().get(uri) {
+                return Ok(text.clone());
+            }
+            self.pending.lock().unwrap().push(uri.to_string());
+            Err(format!("{} has not been fetched yet", uri))
+        }
+
+        fn fetch_pending(&self) -> Option<JoinHandle<Result<(), String>>> {
+            let pending = std::mem::take(&mut *self.pending.lock().unwrap());
+            if pending.is_empty() {
+                return None;
+            }
+            let mut resources = self.resources.lock().unwrap();
+            for uri in pending {
+                resources.insert(uri.clone(), format!("contents of {}", uri));
+            }
+            Some(tokio::spawn(async { Ok(()) }))
+        }
+    }
+
+    #[tokio::test]
+    async fn test_resolve_mcp_waits_for_fetch() {
+        let mut controller = BarkController::new();
+        controller.mcp = Some(Arc::new(FetchingMcp::default()));
+        let resource = TextValue::McpResource {
+            service: "filesystem".to_string(),
+            uri: Box::new(TextValue::Simple("file:///README.md".to_string())),
+        };
+        let mut fetch = None;
+        let resolve = |controller: &BarkController| controller.try_get_text(&resource);
+        assert_eq!(controller.resolve_mcp(&mut fetch, resolve), None);
+        while !fetch.as_ref().unwrap().is_finished() {
+            tokio::task::yield_now().await;
+        }
+        assert_eq!(
+            controller.resolve_mcp(&mut fetch, resolve),
+            Some(Ok("contents of file:///README.md".to_string()))
+        );
+        assert!(fetch.is_none());
+    }
 }
@@ -0,0 +1,127 @@
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{Connection, OptionalExtension};
+use sha2::{Digest, Sha256};
+use zerocopy::AsBytes;
+
+use super::vector::decode_embedding;
+
+/// Embeddings stored on disk, keyed by embedding model and a hash of the embedded text.
+/// Clones share one connection, opened on first use. Its methods block, so async callers
+/// should run them on a blocking thread.
+#[derive(Debug, Clone)]
+pub struct EmbeddingCache {
+    path: String,
+    connection: Arc<Mutex<Option<Connection>>>,
+}
+
+pub fn text_hash(text: &str) -> String {
+    Sha256::digest(text.as_bytes())
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+impl EmbeddingCache {
+    pub fn new(path: impl ToString) -> Self {
+        Self {
+            path: path.to_string(),
+            connection: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Runs `f` on the shared connection, opening it and creating the table if need be.
+    fn with_db<T>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T>,
+    ) -> rusqlite::Result<T> {
+        let mut connection = self.connection.lock().unwrap();
+        if connection.is_none() {
+            let db = Connection::open(&self.path)?;
+            db.execute(
+                "create table if not exists embedding_cache (model text not null, hash text not null, embedding blob not null, primary key (model, hash))",
+                [],
+            )?;
+            *connection = Some(db);
+        }
+        f(connection.as_mut().unwrap())
+    }
+
+    /// Looks up each text, returning `None` for those which were never cached.
+    pub fn get_many(
+        &self,
+        model: &str,
+        texts: &[String],
+    ) -> rusqlite::Result<Vec<Option<Vec<f32>>>> {
+        self.with_db(|db| {
+            let mut stmt = db.prepare_cached(
+                "select embedding from embedding_cache where model = ? and hash = ?",
+            )?;
+            let embeddings = texts
+                .iter()
+                .map(|text| {
+                    stmt.query_row(rusqlite::params![model, text_hash(text)], |row| {
+                        row.get::<_, Vec<u8>>(0)
+                    })
+                    .optional()
+                    .map(|bytes| bytes.map(|bytes| decode_embedding(&bytes)))
+                })
+                .collect();
+            embeddings
+        })
+    }
+
+    pub fn put_many(
+        &self,
+        model: &str,
+        texts: &[String],
+        embeddings: &[Vec<f32>],
+    ) -> rusqlite::Result<()> {
+        self.with_db(|db| {
+            let transaction = db.transaction()?;
+            {
+                let mut stmt = transaction.prepare_cached(
+                    "insert or replace into embedding_cache (model, hash, embedding) values (?, ?, ?)",
+                )?;
+                for (text, embedding) in texts.iter().zip(embeddings) {
+                    stmt.execute(rusqlite::params![
+                        model,
+                        text_hash(text),
+                        embedding.as_bytes()
+                    ])?;
+                }
+            }
+            transaction.commit()
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_cache_round_trip() {
+        let path = std::env::temp_dir().join(format!("bark-cache-{}.db", std::process::id()));
+        let cache = EmbeddingCache::new(path.to_string_lossy());
+        let texts = vec!["a".to_string(), "b".to_string()];
+        cache
+            .put_many("model-a", &texts[..1], &[vec![0.5, -1.0]])
+            .unwrap();
+        assert_eq!(
+            cache.get_many("model-a", &texts).unwrap(),
+            vec![Some(vec![0.5, -1.0]), None]
+        );
+        assert_eq!(cache.get_many("model-b", &texts).unwrap(), vec![None, None]);
+        drop(cache);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_text_hash() {
+        assert_eq!(
+            text_hash("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+    }
+}
@@ -0,0 +1,523 @@
+use std::io::Write;
+use std::path::Path;
+
+use rusqlite::Connection;
+use serde::{Deserialize, Serialize};
+
+use super::vector::{list_entries, Collection, StoredEntry};
+use super::BarkModel;
+use crate::clients::ToolCaller;
+
+/// How a document is split into the texts which are embedded.
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+pub enum ChunkStrategy {
+    /// One chunk per run of non-blank lines.
+    #[default]
+    Paragraph,
+    /// One chunk per markdown section, from a heading up to the next heading.
+    Heading,
+    /// Windows of `size` words, each repeating the last `overlap` words of the one before.
+    Window { size: usize, overlap: usize },
+}
+
+impl std::str::FromStr for ChunkStrategy {
+    type Err = String;
+
+    /// Parses `paragraph`, `heading` or `window:SIZE:OVERLAP`.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.split(':').collect::<Vec<_>>().as_slice() {
+            ["paragraph"] => Ok(ChunkStrategy::Paragraph),
+            ["heading"] => Ok(ChunkStrategy::Heading),
+            ["window", size] => Ok(ChunkStrategy::Window {
+                size: size
+                    .parse()
+                    .map_err(|_| format!("Bad window size: {}", size))?,
+                overlap: 0,
+            }),
+            ["window", size, overlap] => Ok(ChunkStrategy::Window {
+                size: size
+                    .parse()
+                    .map_err(|_| format!("Bad window size: {}", size))?,
+                overlap: overlap
+                    .parse()
+                    .map_err(|_| format!("Bad window overlap: {}", overlap))?,
+            }),
+            _ => Err(format!("Unknown chunking strategy: {}", s)),
+        }
+    }
+}
+
+/// A piece of a document, with its byte offsets in the document.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Chunk {
+    pub index: usize,
+    pub start: usize,
+    pub end: usize,
+    pub text: String,
+}
+
+impl Chunk {
+    fn new(text: &str, index: usize, start: usize, end: usize) -> Self {
+        Self {
+            index,
+            start,
+            end,
+            text: text[start..end].to_string(),
+        }
+    }
+
+    /// The key/values stored alongside the chunk's embedding.
+    pub fn key_values(&self, source: &str) -> Vec<(String, String)> {
+        vec![
+            ("source".to_string(), source.to_string()),
+            ("chunk".to_string(), self.index.to_string()),
+            ("start".to_string(), self.start.to_string()),
+            ("end".to_string(), self.end.to_string()),
+        ]
+    }
+}
+
+/// The byte spans of each line, without their line endings.
+fn line_spans(text: &str) -> Vec<(usize, usize)> {
+    let mut spans = vec![];
+    let mut start = 0;
+    for line in text.split_inclusive('\n') {
+        let content = line.trim_end_matches(['\n', '\r']);
+        spans.push((start, start + content.len()));
+        start += line.len();
+    }
+    spans
+}
+
+/// Trims whitespace from a span, returning `None` if nothing is left.
+fn trim_span(text: &str, start: usize, end: usize) -> Option<(usize, usize)> {
+    let span = &text[start..end];
+    let trimmed = span.trim();
+    if trimmed.is_empty() {
+        return None;
+    }
+    let start = start + (span.len() - span.trim_start().len());
+    Some((start, start + trimmed.len()))
+}
+
+fn chunks_from_spans(text: &str, spans: Vec<(usize, usize)>) -> Vec<Chunk> {
+    spans
+        .into_iter()
+        .filter_map(|(start, end)| trim_span(text, start, end))
+        .enumerate()
+        .map(|(index, (start, end))| Chunk::new(text, index, start, end))
+        .collect()
+}
+
+pub fn chunk_text(text: &str, strategy: &ChunkStrategy) -> Vec<Chunk> {
+    match strategy {
+        ChunkStrategy::Paragraph => {
+            let mut spans = vec![];
+            let mut current: Option<(usize, usize)> = None;
+            for (start, end) in line_spans(text) {
+                if text[start..end].trim().is_empty() {
+                    spans.extend(current.take());
+                } else {
+                    current = Some((current.map_or(start, |(first, _)| first), end));
+                }
+            }
+            spans.extend(current);
+            chunks_from_spans(text, spans)
+        }
+        ChunkStrategy::Heading => {
+            let mut spans = vec![];
+            let mut section_start = 0;
+            for (start, _) in line_spans(text) {
+                if start > section_start && text[start..].starts_with('#') {
+                    spans.push((section_start, start));
+                    section_start = start;
+                }
+            }
+            spans.push((section_start, text.len()));
+            chunks_from_spans(text, spans)
+        }
+        ChunkStrategy::Window { size, overlap } => {
+            let size = (*size).max(1);
+            let step = size.saturating_sub(*overlap).max(1);
+            let mut words = vec![];
+            let mut word_start = None;
+            for (offset, c) in text.char_indices() {
+                match (c.is_whitespace(), word_start) {
+                    (true, Some(start)) => {
+                        words.push((start, offset));
+                        word_start = None;
+                    }
+                    (false, None) => word_start = Some(offset),
+                    _ => {}
+                }
+            }
+            if let Some(start) = word_start {
+                words.push((start, text.len()));
+            }
+            let mut chunks = vec![];
+            let mut first = 0;
+            while first < words.len() {
+                let last = (first + size).min(words.len()) - 1;
+                chunks.push(Chunk::new(
+                    text,
+                    chunks.len(),
+                    words[first].0,
+                    words[last].1,
+                ));
+                if last == words.len() - 1 {
+                    break;
+                }
+                first += step;
+            }
+            chunks
+        }
+    }
+}
+
+/// A text to ingest, and where it came from.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Document {
+    pub source: String,
+    pub text: String,
+}
+
+const INGESTED_EXTENSIONS: [&str; 4] = ["txt", "md", "markdown", "jsonl"];
+
+/// Reads the documents in a file, or in every text, markdown or JSONL file below a directory.
+///
+/// Each line of a JSONL file is its own document: either a string, or an object with a
+/// `text` field and an optional `source` field.
+pub fn read_documents(path: &Path) -> std::io::Result<Vec<Document>> {
+    if path.is_dir() {
+        let mut entries = std::fs::read_dir(path)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<std::io::Result<Vec<_>>>()?;
+        entries.sort();
+        let mut documents = vec![];
+        for entry in entries {
+            let ingested = entry
+                .extension()
+                .map(|extension| INGESTED_EXTENSIONS.contains(&&*extension.to_string_lossy()))
+                .unwrap_or(false);
+            if entry.is_dir() || ingested {
+                documents.extend(read_documents(&entry)?);
+            }
+        }
+        return Ok(documents);
+    }
+    let source = path.to_string_lossy().to_string();
+    let text = std::fs::read_to_string(path)?;
+    if path
+        .extension()
+        .map_or(false, |extension| extension == "jsonl")
+    {
+        return read_jsonl_documents(&source, &text);
+    }
+    Ok(vec![Document { source, text }])
+}
+
+fn read_jsonl_documents(source: &str, text: &str) -> std::io::Result<Vec<Document>> {
+    let mut documents = vec![];
+    for (line_number, line) in text.lines().enumerate() {
+        if line.trim().is_empty() {
+            continue;
+        }
+        let invalid = |message: String| {
+            std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("{}:{}: {}", source, line_number + 1, message),
+            )
+        };
+        let value: serde_json::Value =
+            serde_json::from_str(line).map_err(|err| invalid(err.to_string()))?;
+        let line_source = format!("{}:{}", source, line_number + 1);
+        let document = match value {
+            serde_json::Value::String(text) => Document {
+                source: line_source,
+                text,
+            },
+            serde_json::Value::Object(object) => Document {
+                text: object
+                    .get("text")
+                    .and_then(|text| text.as_str())
+                    .ok_or_else(|| invalid("Expected a \"text\" field".to_string()))?
+                    .to_string(),
+                source: object
+                    .get("source")
+                    .and_then(|source| source.as_str())
+                    .map(str::to_string)
+                    .unwrap_or(line_source),
+            },
+            _ => return Err(invalid("Expected a string or an object".to_string())),
+        };
+        documents.push(document);
+    }
+    Ok(documents)
+}
+
+pub fn default_batch_size() -> usize {
+    32
+}
+
+/// Chunks every document at `path`, embeds the chunks in batches and pushes them into `db`,
+/// returning how many chunks were pushed before running out of gas.
+pub async fn ingest_path<TC: ToolCaller>(
+    model: BarkModel<TC>,
+    embedding_model: Option<String>,
+    db: String,
+    path: String,
+    strategy: ChunkStrategy,
+    batch_size: usize,
+    mut gas: Option<i32>,
+) -> Result<(usize, Option<i32>), String> {
+    let documents = read_documents(Path::new(&path))
+        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let chunks = documents
+        .iter()
+        .flat_map(|document| {
+            chunk_text(&document.text, &strategy)
+                .into_iter()
+                .map(move |chunk| (document.source.as_str(), chunk))
+        })
+        .collect::<Vec<_>>();
+    let mut pushed = 0;
+    for batch in chunks.chunks(batch_size.max(1)) {
+        if gas.map_or(false, |gas| gas <= 0) {
+            break;
+        }
+        let texts = batch.iter().map(|(_, chunk)| chunk.text.clone()).collect();
+        let (embeddings, new_gas) = model
+            .clone()
+            .get_embeddings(embedding_model.clone(), texts, gas)
+            .await?;
+        gas = new_gas;
+        let entries = batch
+            .iter()
+            .zip(embeddings)
+            .map(|((source, chunk), embedding)| {
+                (chunk.text.clone(), embedding, chunk.key_values(source))
+            })
+            .collect();
+        let (embedding_model, db) = (embedding_model.clone(), db.clone());
+        pushed += model
+            .vector_task(move |model| {
+                model.push_embeddings(embedding_model.as_deref(), &db, entries, false)
+            })
+            .await
+            .map_err(|err| format!("Failed to push chunks of {}: {}", path, err))?;
+    }
+    Ok((pushed, gas))
+}
+
+/// Writes a collection as JSONL, one entry per line in the order they were stored.
+/// Ids and timestamps are left out, so that exports of the same entries are identical.
+pub fn export_entries(
+    db: &Connection,
+    collection: &Collection,
+    out: &mut impl Write,
+    with_embeddings: bool,
+) -> Result<usize, String> {
+    let entries = list_entries(db, collection, 0, None, with_embeddings)
+        .map_err(|err| format!("Failed to list {}: {}", collection.name, err))?;
+    for entry in &entries {
+        let entry = StoredEntry {
+            id: None,
+            created_at: None,
+            updated_at: None,
+            ..entry.clone()
+        };
+        let line = serde_json::to_string(&entry).map_err(|err| err.to_string())?;
+        writeln!(out, "{}", line).map_err(|err| err.to_string())?;
+    }
+    Ok(entries.len())
+}
+
+/// Parses exported entries, one JSON object per non-blank line.
+pub fn read_entries(text: &str) -> Result<Vec<StoredEntry>, String> {
+    text.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty())
+        .map(|(line_number, line)| {
+            serde_json::from_str(line).map_err(|err| format!("{}: {}", line_number + 1, err))
+        })
+        .collect()
+}
+
+/// Imports exported entries into `db`, replacing stored entries with the same text.
+/// Entries without an embedding are embedded in batches; the others are assumed to come
+/// from the embedding model used here.
+pub async fn import_path<TC: ToolCaller>(
+    model: BarkModel<TC>,
+    embedding_model: Option<String>,
+    db: String,
+    path: String,
+    batch_size: usize,
+    mut gas: Option<i32>,
+) -> Result<(usize, Option<i32>), String> {
+    let text = std::fs::read_to_string(&path)
+        .map_err(|err| format!("Failed to read {}: {}", path, err))?;
+    let entries = read_entries(&text).map_err(|err| format!("{}:{}", path, err))?;
+    let mut imported = 0;
+    for batch in entries.chunks(batch_size.max(1)) {
+        if gas.map_or(false, |gas| gas <= 0) {
+            break;
+        }
+        let missing = batch
+            .iter()
+            .filter(|entry| entry.embedding.is_none())
+            .map(|entry| entry.text.clone())
+            .collect::<Vec<_>>();
+        let mut embedded = if missing.is_empty() {
+            vec![]
+        } else {
+            let (embeddings, new_gas) = model
+                .clone()
+                .get_embeddings(embedding_model.clone(), missing, gas)
+                .await?;
+            gas = new_gas;
+            embeddings
+        }
+        .into_iter();
+        let mut entries = vec![];
+        for entry in batch {
+            let embedding = match &entry.embedding {
+                Some(embedding) => embedding.clone(),
+                None => embedded
+                    .next()
+                    .ok_or_else(|| "Missing embedding for imported entry".to_string())?,
+            };
+            entries.push((entry.text.clone(), embedding, entry.key_values.clone()));
+        }
+        let (embedding_model, db) = (embedding_model.clone(), db.clone());
+        imported += model
+            .vector_task(move |model| {
+                model.push_embeddings(embedding_model.as_deref(), &db, entries, true)
+            })
+            .await
+            .map_err(|err| format!("Failed to import {}: {}", path, err))?;
+    }
+    Ok((imported, gas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texts(chunks: &[Chunk]) -> Vec<&str> {
+        chunks.iter().map(|chunk| chunk.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_paragraph_chunks() {
+        let text = "First line\nstill first.\n\n  \nSecond.\r\n\r\nThird\n";
+        let chunks = chunk_text(text, &ChunkStrategy::Paragraph);
+        assert_eq!(
+            texts(&chunks),
+            vec!["First line\nstill first.", "Second.", "Third"]
+        );
+        for chunk in &chunks {
+            assert_eq!(&text[chunk.start..chunk.end], chunk.text);
+        }
+        assert_eq!(chunks[2].index, 2);
+    }
+
+    #[test]
+    fn test_heading_chunks() {
+        let text = "Intro\n# One\nBody one\n## Two\nBody two\n";
+        let chunks = chunk_text(text, &ChunkStrategy::Heading);
+        assert_eq!(
+            texts(&chunks),
+            vec!["Intro", "# One\nBody one", "## Two\nBody two"]
+        );
+        let chunks = chunk_text("# Only\ncontent", &ChunkStrategy::Heading);
+        assert_eq!(texts(&chunks), vec!["# Only\ncontent"]);
+    }
+
+    #[test]
+    fn test_window_chunks() {
+        let text = "a b c d e f g";
+        let strategy = ChunkStrategy::Window {
+            size: 3,
+            overlap: 1,
+        };
+        let chunks = chunk_text(text, &strategy);
+        assert_eq!(texts(&chunks), vec!["a b c", "c d e", "e f g"]);
+        assert_eq!((chunks[1].start, chunks[1].end), (4, 9));
+        let chunks = chunk_text("a  b", &strategy);
+        assert_eq!(texts(&chunks), vec!["a  b"]);
+        assert!(chunk_text("   ", &strategy).is_empty());
+    }
+
+    #[test]
+    fn test_parse_strategy() {
+        assert_eq!("heading".parse(), Ok(ChunkStrategy::Heading));
+        assert_eq!(
+            "window:200:50".parse(),
+            Ok(ChunkStrategy::Window {
+                size: 200,
+                overlap: 50
+            })
+        );
+        assert!("window:many".parse::<ChunkStrategy>().is_err());
+        assert!("sentences".parse::<ChunkStrategy>().is_err());
+    }
+
+    #[test]
+    fn test_export_round_trip() {
+        let db = crate::bt::vector::tests::open_test_db();
+        crate::bt::vector::tests::insert(&db, "a", [1.0, 0.0], &[("source", "x")]);
+        crate::bt::vector::tests::insert(&db, "b", [0.0, 1.0], &[]);
+        let mut out = vec![];
+        assert_eq!(
+            export_entries(&db, &Collection::default(), &mut out, false).unwrap(),
+            2
+        );
+        let exported = String::from_utf8(out).unwrap();
+        assert_eq!(
+            exported,
+            "{\"text\":\"a\",\"key_values\":[[\"source\",\"x\"]]}\n{\"text\":\"b\",\"key_values\":[]}\n"
+        );
+        let entries = read_entries(&exported).unwrap();
+        assert_eq!(entries[0].key_values[0].1, "x");
+        assert_eq!(entries[1].embedding, None);
+        let mut out = vec![];
+        export_entries(&db, &Collection::default(), &mut out, true).unwrap();
+        let entries = read_entries(&String::from_utf8(out).unwrap()).unwrap();
+        assert_eq!(entries[0].embedding, Some(vec![1.0, 0.0]));
+        assert!(read_entries("{\"key_values\": []}").is_err());
+    }
+
+    #[test]
+    fn test_read_documents() {
+        let dir = std::env::temp_dir().join(format!("bark-ingest-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("nested")).unwrap();
+        std::fs::write(dir.join("a.md"), "# A").unwrap();
+        std::fs::write(dir.join("image.png"), "not text").unwrap();
+        std::fs::write(
+            dir.join("nested").join("b.jsonl"),
+            "\"plain\"\n\n{\"text\": \"object\", \"source\": \"wiki\"}\n",
+        )
+        .unwrap();
+        let documents = read_documents(&dir).unwrap();
+        let sources = documents
+            .iter()
+            .map(|document| (document.source.clone(), document.text.as_str()))
+            .collect::<Vec<_>>();
+        let b = dir
+            .join("nested")
+            .join("b.jsonl")
+            .to_string_lossy()
+            .to_string();
+        assert_eq!(
+            sources,
+            vec![
+                (dir.join("a.md").to_string_lossy().to_string(), "# A"),
+                (format!("{}:1", b), "plain"),
+                ("wiki".to_string(), "object"),
+            ]
+        );
+        std::fs::write(dir.join("bad.jsonl"), "{\"title\": 1}").unwrap();
+        assert!(read_documents(&dir.join("bad.jsonl")).is_err());
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -7,7 +7,11 @@ pub use controller::*;
 pub use model::*;
 
 use crate::clients::ToolCaller;
+pub mod embedding_cache;
+pub mod ingest;
+pub mod rerank;
 pub mod values;
+pub mod vector;
 
 pub type BarkDef<TC> = BehaviorTreeDef<BarkNode<TC>, BarkWrapper<TC>>;
 
@@ -3,11 +3,11 @@ use anyhow::anyhow;
 use ollama_rs::{generation::embeddings::request::GenerateEmbeddingsRequest, Ollama};
 
 use openai_api_rs::v1::embedding::EmbeddingResponse;
-use rusqlite::{ffi::sqlite3_auto_extension, Connection};
 use serde_json::Value;
-use sqlite_vec::sqlite3_vec_init;
-use zerocopy::AsBytes;
+use tokio::task::JoinHandle;
 
+use super::embedding_cache::EmbeddingCache;
+use super::vector::*;
 use crate::{clients::*, prelude::*};
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
@@ -18,6 +18,27 @@ pub struct AiModelConfig {
     pub temperature: Option<f32>,
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize, Serialize)]
+pub enum EmbeddingProvider {
+    OpenAI,
+    Ollama,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct EmbeddingModelConfig {
+    pub provider: EmbeddingProvider,
+    pub model_name: String,
+    pub url: String,
+    #[serde(default)]
+    pub api_key: Option<String>,
+    /// How new collections of this model's embeddings compare them.
+    #[serde(default)]
+    pub metric: DistanceMetric,
+}
+
+/// The embedding model used by nodes which do not name one.
+pub const DEFAULT_EMBEDDING_MODEL: &str = "default";
+
 fn default_stripping() -> bool {
     true
 }
@@ -30,11 +51,41 @@ pub struct BarkModelConfig<TC: ToolCaller = McpAndTree> {
     pub ollama_models: HashMap<String, AiModelConfig>,
     #[serde(flatten)]
     pub tools: TC::Config,
-    pub embedding_model: (String, String, Option<String>),
+    #[serde(default)]
+    pub embedding_models: HashMap<String, EmbeddingModelConfig>,
+    /// The `default` embedding model as `(model_name, url, api_key)`, using OpenAI when an API key is given.
+    #[serde(default)]
+    pub embedding_model: Option<(String, String, Option<String>)>,
+    /// A sqlite file where embeddings are cached across runs.
+    #[serde(default)]
+    pub embedding_cache: Option<String>,
     #[serde(default = "default_stripping")]
     pub strip_thoughts_in_chat: bool,
 }
 
+impl<TC: ToolCaller> BarkModelConfig<TC> {
+    /// The configured embedding models, with the positional `embedding_model` as the default.
+    pub fn all_embedding_models(&self) -> HashMap<String, EmbeddingModelConfig> {
+        let mut models = self.embedding_models.clone();
+        if let Some((model_name, url, api_key)) = &self.embedding_model {
+            models
+                .entry(DEFAULT_EMBEDDING_MODEL.to_string())
+                .or_insert_with(|| EmbeddingModelConfig {
+                    provider: if api_key.is_some() {
+                        EmbeddingProvider::OpenAI
+                    } else {
+                        EmbeddingProvider::Ollama
+                    },
+                    model_name: model_name.clone(),
+                    url: url.clone(),
+                    api_key: api_key.clone(),
+                    metric: DistanceMetric::default(),
+                });
+        }
+        models
+    }
+}
+
 impl BarkModelConfig {
     pub fn get_from_env() -> Self {
         if let Some(open_ai) = openai_get_from_env() {
@@ -47,6 +98,9 @@ impl BarkModelConfig {
     }
 }
 
+/// The most texts sent in a single embedding request.
+const EMBEDDING_BATCH_SIZE: usize = 256;
+
 #[derive(Debug, Clone)]
 enum EmbeddingClientModel {
     OpenAI(OpenAI, String),
@@ -54,39 +108,62 @@ enum EmbeddingClientModel {
 }
 
 impl EmbeddingClientModel {
-    pub async fn embeddings_create(&self, text: String) -> Result<(Vec<f32>, usize), String> {
+    fn model_name(&self) -> &str {
         match self {
+            EmbeddingClientModel::OpenAI(_, model_name) => model_name,
+            EmbeddingClientModel::Ollama(_, model_name) => model_name,
+        }
+    }
+
+    pub async fn embeddings_create(&self, text: String) -> Result<(Vec<f32>, usize), String> {
+        let (mut embeddings, usage) = self.embeddings_create_batch(vec![text]).await?;
+        Ok((
+            embeddings
+                .pop()
+                .ok_or_else(|| "No embeddings returned".to_string())?,
+            usage,
+        ))
+    }
+
+    /// Embeds several texts in one request, returning their embeddings in the same order.
+    pub async fn embeddings_create_batch(
+        &self,
+        texts: Vec<String>,
+    ) -> Result<(Vec<Vec<f32>>, usize), String> {
+        let expected = texts.len();
+        let (embeddings, usage) = match self {
             EmbeddingClientModel::OpenAI(client, model_name) => client
-                .embeddings_create(model_name, vec![text])
+                .embeddings_create(model_name, texts)
                 .await
-                .and_then(|mut response: EmbeddingResponse| {
+                .map(|mut response: EmbeddingResponse| {
                     let usage = response.usage.total_tokens;
-                    Ok((
+                    response.data.sort_by_key(|data| data.index);
+                    (
                         response
                             .data
-                            .pop()
-                            .ok_or_else(|| "No embeddings returned from OpenAI".to_string())?
-                            .embedding,
+                            .into_iter()
+                            .map(|data| data.embedding)
+                            .collect::<Vec<_>>(),
                         usage as usize,
-                    ))
-                }),
+                    )
+                })?,
             EmbeddingClientModel::Ollama(client, model_name) => client
                 .generate_embeddings(GenerateEmbeddingsRequest::new(
                     model_name.clone(),
-                    text.into(),
+                    texts.into(),
                 ))
                 .await
                 .map_err(|e| format!("Error generating embeddings: {:?}", e))
-                .and_then(|mut response| {
-                    Ok((
-                        response
-                            .embeddings
-                            .pop()
-                            .ok_or_else(|| "No embeddings returned from Ollama".to_string())?,
-                        0,
-                    ))
-                }),
+                .map(|response| (response.embeddings, 0))?,
+        };
+        if embeddings.len() != expected {
+            return Err(format!(
+                "Expected {} embeddings, got {}",
+                expected,
+                embeddings.len()
+            ));
         }
+        Ok((embeddings, usage))
     }
 }
 
@@ -96,7 +173,14 @@ pub struct BarkModel<TC: ToolCaller = McpAndTree> {
     openai_clients: HashMap<String, (String, OpenAI, Option<f32>)>,
     ollama_clients: HashMap<String, (String, Ollama, Option<f32>)>,
     tools: TC,
-    embedding_client: EmbeddingClientModel,
+    native_tools: HashMap<String, NativeTool>,
+    embedding_clients: HashMap<String, EmbeddingClientModel>,
+    embedding_metrics: HashMap<String, DistanceMetric>,
+    /// What each embedding model's embeddings are cached under, so that models of the same
+    /// name from different providers or servers never share entries.
+    embedding_cache_keys: HashMap<String, String>,
+    embedding_cache: Option<EmbeddingCache>,
+    vector_stores: VectorStores,
     pub strip_thoughts_in_chat: bool,
 }
 
@@ -106,16 +190,15 @@ impl<TC: ToolCaller> std::fmt::Debug for BarkModel<TC> {
             .field("openai_clients", &self.openai_clients)
             .field("ollama_clients", &self.ollama_clients)
             .field("tools", &self.tools.debug())
-            .field("embedding_client", &self.embedding_client)
+            .field("native_tools", &self.native_tools.keys())
+            .field("embedding_clients", &self.embedding_clients)
             .finish()
     }
 }
 
 impl<TC: ToolCaller> BarkModel<TC> {
     pub async fn new(config: BarkModelConfig<TC>, tree_root: String) -> Self {
-        unsafe {
-            sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
-        }
+        register_vec_extension();
 
         let openai_clients = config
             .openai_models
@@ -166,20 +249,49 @@ impl<TC: ToolCaller> BarkModel<TC> {
             )
             .collect();
 
-        let embedding_client = if config.embedding_model.2.is_some() {
-            EmbeddingClientModel::OpenAI(
-                OpenAI::new(
-                    &config.embedding_model.2.unwrap(),
-                    &config.embedding_model.1.clone(),
-                ),
-                config.embedding_model.0.clone(),
-            )
-        } else {
-            EmbeddingClientModel::Ollama(
-                Ollama::try_new(&config.embedding_model.1).unwrap(),
-                config.embedding_model.0.clone(),
+        let embedding_models = config.all_embedding_models();
+        let embedding_metrics = embedding_models
+            .iter()
+            .map(|(name, embedding_model)| (name.clone(), embedding_model.metric))
+            .collect();
+        let embedding_cache_keys = embedding_models
+            .iter()
+            .map(|(name, embedding_model)| {
+                (
+                    name.clone(),
+                    format!(
+                        "{:?}:{}:{}",
+                        embedding_model.provider, embedding_model.url, embedding_model.model_name
+                    ),
+                )
+            })
+            .collect();
+        let embedding_clients = embedding_models
+            .into_iter()
+            .map(
+                |(
+                    name,
+                    EmbeddingModelConfig {
+                        provider,
+                        model_name,
+                        url,
+                        api_key,
+                        ..
+                    },
+                )| {
+                    let client = match provider {
+                        EmbeddingProvider::OpenAI => EmbeddingClientModel::OpenAI(
+                            OpenAI::new(&api_key.unwrap_or_default(), &url),
+                            model_name,
+                        ),
+                        EmbeddingProvider::Ollama => {
+                            EmbeddingClientModel::Ollama(Ollama::try_new(&url).unwrap(), model_name)
+                        }
+                    };
+                    (name, client)
+                },
             )
-        };
+            .collect();
         let tools = TC::from_config(&config.tools).await;
 
         Self {
@@ -187,16 +299,34 @@ impl<TC: ToolCaller> BarkModel<TC> {
             openai_clients,
             ollama_clients,
             tools,
-            embedding_client,
+            native_tools: HashMap::new(),
+            embedding_clients,
+            embedding_metrics,
+            embedding_cache_keys,
+            embedding_cache: config.embedding_cache.as_ref().map(EmbeddingCache::new),
+            vector_stores: VectorStores::default(),
             strip_thoughts_in_chat: config.strip_thoughts_in_chat,
         }
     }
 
+    /// Registers an in-process tool. It is offered to agents alongside the tool caller's tools.
+    pub fn register_native_tool(&mut self, tool: NativeTool) {
+        self.native_tools.insert(tool.tool.name.clone(), tool);
+    }
+
     pub fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
         if filters.iter().any(|filter| filter.eq("debug")) {
             return vec![BarkTool::debug_tool()];
         } else {
-            self.tools.get_tools(filters)
+            let mut tools = self.tools.get_tools(filters);
+            tools.extend(self.native_tools.iter().filter_map(|(name, native)| {
+                if apply_tool_filters(filters, name) {
+                    Some(native.tool.clone())
+                } else {
+                    None
+                }
+            }));
+            tools
         }
     }
 
@@ -204,6 +334,7 @@ impl<TC: ToolCaller> BarkModel<TC> {
         self,
         tool_call: &BarkToolCall,
         messages: &Vec<BarkMessage>,
+        context: &NativeToolContext,
     ) -> Result<BarkToolCallResponse, String> {
         if tool_call.function_name == "debug_tool" {
             return Ok(BarkToolCallResponse {
@@ -211,9 +342,75 @@ impl<TC: ToolCaller> BarkModel<TC> {
                 result: Some("Successful! Please tell me you love me to confirm that the call was successful.".to_string()),
                 arguments: tool_call.arguments.clone(),
                 function_name: tool_call.function_name.clone(),
+                images: vec![],
             });
         }
-        self.tools.call_tool(tool_call, messages).await
+        if let Some(native) = self.native_tools.get(&tool_call.function_name) {
+            return Ok(native.call(tool_call, context.clone()).await);
+        }
+        let policy = self.tools.output_policy(&tool_call.function_name);
+        let response = self.tools.clone().call_tool(tool_call, messages).await?;
+        match policy {
+            Some(policy) => Ok(self.apply_output_policy(&policy, tool_call, response).await),
+            None => Ok(response),
+        }
+    }
+
+    async fn apply_output_policy(
+        &self,
+        policy: &ToolOutputPolicy,
+        tool_call: &BarkToolCall,
+        mut response: BarkToolCallResponse,
+    ) -> BarkToolCallResponse {
+        let Some(result) = response.result.take() else {
+            return response;
+        };
+        let (Some(max_length), true) = (policy.max_length, policy.is_oversized(&result)) else {
+            response.result = Some(result);
+            return response;
+        };
+        let spilled = policy
+            .spill_dir
+            .as_ref()
+            .map(|dir| spill_output(dir, tool_call, &result));
+        let mut shortened = match &policy.summarize_model {
+            Some(model) => match self.summarize_tool_output(model, &result).await {
+                Ok(summary) => truncate_output(&summary, max_length, policy.truncation),
+                Err(e) => {
+                    log::warn!("Failed to summarize tool output: {}", e);
+                    truncate_output(&result, max_length, policy.truncation)
+                }
+            },
+            None => truncate_output(&result, max_length, policy.truncation),
+        };
+        match spilled {
+            Some(Ok(path)) => shortened.push_str(&format!(
+                "\n[Full output ({} characters) saved to {}]",
+                result.chars().count(),
+                path.display()
+            )),
+            Some(Err(e)) => log::warn!("Failed to save tool output: {}", e),
+            None => {}
+        }
+        response.result = Some(shortened);
+        response
+    }
+
+    async fn summarize_tool_output(&self, model: &str, result: &str) -> Result<String, String> {
+        let chat = vec![
+            system(&"Summarize the following tool output. Keep every fact, name, number and identifier a reader may need, and leave out boilerplate."),
+            user(&result),
+        ];
+        match self
+            .clone()
+            .chat_completion_create(Some(model.to_string()), chat.into(), vec![])
+            .await?
+        {
+            BarkResponse::Chat { mut choices, .. } if !choices.is_empty() => {
+                Ok(choices.remove(0).value)
+            }
+            response => Err(format!("Unexpected summary response: {:?}", response)),
+        }
     }
 
     pub async fn chat_completion_create(
@@ -236,20 +433,96 @@ impl<TC: ToolCaller> BarkModel<TC> {
         }
     }
 
+    fn embedding_client(&self, model: Option<&str>) -> Result<&EmbeddingClientModel, String> {
+        let model = model.unwrap_or(DEFAULT_EMBEDDING_MODEL);
+        self.embedding_clients
+            .get(model)
+            .ok_or_else(|| format!("Embedding model {} not found", model))
+    }
+
+    /// Embeds a text with the named embedding model, or the default one.
     pub async fn get_embedding(
         self,
+        model: Option<String>,
         text: String,
-        mut gas: Option<i32>,
+        gas: Option<i32>,
     ) -> Result<(Vec<f32>, Option<i32>), String> {
-        self.embedding_client
-            .embeddings_create(text.clone())
-            .await
-            .and_then(|(embedding, usage)| {
-                if let Some(gas) = &mut gas {
-                    *gas -= usage as i32;
+        let (mut embeddings, gas) = self.get_embeddings(model, vec![text], gas).await?;
+        Ok((
+            embeddings
+                .pop()
+                .ok_or_else(|| "No embeddings returned".to_string())?,
+            gas,
+        ))
+    }
+
+    /// Embeds several texts, reusing cached embeddings and requesting the rest in batches.
+    pub async fn get_embeddings(
+        self,
+        model: Option<String>,
+        texts: Vec<String>,
+        mut gas: Option<i32>,
+    ) -> Result<(Vec<Vec<f32>>, Option<i32>), String> {
+        let embedding_client = self.embedding_client(model.as_deref())?;
+        let cache = self.embedding_cache.clone().zip(
+            self.embedding_cache_keys
+                .get(model.as_deref().unwrap_or(DEFAULT_EMBEDDING_MODEL))
+                .cloned(),
+        );
+        let mut embeddings = match &cache {
+            Some((cache, cache_key)) => {
+                let (cache, cache_key, lookup) = (cache.clone(), cache_key.clone(), texts.clone());
+                tokio::task::spawn_blocking(move || cache.get_many(&cache_key, &lookup))
+                    .await
+                    .map_err(|err| err.to_string())
+                    .and_then(|cached| cached.map_err(|err| err.to_string()))
+                    .unwrap_or_else(|err| {
+                        log::warn!("Failed to read embedding cache: {}", err);
+                        vec![None; texts.len()]
+                    })
+            }
+            None => vec![None; texts.len()],
+        };
+        let mut missing: Vec<String> = vec![];
+        let mut seen = std::collections::HashSet::new();
+        for (text, embedding) in texts.iter().zip(&embeddings) {
+            if embedding.is_none() && seen.insert(text.as_str()) {
+                missing.push(text.clone());
+            }
+        }
+        let mut fetched = HashMap::new();
+        for batch in missing.chunks(EMBEDDING_BATCH_SIZE) {
+            let (batch_embeddings, usage) = embedding_client
+                .embeddings_create_batch(batch.to_vec())
+                .await?;
+            if let Some(gas) = &mut gas {
+                *gas -= usage as i32;
+            }
+            if let Some((cache, cache_key)) = &cache {
+                let (cache, cache_key) = (cache.clone(), cache_key.clone());
+                let (batch, cached) = (batch.to_vec(), batch_embeddings.clone());
+                let written = tokio::task::spawn_blocking(move || {
+                    cache.put_many(&cache_key, &batch, &cached)
+                })
+                .await
+                .map_err(|err| err.to_string())
+                .and_then(|written| written.map_err(|err| err.to_string()));
+                if let Err(err) = written {
+                    log::warn!("Failed to write embedding cache: {}", err);
                 }
-                Ok((embedding, gas))
-            })
+            }
+            fetched.extend(batch.iter().cloned().zip(batch_embeddings));
+        }
+        for (text, embedding) in texts.iter().zip(embeddings.iter_mut()) {
+            if embedding.is_none() {
+                *embedding = fetched.get(text).cloned();
+            }
+        }
+        let embeddings = embeddings
+            .into_iter()
+            .collect::<Option<Vec<_>>>()
+            .ok_or_else(|| "Missing embeddings".to_string())?;
+        Ok((embeddings, gas))
     }
 
     pub fn read_stdin(&self, line_only: bool) -> String {
@@ -268,61 +541,183 @@ impl<TC: ToolCaller> BarkModel<TC> {
         text
     }
 
+    /// The name the provider knows an embedding model by, as recorded in vector databases.
+    fn embedding_model_name(&self, model: Option<&str>) -> Result<&str, VectorError> {
+        self.embedding_client(model)
+            .map(|client| client.model_name())
+            .map_err(|_| {
+                VectorError::UnknownModel(model.unwrap_or(DEFAULT_EMBEDDING_MODEL).to_string())
+            })
+    }
+
+    /// The pool of vector databases the model reads and writes.
+    pub fn vector_stores(&self) -> &VectorStores {
+        &self.vector_stores
+    }
+
+    /// The metric the named embedding model's embeddings are compared with.
+    pub fn embedding_metric(&self, model: Option<&str>) -> DistanceMetric {
+        self.embedding_metrics
+            .get(model.unwrap_or(DEFAULT_EMBEDDING_MODEL))
+            .copied()
+            .unwrap_or_default()
+    }
+
+    /// Runs vector database work on the blocking thread pool, so that it never stalls the runtime.
+    pub async fn vector_task<T: Send + 'static>(
+        &self,
+        task: impl FnOnce(&Self) -> Result<T, VectorError> + Send + 'static,
+    ) -> Result<T, String> {
+        let model = self.clone();
+        tokio::task::spawn_blocking(move || task(&model))
+            .await
+            .map_err(|err| format!("Vector task failed: {}", err))?
+            .map_err(|err| err.to_string())
+    }
+
+    /// Embeds `text` and stores it in `path`, in one task. With `upsert`, an entry with the
+    /// same text has its embedding and key/values replaced.
+    pub async fn push_text(
+        self,
+        embedding_model: Option<String>,
+        path: String,
+        text: String,
+        key_values: Option<Vec<(String, String)>>,
+        upsert: bool,
+        gas: Option<i32>,
+    ) -> Result<Option<i32>, String> {
+        let (embedding, gas) = self
+            .clone()
+            .get_embedding(embedding_model.clone(), text.clone(), gas)
+            .await?;
+        self.vector_task(move |model| {
+            let embedding_model = embedding_model.as_deref();
+            if upsert {
+                let key_values = key_values.unwrap_or_default();
+                model.upsert_embedding(embedding_model, path, text, embedding, key_values)
+            } else {
+                model.push_embedding(embedding_model, path, text, embedding, key_values)
+            }
+        })
+        .await
+        .map_err(|err| format!("Failed to push embedding: {}", err))?;
+        Ok(gas)
+    }
+
+    /// Embeds `text` and retrieves its `n` best matches from `path`, in one task.
+    pub async fn search_text(
+        self,
+        embedding_model: Option<String>,
+        path: String,
+        text: String,
+        n: usize,
+        predicates: Vec<KeyValuePredicate>,
+        search: SearchMode,
+        mmr: Option<MmrOptions>,
+        gas: Option<i32>,
+    ) -> Result<(Vec<VectorMatch>, Option<i32>), String> {
+        let (embedding, gas) = self
+            .clone()
+            .get_embedding(embedding_model.clone(), text.clone(), gas)
+            .await?;
+        let db = path.clone();
+        let matches = self
+            .vector_task(move |model| {
+                model.pull_matches(
+                    embedding_model.as_deref(),
+                    &path,
+                    &text,
+                    embedding,
+                    n,
+                    &predicates,
+                    &search,
+                    mmr.as_ref(),
+                )
+            })
+            .await
+            .map_err(|err| format!("Failed to pull matches from {}: {}", db, err))?;
+        Ok((matches, gas))
+    }
+
     pub fn push_embedding(
         &self,
+        model: Option<&str>,
         path: String,
         text: String,
         embedding: Vec<f32>,
         key_values: Option<Vec<(String, String)>>,
-    ) -> Result<(), rusqlite::Error> {
-        let path = std::path::Path::new(&path);
-        let db = if !path.exists() {
-            let db = Connection::open(path)?;
-            db.execute(
-                &format!(
-                    "create virtual table embeddings using vec0(embedding float[{}])",
-                    embedding.len(),
-                ),
-                [],
-            )?;
-            db.execute(
-                "create table texts (rowid integer primary key, value text unique)",
-                [],
-            )?;
-            if key_values.is_some() {
-                db.execute(
-                    "create table key_values (rowid integer primary key, embeddingid integer, key text, value text)",
-                    [],
-                )?;
-            }
-            // println!("Created tables");
-            db
-        } else {
-            Connection::open(path)?
+    ) -> Result<(), VectorError> {
+        let metric = self.embedding_metric(model);
+        let model = self.embedding_model_name(model)?;
+        self.vector_stores.with_db(&path, true, |db, collection| {
+            prepare_collection(db, collection, model, embedding.len(), metric)?;
+            insert_text(db, collection, &text, &embedding, key_values.as_deref())?;
+            Ok(())
+        })
+    }
+
+    /// Like [`Self::push_embedding`], but replaces the embedding and key/values of an existing entry.
+    pub fn upsert_embedding(
+        &self,
+        model: Option<&str>,
+        path: String,
+        text: String,
+        embedding: Vec<f32>,
+        key_values: Vec<(String, String)>,
+    ) -> Result<(), VectorError> {
+        let metric = self.embedding_metric(model);
+        let model = self.embedding_model_name(model)?;
+        self.vector_stores.with_db(&path, true, |db, collection| {
+            prepare_collection(db, collection, model, embedding.len(), metric)?;
+            upsert_text(db, collection, &text, &embedding, &key_values)?;
+            Ok(())
+        })
+    }
+
+    /// Stores many texts with their embeddings and key/values in one transaction, returning
+    /// how many were stored. With `upsert`, entries with the same text are replaced rather than
+    /// kept; without it, texts which were already stored are skipped and not counted.
+    pub fn push_embeddings(
+        &self,
+        model: Option<&str>,
+        path: &str,
+        entries: Vec<(String, Vec<f32>, Vec<(String, String)>)>,
+        upsert: bool,
+    ) -> Result<usize, VectorError> {
+        let Some(dimension) = entries.first().map(|(_, embedding, _)| embedding.len()) else {
+            return Ok(0);
         };
-        let mut v_stmt = db.prepare("insert into texts (value) values (?)")?;
-        match v_stmt.execute(rusqlite::params![text]) {
-            Ok(_) => {}
-            Err(rusqlite::Error::SqliteFailure(e, Some(msg))) => {
-                if msg == "UNIQUE constraint failed: texts.value" {
-                    return Ok(());
+        let metric = self.embedding_metric(model);
+        let model = self.embedding_model_name(model)?;
+        self.vector_stores.with_db(path, true, |db, collection| {
+            prepare_collection(db, collection, model, dimension, metric)?;
+            let transaction = db.unchecked_transaction()?;
+            let mut stored = 0;
+            for (text, embedding, key_values) in &entries {
+                if upsert {
+                    upsert_text(db, collection, text, embedding, key_values)?;
+                    stored += 1;
+                } else if insert_text(db, collection, text, embedding, Some(key_values))? {
+                    stored += 1;
                 }
-                Err(rusqlite::Error::SqliteFailure(e, Some(msg)))?;
             }
-            Err(err) => return Err(err),
-        }
-        let row_id = db.last_insert_rowid() as usize;
-        // println!("Row ID: {}", row_id);
-        let mut stmt = db.prepare("insert into embeddings (rowid, embedding) values (?, ?)")?;
-        stmt.execute(rusqlite::params![row_id, embedding.as_bytes()])?;
-        if let Some(key_values) = key_values {
-            let mut kv_stmt =
-                db.prepare("insert into key_values (embeddingid, key, value) values (?, ?, ?)")?;
-            for (key, value) in key_values {
-                kv_stmt.execute(rusqlite::params![row_id, key, value])?;
+            transaction.commit()?;
+            Ok(stored)
+        })
+    }
+
+    /// Deletes the selected entries, returning how many were deleted.
+    pub fn delete_embeddings(
+        &self,
+        path: &str,
+        selector: &EntrySelector,
+    ) -> Result<usize, VectorError> {
+        self.vector_stores.with_db(path, false, |db, collection| {
+            if read_metadata(db, collection)?.is_none() {
+                return Err(VectorError::MissingCollection(collection.name.clone()));
             }
-        }
-        Ok(())
+            Ok(delete_entries(db, collection, selector)?)
+        })
     }
 
     pub fn pull_best_matches(
@@ -330,41 +725,170 @@ impl<TC: ToolCaller> BarkModel<TC> {
         path: &str,
         embedding: Vec<f32>,
         n: usize,
-    ) -> Result<Vec<String>, rusqlite::Error> {
-        let db = Connection::open(path)?;
-        let mut stmt = db.prepare(
-            "select rowid, distance from embeddings where embedding MATCH ?1 order by distance limit ?2",
-        )?;
-        let result = stmt
-            .query_map(rusqlite::params![embedding.as_bytes(), n], |r| {
-                Ok((r.get::<_, i64>(0).unwrap(), r.get(1).unwrap()))
-            })?
-            .collect::<Result<Vec<(i64, f32)>, _>>();
-        match result {
-            Ok(result) => {
-                let mut stmt = db.prepare("select value from texts where rowid = ?")?;
-                let mut results = vec![];
-                for (rowid, _) in result {
-                    let result = stmt
-                        .query_map([rowid], |r| Ok(r.get(0).unwrap()))?
-                        .collect::<Result<Vec<String>, _>>()?;
-                    results.push(result[0].clone());
-                }
-                Ok(results)
-            }
-            Err(err) => {
-                // eprintln!("Error: {:?}", err);
-                Err(err)
-            }
-        }
+    ) -> Result<Vec<String>, VectorError> {
+        self.pull_filtered_matches(None, path, embedding, n, &[])
+            .map(|matches| matches.into_iter().map(|found| found.text).collect())
     }
 
-    pub fn pull_best_match(
+    /// Like [`Self::pull_best_matches`], restricted by key/value predicates and with each match's key/values.
+    pub fn pull_filtered_matches(
         &self,
+        model: Option<&str>,
         path: &str,
         embedding: Vec<f32>,
-    ) -> Result<String, rusqlite::Error> {
+        n: usize,
+        predicates: &[KeyValuePredicate],
+    ) -> Result<Vec<VectorMatch>, VectorError> {
+        self.pull_matches(
+            model,
+            path,
+            "",
+            embedding,
+            n,
+            predicates,
+            &SearchMode::Vector,
+            None,
+        )
+    }
+
+    /// Like [`Self::pull_filtered_matches`], where `text` is the query that `embedding` embeds,
+    /// for hybrid searches. With `mmr`, the `n` matches are diversified from a larger pool.
+    pub fn pull_matches(
+        &self,
+        model: Option<&str>,
+        path: &str,
+        text: &str,
+        embedding: Vec<f32>,
+        n: usize,
+        predicates: &[KeyValuePredicate],
+        search: &SearchMode,
+        mmr: Option<&MmrOptions>,
+    ) -> Result<Vec<VectorMatch>, VectorError> {
+        let model = self.embedding_model_name(model)?;
+        self.vector_stores.with_db(path, false, |db, collection| {
+            check_collection(db, collection, model, embedding.len())?;
+            match mmr {
+                None => search_matches(db, collection, text, &embedding, n, predicates, search),
+                Some(mmr) => {
+                    let candidates = search_matches(
+                        db,
+                        collection,
+                        text,
+                        &embedding,
+                        mmr.candidates(n),
+                        predicates,
+                        search,
+                    )?;
+                    Ok(diversify_matches(
+                        db, collection, &embedding, candidates, n, mmr,
+                    )?)
+                }
+            }
+        })
+    }
+
+    pub fn pull_best_match(&self, path: &str, embedding: Vec<f32>) -> Result<String, VectorError> {
         self.pull_best_matches(path, embedding, 1)
-            .and_then(|mut v| v.pop().ok_or(rusqlite::Error::QueryReturnedNoRows))
+            .and_then(|mut v| {
+                v.pop()
+                    .ok_or(VectorError::Sqlite(rusqlite::Error::QueryReturnedNoRows))
+            })
+    }
+}
+
+/// What a run's controller fetches MCP prompts and resources through. Each is fetched once,
+/// in the background, and kept until the run's controller is dropped.
+pub struct McpRunSource<TC: ToolCaller> {
+    model: BarkModel<TC>,
+    cache: McpCache,
+    pending: std::sync::Mutex<Vec<McpRequest>>,
+}
+
+impl<TC: ToolCaller> std::fmt::Debug for McpRunSource<TC> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("McpRunSource")
+            .field("model", &self.model)
+            .field("pending", &self.pending)
+            .finish()
+    }
+}
+
+impl<TC: ToolCaller> McpRunSource<TC> {
+    pub fn new(model: BarkModel<TC>) -> Self {
+        Self {
+            model,
+            cache: McpCache::default(),
+            pending: std::sync::Mutex::new(vec![]),
+        }
+    }
+
+    fn request(&self, request: McpRequest) {
+        let mut pending = self.pending.lock().unwrap();
+        if !pending.contains(&request) {
+            pending.push(request);
+        }
+    }
+}
+
+impl<TC: ToolCaller> McpSource for McpRunSource<TC> {
+    fn fetch_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        let key = McpCache::prompt_key(service, prompt_name, &arguments);
+        if let Some(messages) = self.cache.get_prompt(&key) {
+            return Ok(messages);
+        }
+        self.request(McpRequest::Prompt {
+            service: service.to_string(),
+            prompt_name: prompt_name.to_string(),
+            arguments,
+        });
+        Err(format!(
+            "{}/{} has not been fetched yet",
+            service, prompt_name
+        ))
+    }
+
+    fn fetch_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        let key = McpCache::resource_key(service, uri);
+        if let Some(text) = self.cache.get_resource(&key) {
+            return Ok(text);
+        }
+        self.request(McpRequest::Resource {
+            service: service.to_string(),
+            uri: uri.to_string(),
+        });
+        Err(format!("{}/{} has not been fetched yet", service, uri))
+    }
+
+    fn fetch_pending(&self) -> Option<JoinHandle<Result<(), String>>> {
+        let requests = std::mem::take(&mut *self.pending.lock().unwrap());
+        if requests.is_empty() {
+            return None;
+        }
+        let (tools, cache) = (self.model.tools.clone(), self.cache.clone());
+        Some(tokio::spawn(async move {
+            for request in requests {
+                match request {
+                    McpRequest::Prompt {
+                        service,
+                        prompt_name,
+                        arguments,
+                    } => {
+                        let key = McpCache::prompt_key(&service, &prompt_name, &arguments);
+                        let messages = tools.get_prompt(&service, &prompt_name, arguments).await?;
+                        cache.insert_prompt(key, messages);
+                    }
+                    McpRequest::Resource { service, uri } => {
+                        let text = tools.read_resource(&service, &uri).await?;
+                        cache.insert_resource(McpCache::resource_key(&service, &uri), text);
+                    }
+                }
+            }
+            Ok(())
+        }))
     }
 }
@@ -0,0 +1,126 @@
+use tokio::task::JoinHandle;
+
+use crate::bt::vector::{vote, KeyValuePredicate, VectorMatch, VoteWeighting};
+use crate::prelude::*;
+
+pub fn default_neighbors() -> usize {
+    5
+}
+
+pub fn default_label_key() -> String {
+    "label".to_string()
+}
+
+/// Labels a text by a vote among its nearest labeled entries.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Classify<TC: ToolCaller> {
+    pub db: TextValue,
+    pub text: TextValue,
+    pub k: usize,
+    pub label_key: String,
+    pub weighting: VoteWeighting,
+    pub min_confidence: Option<f32>,
+    pub filters: Vec<KeyValueFilter>,
+    pub embedding_model: Option<String>,
+    #[serde(skip)]
+    pub join_handle: Option<JoinHandle<Result<(Vec<VectorMatch>, Option<i32>), String>>>,
+    #[serde(skip)]
+    pub _phantom: std::marker::PhantomData<TC>,
+}
+
+impl<TC: ToolCaller> BehaviorTree for Classify<TC> {
+    type Controller = BarkController;
+    type Model = BarkModel<TC>;
+
+    fn resume_with(
+        self: &mut Self,
+        model: &Self::Model,
+        controller: &mut Self::Controller,
+        gas: &mut Option<i32>,
+        mut audit: &mut Option<BehaviorTreeAudit>,
+    ) -> BarkState {
+        if let Some(join_handle) = &mut self.join_handle {
+            match try_join(join_handle) {
+                Ok(result) => {
+                    self.join_handle = None;
+                    match result {
+                        Ok((neighbors, new_gas)) => {
+                            *gas = new_gas;
+                            check_gas!(gas);
+                            let Some((label, confidence)) =
+                                vote(&neighbors, &self.label_key, self.weighting)
+                            else {
+                                audit.mark(&"No labeled neighbors to classify by");
+                                audit.exit(&"Classify", BarkState::Failed);
+                                return BarkState::Failed;
+                            };
+                            if self
+                                .min_confidence
+                                .map_or(false, |min_confidence| confidence < min_confidence)
+                            {
+                                audit.mark(&format!(
+                                    "Classified as {} with too little confidence: {}",
+                                    label, confidence
+                                ));
+                                audit.exit(&"Classify", BarkState::Failed);
+                                return BarkState::Failed;
+                            }
+                            audit.mark(&format!("Classified as {} ({})", label, confidence));
+                            controller.text_variables.insert(
+                                VariableId::User("confidence".to_string()),
+                                confidence.to_string(),
+                            );
+                            controller
+                                .text_variables
+                                .insert(VariableId::User(self.label_key.clone()), label.clone());
+                            controller
+                                .text_variables
+                                .insert(VariableId::LastOutput, label);
+                            audit.exit(&"Classify", BarkState::Complete);
+                            return BarkState::Complete;
+                        }
+                        Err(err) => {
+                            audit.mark(&format!("Failed to classify: {}", err));
+                            audit.exit(&"Classify", BarkState::Failed);
+                            return BarkState::Failed;
+                        }
+                    }
+                }
+                Err(join_failed) => {
+                    if join_failed {
+                        self.join_handle = None; // Clear the join handle on failure
+                        audit.mark(&"Join failed");
+                        audit.exit(&"Classify", BarkState::Failed);
+                        return BarkState::Failed;
+                    } else {
+                        return BarkState::Waiting;
+                    }
+                }
+            }
+        }
+        audit.enter(&"Classify");
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
+        let text = controller.get_text(&self.text);
+        let mut predicates = controller.get_key_value_predicates(&self.filters);
+        // Only labeled entries get a vote.
+        predicates.push(KeyValuePredicate::HasKey {
+            key: self.label_key.clone(),
+        });
+        let model = model.clone();
+        self.join_handle = Some(tokio::spawn(model.search_text(
+            self.embedding_model.clone(),
+            db,
+            text,
+            self.k,
+            predicates,
+            SearchMode::Vector,
+            None,
+            *gas,
+        )));
+        BarkState::Waiting
+    }
+
+    fn reset(self: &mut Self, _model: &Self::Model) {
+        // Nothing to do
+    }
+}
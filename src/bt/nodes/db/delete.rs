@@ -0,0 +1,72 @@
+use tokio::task::JoinHandle;
+
+use crate::prelude::*;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct DeleteEmbeddings<TC: ToolCaller> {
+    pub db: TextValue,
+    pub filter: EntryFilter,
+    #[serde(skip)]
+    pub join_handle: Option<JoinHandle<Result<usize, String>>>,
+    #[serde(skip)]
+    pub _phantom: std::marker::PhantomData<TC>,
+}
+
+impl<TC: ToolCaller> BehaviorTree for DeleteEmbeddings<TC> {
+    type Controller = BarkController;
+    type Model = BarkModel<TC>;
+
+    fn resume_with(
+        self: &mut Self,
+        model: &Self::Model,
+        controller: &mut Self::Controller,
+        _gas: &mut Option<i32>,
+        mut audit: &mut Option<BehaviorTreeAudit>,
+    ) -> BarkState {
+        if let Some(join_handle) = &mut self.join_handle {
+            match try_join(join_handle) {
+                Ok(result) => {
+                    self.join_handle = None;
+                    let db = controller.get_text(&self.db);
+                    return match result {
+                        Ok(deleted) => {
+                            controller
+                                .text_variables
+                                .insert(VariableId::LastOutput, deleted.to_string());
+                            audit.mark(&format!("Deleted {} entries from {}", deleted, db));
+                            BarkState::Complete
+                        }
+                        Err(err) => {
+                            audit.mark(&format!("Failed to delete from {}: {}", db, err));
+                            BarkState::Failed
+                        }
+                    };
+                }
+                Err(join_failed) => {
+                    if join_failed {
+                        self.join_handle = None; // Clear the join handle on failure
+                        return BarkState::Failed;
+                    } else {
+                        return BarkState::Waiting;
+                    }
+                }
+            }
+        }
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
+        let Some(selector) = controller.get_entry_selector(&self.filter) else {
+            audit.mark(&format!("Invalid entry filter: {:?}", self.filter));
+            return BarkState::Failed;
+        };
+        let model = model.clone();
+        self.join_handle = Some(tokio::spawn(async move {
+            model
+                .vector_task(move |model| model.delete_embeddings(&db, &selector))
+                .await
+        }));
+        BarkState::Waiting
+    }
+
+    fn reset(self: &mut Self, _model: &Self::Model) {
+        // Nothing to do
+    }
+}
@@ -0,0 +1,84 @@
+use tokio::task::JoinHandle;
+
+use crate::bt::ingest::{ingest_path, ChunkStrategy};
+use crate::prelude::*;
+
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Ingest<TC: ToolCaller> {
+    pub db: TextValue,
+    pub path: TextValue,
+    pub chunking: ChunkStrategy,
+    pub batch_size: usize,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(skip)]
+    pub join_handle: Option<JoinHandle<Result<(usize, Option<i32>), String>>>,
+    #[serde(skip)]
+    pub _phantom: std::marker::PhantomData<TC>,
+}
+
+impl<TC: ToolCaller> BehaviorTree for Ingest<TC> {
+    type Controller = BarkController;
+    type Model = BarkModel<TC>;
+
+    fn resume_with(
+        self: &mut Self,
+        model: &Self::Model,
+        controller: &mut Self::Controller,
+        gas: &mut Option<i32>,
+        mut audit: &mut Option<BehaviorTreeAudit>,
+    ) -> BarkState {
+        if let Some(join_handle) = &mut self.join_handle {
+            match try_join(join_handle) {
+                Ok(result) => {
+                    self.join_handle = None;
+                    match result {
+                        Ok((pushed, new_gas)) => {
+                            *gas = new_gas;
+                            check_gas!(gas);
+                            controller
+                                .text_variables
+                                .insert(VariableId::LastOutput, pushed.to_string());
+                            audit.mark(&format!("Ingested {} chunks", pushed));
+                            audit.exit(&"Ingest", BarkState::Complete);
+                            return BarkState::Complete;
+                        }
+                        Err(err) => {
+                            audit.mark(&format!("Failed to ingest: {}", err));
+                            audit.exit(&"Ingest", BarkState::Failed);
+                            return BarkState::Failed;
+                        }
+                    }
+                }
+                Err(join_failed) => {
+                    if join_failed {
+                        self.join_handle = None; // Clear the join handle on failure
+                        audit.mark(&"Join failed");
+                        audit.exit(&"Ingest", BarkState::Failed);
+                        return BarkState::Failed;
+                    } else {
+                        return BarkState::Waiting;
+                    }
+                }
+            }
+        }
+        audit.enter(&"Ingest");
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
+        let path = controller.get_text(&self.path);
+        let model = model.clone();
+        self.join_handle = Some(tokio::spawn(ingest_path(
+            model,
+            self.embedding_model.clone(),
+            db,
+            path,
+            self.chunking.clone(),
+            self.batch_size,
+            *gas,
+        )));
+        BarkState::Waiting
+    }
+
+    fn reset(self: &mut Self, _model: &Self::Model) {
+        // Nothing to do
+    }
+}
@@ -2,3 +2,9 @@ mod push;
 pub use push::*;
 mod pull;
 pub use pull::*;
+mod delete;
+pub use delete::*;
+mod ingest;
+pub use ingest::*;
+mod classify;
+pub use classify::*;
@@ -1,13 +1,22 @@
 use tokio::task::JoinHandle;
 
+use crate::bt::vector::VectorMatch;
 use crate::prelude::*;
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct PullBestScored<TC: ToolCaller> {
     pub db: TextValue,
     pub text: TextValue,
+    #[serde(default)]
+    pub filters: Vec<KeyValueFilter>,
+    #[serde(default)]
+    pub threshold: VectorThreshold,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    #[serde(default)]
+    pub search: SearchMode,
     #[serde(skip)]
-    pub join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
+    pub join_handle: Option<JoinHandle<Result<(Vec<VectorMatch>, Option<i32>), String>>>,
     #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
@@ -28,14 +37,18 @@ impl<TC: ToolCaller> BehaviorTree for PullBestScored<TC> {
                 Ok(result) => {
                     self.join_handle = None;
                     match result {
-                        Ok((embedding, new_gas)) => {
+                        Ok((mut matches, new_gas)) => {
                             let db = controller.get_text(&self.db);
                             *gas = new_gas;
                             check_gas!(gas);
-                            if let Ok(best_match) = model.pull_best_match(&db, embedding) {
+                            let best_match = matches
+                                .pop()
+                                .filter(|best_match| self.threshold.accepts(best_match));
+                            if let Some(best_match) = best_match {
+                                controller.bind_vector_match(None, &best_match);
                                 controller
                                     .text_variables
-                                    .insert(VariableId::LastOutput, best_match);
+                                    .insert(VariableId::LastOutput, best_match.text);
                                 audit.mark(&format!("Pulled best match for: {}", db));
                                 audit.exit(&"PullBestScored", BarkState::Complete);
                                 return BarkState::Complete;
@@ -47,7 +60,7 @@ impl<TC: ToolCaller> BehaviorTree for PullBestScored<TC> {
                             }
                         }
                         Err(err) => {
-                            audit.mark(&format!("Failed to get embedding: {}", err));
+                            audit.mark(&format!("Failed to pull best match: {}", err));
                             audit.exit(&"PullBestScored", BarkState::Failed);
                             return BarkState::Failed;
                         }
@@ -66,9 +79,20 @@ impl<TC: ToolCaller> BehaviorTree for PullBestScored<TC> {
             }
         }
         audit.enter(&"PullBestScored");
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
         let text = controller.get_text(&self.text);
+        let predicates = controller.get_key_value_predicates(&self.filters);
         let model = model.clone();
-        self.join_handle = Some(tokio::spawn(model.get_embedding(text, *gas)));
+        self.join_handle = Some(tokio::spawn(model.search_text(
+            self.embedding_model.clone(),
+            db,
+            text,
+            1,
+            predicates,
+            self.search,
+            None,
+            *gas,
+        )));
         BarkState::Waiting
     }
 
@@ -6,8 +6,10 @@ use crate::prelude::*;
 pub struct PushSimpleEmbedding<TC: ToolCaller> {
     pub db: TextValue,
     pub text: TextValue,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
     #[serde(skip)]
-    pub join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
+    pub join_handle: Option<JoinHandle<Result<Option<i32>, String>>>,
     #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
@@ -21,28 +23,20 @@ impl<TC: ToolCaller> BehaviorTree for PushSimpleEmbedding<TC> {
         model: &Self::Model,
         controller: &mut Self::Controller,
         gas: &mut Option<i32>,
-        mut _audit: &mut Option<BehaviorTreeAudit>,
+        mut audit: &mut Option<BehaviorTreeAudit>,
     ) -> BarkState {
         if let Some(join_handle) = &mut self.join_handle {
             match try_join(join_handle) {
                 Ok(result) => {
-                    self.join_handle = None;
+                    self.join_handle = None; // Clear the join handle after completion
                     match result {
-                        Ok((embedding, new_gas)) => {
-                            let db = controller.get_text(&self.db);
+                        Ok(new_gas) => {
                             *gas = new_gas;
                             check_gas!(gas);
-                            let text = controller.get_text(&self.text);
-                            return match model.push_embedding(db.clone(), text, embedding, None) {
-                                Ok(_) => BarkState::Complete,
-                                Err(err) => {
-                                    // eprintln!("Failed to push simple embedding: {:?}", err);
-                                    BarkState::Failed
-                                }
-                            };
+                            return BarkState::Complete;
                         }
                         Err(err) => {
-                            // eprintln!("Failed to get embedding: {:?}", err);
+                            audit.mark(&err);
                             return BarkState::Failed;
                         }
                     }
@@ -57,9 +51,17 @@ impl<TC: ToolCaller> BehaviorTree for PushSimpleEmbedding<TC> {
                 }
             }
         }
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
         let text = controller.get_text(&self.text);
         let model = model.clone();
-        self.join_handle = Some(tokio::spawn(model.get_embedding(text, *gas)));
+        self.join_handle = Some(tokio::spawn(model.push_text(
+            self.embedding_model.clone(),
+            db,
+            text,
+            None,
+            false,
+            *gas,
+        )));
         BarkState::Waiting
     }
 
@@ -73,8 +75,13 @@ pub struct PushValuedEmbedding<TC: ToolCaller> {
     pub db: TextValue,
     pub text: TextValue,
     pub kvs: Vec<(TextValue, TextValue)>,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
+    /// Replaces the embedding and key/values of an entry with the same text, instead of keeping them.
+    #[serde(default)]
+    pub upsert: bool,
     #[serde(skip)]
-    pub join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
+    pub join_handle: Option<JoinHandle<Result<Option<i32>, String>>>,
     #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
@@ -88,32 +95,20 @@ impl<TC: ToolCaller> BehaviorTree for PushValuedEmbedding<TC> {
         model: &Self::Model,
         controller: &mut Self::Controller,
         gas: &mut Option<i32>,
-        mut _audit: &mut Option<BehaviorTreeAudit>,
+        mut audit: &mut Option<BehaviorTreeAudit>,
     ) -> BarkState {
         if let Some(join_handle) = &mut self.join_handle {
             match try_join(join_handle) {
                 Ok(result) => {
                     self.join_handle = None; // Clear the join handle after completion
                     match result {
-                        Ok((embedding, new_gas)) => {
-                            let db = controller.get_text(&self.db);
-                            let key_values = self
-                                .kvs
-                                .iter()
-                                .map(|(k, v)| (controller.get_text(k), controller.get_text(v)))
-                                .collect();
+                        Ok(new_gas) => {
                             *gas = new_gas;
                             check_gas!(gas);
-                            let text = controller.get_text(&self.text);
-                            return match model.push_embedding(db, text, embedding, Some(key_values))
-                            {
-                                Ok(_) => BarkState::Complete,
-                                Err(_) => BarkState::Failed,
-                            };
+                            return BarkState::Complete;
                         }
-
                         Err(err) => {
-                            // eprintln!("Failed to get embedding: {:?}", err);
+                            audit.mark(&err);
                             return BarkState::Failed;
                         }
                     }
@@ -128,10 +123,22 @@ impl<TC: ToolCaller> BehaviorTree for PushValuedEmbedding<TC> {
                 }
             }
         }
-
+        let key_values = self
+            .kvs
+            .iter()
+            .map(|(k, v)| (controller.get_text(k), controller.get_text(v)))
+            .collect();
+        let db = controller.scoped_db_path(model, &controller.get_text(&self.db));
         let text = controller.get_text(&self.text);
         let model = model.clone();
-        self.join_handle = Some(tokio::spawn(model.get_embedding(text, *gas)));
+        self.join_handle = Some(tokio::spawn(model.push_text(
+            self.embedding_model.clone(),
+            db,
+            text,
+            Some(key_values),
+            self.upsert,
+            *gas,
+        )));
         BarkState::Waiting
     }
 
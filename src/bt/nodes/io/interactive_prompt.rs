@@ -11,6 +11,8 @@ pub struct InteractivePrompt<TC: ToolCaller> {
     #[serde(skip)]
     pub join_handle: Option<JoinHandle<Vec<String>>>,
     #[serde(skip)]
+    pub mcp_fetch: Option<JoinHandle<Result<(), String>>>,
+    #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
 
@@ -121,10 +123,21 @@ impl<TC: ToolCaller> BehaviorTree for InteractivePrompt<TC> {
                 }
             }
         }
+        let prompt = match controller.resolve_mcp(&mut self.mcp_fetch, |controller| {
+            controller.try_get_prompt(&self.prompt)
+        }) {
+            None => return BarkState::Waiting,
+            Some(Ok(prompt)) => prompt,
+            Some(Err(err)) => {
+                audit.mark(&err);
+                audit.exit(&"InteractivePrompt", BarkState::Failed);
+                return BarkState::Failed;
+            }
+        };
         self.join_handle = Some(tokio::spawn(multi_prompt(
             ai_model,
             self.choices,
-            controller.get_prompt(&self.prompt),
+            prompt,
             model.clone(),
             *gas,
         )));
@@ -132,7 +145,7 @@ impl<TC: ToolCaller> BehaviorTree for InteractivePrompt<TC> {
     }
 
     fn reset(self: &mut Self, _model: &Self::Model) {
-        // Nothing to do
+        self.mcp_fetch = None;
     }
 }
 
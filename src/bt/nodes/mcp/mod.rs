@@ -13,18 +13,30 @@ pub struct Agent<TC: ToolCaller> {
     pub ai_model: Option<TextValue>,
     pub prompt: PromptValue,
     pub tool_filters: TextValue,
+    #[serde(default)]
+    pub limits: AgentLimits,
     #[serde(skip)]
     pub join_handle: Option<
         JoinHandle<
             Result<
-                (String, Vec<BarkMessage>, BarkState, Option<i32>),
+                (
+                    String,
+                    Option<FinalAnswer>,
+                    Vec<BarkMessage>,
+                    BarkState,
+                    Option<i32>,
+                ),
                 (String, Vec<BarkMessage>, Option<i32>),
             >,
         >,
     >,
     #[serde(skip)]
+    pub mcp_fetch: Option<JoinHandle<Result<(), String>>>,
+    #[serde(skip)]
     pub prompt_id: Option<usize>,
     #[serde(skip)]
+    pub context: Option<NativeToolContext>,
+    #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
 
@@ -44,10 +56,33 @@ impl<TC: ToolCaller> BehaviorTree for Agent<TC> {
             match try_join(join_handle) {
                 Ok(result) => {
                     self.join_handle = None;
+                    if let Some(context) = self.context.take() {
+                        for (name, value) in context.take_updates() {
+                            controller
+                                .text_variables
+                                .insert(VariableId::User(name), value);
+                        }
+                    }
                     match result {
-                        Ok((output, chat, result, new_gas)) => {
+                        Ok((output, final_answer, chat, result, new_gas)) => {
                             *gas = new_gas;
                             audit.data(&"Prompt", &format!("output-{}", id), &output);
+                            if let Some(final_answer) = final_answer {
+                                audit.data(
+                                    &"Prompt",
+                                    &format!("final-answer-{}", id),
+                                    &final_answer,
+                                );
+                                for (key, value) in final_answer {
+                                    let value = match value {
+                                        serde_json::Value::String(value) => value,
+                                        value => value.to_string(),
+                                    };
+                                    controller
+                                        .text_variables
+                                        .insert(VariableId::User(key), value);
+                                }
+                            }
                             if result == BarkState::Complete {
                                 controller
                                     .text_variables
@@ -76,7 +111,16 @@ impl<TC: ToolCaller> BehaviorTree for Agent<TC> {
                 }
             }
         }
-        let prompt = controller.get_prompt(&self.prompt);
+        let prompt = match controller.resolve_mcp(&mut self.mcp_fetch, |controller| {
+            controller.try_get_prompt(&self.prompt)
+        }) {
+            None => return BarkState::Waiting,
+            Some(Ok(prompt)) => prompt,
+            Some(Err(err)) => {
+                audit.mark(&err);
+                return BarkState::Failed;
+            }
+        };
         let prompt_id = PROMPT_IDS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         self.prompt_id = Some(prompt_id);
         if prompt.is_empty() {
@@ -91,16 +135,20 @@ impl<TC: ToolCaller> BehaviorTree for Agent<TC> {
             .collect();
         let tools = model.get_tools(&tool_filters);
         let ai_model = self.ai_model.as_ref().map(|v| controller.get_text(v));
+        let context = controller.native_tool_context();
+        self.context = Some(context.clone());
         self.join_handle = Some(tokio::spawn(powered_chat(
             ai_model,
             prompt.clone(),
             model.clone(),
             *gas,
             tools.clone(),
+            self.limits.clone(),
+            context,
         )));
         BarkState::Waiting
     }
     fn reset(self: &mut Self, _model: &Self::Model) {
-        // Nothing to do
+        self.mcp_fetch = None;
     }
 }
@@ -13,7 +13,14 @@ mod wrappers;
 use serde::{Deserialize, Serialize};
 pub use wrappers::*;
 
-use crate::{clients::ToolCaller, prelude::read_tree};
+use crate::{
+    bt::{
+        ingest::{default_batch_size, ChunkStrategy},
+        vector::{SearchMode, VectorThreshold, VoteWeighting},
+    },
+    clients::ToolCaller,
+    prelude::{read_tree, AgentLimits},
+};
 
 use super::{values::*, BarkController, BarkModel, BarkState};
 
@@ -26,7 +33,7 @@ pub enum BarkNode<TC: ToolCaller> {
     StartPrompt(VariableId, PromptValue),
     ExtendPrompt(VariableId, PromptValue),
     ReplaceSystemPrompt(VariableId, PromptValue),
-    GetEmbedding(TextValue, VariableId),
+    GetEmbedding(TextValue, VariableId, #[serde(default)] Option<String>),
     // Run prompts.
     Chat(Vec<MessageValue>),
     ChatWith(TextValue, Vec<MessageValue>),
@@ -52,6 +59,14 @@ pub enum BarkNode<TC: ToolCaller> {
         tool_filters: TextValue,
         ai_model: TextValue,
     },
+    AgentWithLimits {
+        prompt: PromptValue,
+        #[serde(default)]
+        tool_filters: Option<TextValue>,
+        #[serde(default)]
+        ai_model: Option<TextValue>,
+        limits: AgentLimits,
+    },
     // Response checks
     MatchResponse(Option<TextValue>, TextMatcher, PromptValue),
     RequireInResponse(Vec<String>, PromptValue),
@@ -80,13 +95,100 @@ pub enum BarkNode<TC: ToolCaller> {
     PrintLine(TextValue),
     Unescape(VariableId),
     // Vector database
-    PushSimpleEmbedding(TextValue, TextValue),
-    PushEmbeddingKeyValues(TextValue, TextValue, Vec<(TextValue, TextValue)>),
-    PullBestScored(TextValue, TextValue),
-    PullBestQueryMatch(TextValue, TextValue),
+    // The trailing `Option<String>`s name the embedding model, defaulting to `default`.
+    PushSimpleEmbedding(TextValue, TextValue, #[serde(default)] Option<String>),
+    PushEmbeddingKeyValues(
+        TextValue,
+        TextValue,
+        Vec<(TextValue, TextValue)>,
+        #[serde(default)] Option<String>,
+    ),
+    /// Like `PushEmbeddingKeyValues`, but replaces the embedding and key/values of an entry
+    /// with the same text.
+    UpsertEmbedding(
+        TextValue,
+        TextValue,
+        #[serde(default)] Vec<(TextValue, TextValue)>,
+        #[serde(default)] Option<String>,
+    ),
+    /// Deletes the matching entries, setting `LastOutput` to how many were deleted.
+    DeleteEmbeddings(TextValue, EntryFilter),
+    PullBestScored(
+        TextValue,
+        TextValue,
+        #[serde(default)] VectorThreshold,
+        #[serde(default)] Option<String>,
+    ),
+    PullBestQueryMatch(
+        TextValue,
+        TextValue,
+        #[serde(default)] VectorThreshold,
+        #[serde(default)] Option<String>,
+    ),
+    PullBestMatch {
+        db: TextValue,
+        text: TextValue,
+        /// Prefixes the text with the `PreEmbed` variable, as in `PullBestQueryMatch`.
+        #[serde(default)]
+        query: bool,
+        #[serde(default)]
+        filters: Vec<KeyValueFilter>,
+        /// Fails instead of pulling a match that is too far from the text.
+        #[serde(default)]
+        threshold: VectorThreshold,
+        #[serde(default)]
+        embedding_model: Option<String>,
+        #[serde(default)]
+        search: SearchMode,
+    },
+    /// Labels the text by a vote among its `k` nearest entries with a `label_key` key/value,
+    /// setting `LastOutput` and `label_key` to the label and `confidence` to its share of the vote.
+    Classify {
+        db: TextValue,
+        text: TextValue,
+        /// Prefixes the text with the `PreEmbed` variable, as in `PullBestQueryMatch`.
+        #[serde(default)]
+        query: bool,
+        #[serde(default = "default_neighbors")]
+        k: usize,
+        #[serde(default = "default_label_key")]
+        label_key: String,
+        #[serde(default)]
+        weighting: VoteWeighting,
+        /// Fails instead of labeling when the winning label has a smaller share of the vote.
+        #[serde(default)]
+        min_confidence: Option<f32>,
+        #[serde(default)]
+        filters: Vec<KeyValueFilter>,
+        #[serde(default)]
+        embedding_model: Option<String>,
+    },
+    /// Chunks and pushes every document at `path`, setting `LastOutput` to the chunk count.
+    Ingest {
+        db: TextValue,
+        path: TextValue,
+        #[serde(default)]
+        chunking: ChunkStrategy,
+        #[serde(default = "default_batch_size")]
+        batch_size: usize,
+        #[serde(default)]
+        embedding_model: Option<String>,
+    },
     Phantom(std::marker::PhantomData<TC>),
 }
 
+/// Prefixes a text with the `PreEmbed` variable when it is used as a search query.
+pub(crate) fn query_text(query: bool, text: &TextValue) -> TextValue {
+    if query {
+        TextValue::Multi(vec![
+            TextValue::Variable(VariableId::PreEmbed),
+            text.clone(),
+        ])
+    } else {
+        text.clone()
+    }
+}
+
 enum Subtree<TC: ToolCaller> {
     Uninitialized(String),
     Initialized(
@@ -136,6 +238,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
             BarkNode::SetText(id, text) => Box::new(SetText::<TC>(
                 id.clone(),
                 text.clone(),
+                None,
                 std::marker::PhantomData,
             )),
             BarkNode::SetTemplate(id, template) => Box::new(SetTemplate::<TC>(
@@ -162,6 +265,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: None,
                 prompt: PromptValue::Chat(messages.clone()),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
                 _phantom: std::marker::PhantomData,
             }),
@@ -169,6 +273,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: Some(model.clone()),
                 prompt: PromptValue::Chat(messages.clone()),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
                 _phantom: std::marker::PhantomData,
             }),
@@ -176,6 +281,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: None,
                 prompt: prompt.clone(),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
                 _phantom: std::marker::PhantomData,
             }),
@@ -183,6 +289,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: Some(model.clone()),
                 prompt: prompt.clone(),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
                 _phantom: std::marker::PhantomData,
             }),
@@ -191,6 +298,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 choices: *choices,
                 prompt: PromptValue::Chat(chat.clone()),
                 join_handle: None,
+                mcp_fetch: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::InteractivePromptWith {
@@ -202,6 +310,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 choices: *choices,
                 prompt: PromptValue::Chat(chat.clone()),
                 join_handle: None,
+                mcp_fetch: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::MatchResponse(ai_model, matches, prompt) => Box::new(MatchResponse::<TC> {
@@ -209,6 +318,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 matches: matches.clone(),
                 prompt: prompt.clone(),
                 join_handle: None,
+                mcp_fetch: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::RequireInResponse(words, prompt) => Box::new(MatchResponse::<TC> {
@@ -221,6 +331,7 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ),
                 prompt: prompt.clone(),
                 join_handle: None,
+                mcp_fetch: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::RejectInResponse(words, prompt) => Box::new(MatchResponse::<TC> {
@@ -233,14 +344,18 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ))),
                 prompt: prompt.clone(),
                 join_handle: None,
+                mcp_fetch: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::Agent(prompt) => Box::new(Agent::<TC> {
                 ai_model: None,
                 prompt: prompt.clone(),
                 tool_filters: TextValue::Simple(String::new()), // Default to no filters
+                limits: AgentLimits::default(),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
+                context: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::AgentWithFilters {
@@ -250,8 +365,11 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: None,
                 prompt: prompt.clone(),
                 tool_filters: tool_filters.clone(),
+                limits: AgentLimits::default(),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
+                context: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::AgentWithFiltersAndModel {
@@ -262,8 +380,29 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
                 ai_model: Some(ai_model.clone()),
                 prompt: prompt.clone(),
                 tool_filters: tool_filters.clone(),
+                limits: AgentLimits::default(),
                 join_handle: None,
+                mcp_fetch: None,
                 prompt_id: None,
+                context: None,
+                _phantom: std::marker::PhantomData,
+            }),
+            BarkNode::AgentWithLimits {
+                prompt,
+                tool_filters,
+                ai_model,
+                limits,
+            } => Box::new(Agent::<TC> {
+                ai_model: ai_model.clone(),
+                prompt: prompt.clone(),
+                tool_filters: tool_filters
+                    .clone()
+                    .unwrap_or(TextValue::Simple(String::new())),
+                limits: limits.clone(),
+                join_handle: None,
+                mcp_fetch: None,
+                prompt_id: None,
+                context: None,
                 _phantom: std::marker::PhantomData,
             }),
             BarkNode::SaveFile { path, content } => Box::new(SaveFile::<TC> {
@@ -303,39 +442,129 @@ impl<TC: ToolCaller> UserNodeDefinition for BarkNode<TC> {
             BarkNode::Unescape(id) => {
                 Box::new(Unescape::<TC>(id.clone(), std::marker::PhantomData))
             }
-            BarkNode::GetEmbedding(text, id) => Box::new(GetEmbedding::<TC> {
+            BarkNode::GetEmbedding(text, id, embedding_model) => Box::new(GetEmbedding::<TC> {
                 text: text.clone(),
                 variable: id.clone(),
+                embedding_model: embedding_model.clone(),
                 join_handle: None,
                 _phantom: std::marker::PhantomData,
             }),
-            BarkNode::PushSimpleEmbedding(path, text) => Box::new(PushSimpleEmbedding::<TC> {
+            BarkNode::PushSimpleEmbedding(path, text, embedding_model) => {
+                Box::new(PushSimpleEmbedding::<TC> {
+                    db: path.clone(),
+                    text: text.clone(),
+                    embedding_model: embedding_model.clone(),
+                    join_handle: None,
+                    _phantom: std::marker::PhantomData,
+                })
+            }
+            BarkNode::PushEmbeddingKeyValues(path, text, values, embedding_model) => {
+                Box::new(PushValuedEmbedding::<TC> {
+                    db: path.clone(),
+                    text: text.clone(),
+                    kvs: values.clone(),
+                    embedding_model: embedding_model.clone(),
+                    upsert: false,
+                    join_handle: None,
+                    _phantom: std::marker::PhantomData,
+                })
+            }
+            BarkNode::UpsertEmbedding(path, text, values, embedding_model) => {
+                Box::new(PushValuedEmbedding::<TC> {
+                    db: path.clone(),
+                    text: text.clone(),
+                    kvs: values.clone(),
+                    embedding_model: embedding_model.clone(),
+                    upsert: true,
+                    join_handle: None,
+                    _phantom: std::marker::PhantomData,
+                })
+            }
+            BarkNode::DeleteEmbeddings(path, filter) => Box::new(DeleteEmbeddings::<TC> {
                 db: path.clone(),
-                text: text.clone(),
+                filter: filter.clone(),
                 join_handle: None,
                 _phantom: std::marker::PhantomData,
             }),
-            BarkNode::PushEmbeddingKeyValues(path, text, values) => {
-                Box::new(PushValuedEmbedding::<TC> {
+            BarkNode::PullBestScored(path, text, threshold, embedding_model) => {
+                Box::new(PullBestScored::<TC> {
                     db: path.clone(),
                     text: text.clone(),
-                    kvs: values.clone(),
+                    filters: vec![],
+                    threshold: *threshold,
+                    embedding_model: embedding_model.clone(),
+                    search: SearchMode::Vector,
                     join_handle: None,
                     _phantom: std::marker::PhantomData,
                 })
             }
-            BarkNode::PullBestScored(path, text) => Box::new(PullBestScored::<TC> {
-                db: path.clone(),
-                text: text.clone(),
+            BarkNode::PullBestQueryMatch(path, text, threshold, embedding_model) => {
+                Box::new(PullBestScored::<TC> {
+                    db: path.clone(),
+                    text: TextValue::Multi(vec![
+                        TextValue::Variable(VariableId::PreEmbed),
+                        text.clone(),
+                    ]),
+                    filters: vec![],
+                    threshold: *threshold,
+                    embedding_model: embedding_model.clone(),
+                    search: SearchMode::Vector,
+                    join_handle: None,
+                    _phantom: std::marker::PhantomData,
+                })
+            }
+            BarkNode::PullBestMatch {
+                db,
+                text,
+                query,
+                filters,
+                threshold,
+                embedding_model,
+                search,
+            } => Box::new(PullBestScored::<TC> {
+                db: db.clone(),
+                text: query_text(*query, text),
+                filters: filters.clone(),
+                threshold: *threshold,
+                embedding_model: embedding_model.clone(),
+                search: *search,
                 join_handle: None,
                 _phantom: std::marker::PhantomData,
             }),
-            BarkNode::PullBestQueryMatch(path, text) => Box::new(PullBestScored::<TC> {
-                db: path.clone(),
-                text: TextValue::Multi(vec![
-                    TextValue::Variable(VariableId::PreEmbed),
-                    text.clone(),
-                ]),
+            BarkNode::Classify {
+                db,
+                text,
+                query,
+                k,
+                label_key,
+                weighting,
+                min_confidence,
+                filters,
+                embedding_model,
+            } => Box::new(Classify::<TC> {
+                db: db.clone(),
+                text: query_text(*query, text),
+                k: *k,
+                label_key: label_key.clone(),
+                weighting: *weighting,
+                min_confidence: *min_confidence,
+                filters: filters.clone(),
+                embedding_model: embedding_model.clone(),
+                join_handle: None,
+                _phantom: std::marker::PhantomData,
+            }),
+            BarkNode::Ingest {
+                db,
+                path,
+                chunking,
+                batch_size,
+                embedding_model,
+            } => Box::new(Ingest::<TC> {
+                db: db.clone(),
+                path: path.clone(),
+                chunking: chunking.clone(),
+                batch_size: *batch_size,
+                embedding_model: embedding_model.clone(),
                 join_handle: None,
                 _phantom: std::marker::PhantomData,
             }),
@@ -15,6 +15,8 @@ pub struct Prompt<TC: ToolCaller> {
     #[serde(skip)]
     pub prompt_id: Option<usize>,
     #[serde(skip)]
+    pub mcp_fetch: Option<JoinHandle<Result<(), String>>>,
+    #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
 
@@ -73,7 +75,16 @@ impl<TC: ToolCaller> BehaviorTree for Prompt<TC> {
                 }
             }
         }
-        let prompt = controller.get_prompt(&self.prompt);
+        let prompt = match controller.resolve_mcp(&mut self.mcp_fetch, |controller| {
+            controller.try_get_prompt(&self.prompt)
+        }) {
+            None => return BarkState::Waiting,
+            Some(Ok(prompt)) => prompt,
+            Some(Err(err)) => {
+                audit.mark(&err);
+                return BarkState::Failed;
+            }
+        };
         let prompt_id = PROMPT_IDS.fetch_add(1, std::sync::atomic::Ordering::Relaxed);
         self.prompt_id = Some(prompt_id);
         audit.data(&"Prompt", &format!("prompt-{}", prompt_id), &prompt);
@@ -94,6 +105,7 @@ impl<TC: ToolCaller> BehaviorTree for Prompt<TC> {
     fn reset(self: &mut Self, _model: &Self::Model) {
         // Nothing to do
         self.join_handle = None;
+        self.mcp_fetch = None;
         self.prompt_id = None;
     }
 }
@@ -107,6 +119,8 @@ pub struct MatchResponse<TC: ToolCaller> {
     pub join_handle:
         Option<JoinHandle<Result<(String, BarkState, Option<i32>), (String, Option<i32>)>>>,
     #[serde(skip)]
+    pub mcp_fetch: Option<JoinHandle<Result<(), String>>>,
+    #[serde(skip)]
     pub _phantom: std::marker::PhantomData<TC>,
 }
 
@@ -163,7 +177,16 @@ impl<TC: ToolCaller> BehaviorTree for MatchResponse<TC> {
                 }
             }
         }
-        let prompt = controller.get_prompt(&self.prompt);
+        let prompt = match controller.resolve_mcp(&mut self.mcp_fetch, |controller| {
+            controller.try_get_prompt(&self.prompt)
+        }) {
+            None => return BarkState::Waiting,
+            Some(Ok(prompt)) => prompt,
+            Some(Err(err)) => {
+                audit.mark(&err);
+                return BarkState::Failed;
+            }
+        };
         audit.data(&"MatchResponse", &"prompt", &prompt);
         if prompt.is_empty() {
             // eprintln!("Prompt {:?} is empty", self.prompt);
@@ -181,5 +204,6 @@ impl<TC: ToolCaller> BehaviorTree for MatchResponse<TC> {
 
     fn reset(self: &mut Self, _model: &Self::Model) {
         self.join_handle = None;
+        self.mcp_fetch = None;
     }
 }
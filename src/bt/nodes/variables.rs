@@ -6,6 +6,7 @@ use crate::prelude::*;
 pub struct SetText<TC: ToolCaller>(
     pub VariableId,
     pub TextValue,
+    #[serde(skip)] pub Option<JoinHandle<Result<(), String>>>,
     #[serde(skip)] pub std::marker::PhantomData<TC>,
 );
 
@@ -20,13 +21,25 @@ impl<TC: ToolCaller> BehaviorTree for SetText<TC> {
         _gas: &mut Option<i32>,
         mut _audit: &mut Option<BehaviorTreeAudit>,
     ) -> BarkState {
-        let text = controller.get_text(&self.1);
-        controller.text_variables.insert(self.0.clone(), text);
-        BarkState::Complete
+        let Some(text) =
+            controller.resolve_mcp(&mut self.2, |controller| controller.try_get_text(&self.1))
+        else {
+            return BarkState::Waiting;
+        };
+        match text {
+            Ok(text) => {
+                controller.text_variables.insert(self.0.clone(), text);
+                BarkState::Complete
+            }
+            Err(err) => {
+                _audit.mark(&err);
+                BarkState::Failed
+            }
+        }
     }
 
     fn reset(self: &mut Self, _model: &Self::Model) {
-        // Nothing to do
+        self.2 = None;
     }
 }
 
@@ -61,6 +74,8 @@ impl<TC: ToolCaller> BehaviorTree for SetTemplate<TC> {
 pub struct GetEmbedding<TC: ToolCaller> {
     pub text: TextValue,
     pub variable: VariableId,
+    #[serde(default)]
+    pub embedding_model: Option<String>,
     #[serde(skip)]
     pub join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
     #[serde(skip)]
@@ -107,7 +122,11 @@ impl<TC: ToolCaller> BehaviorTree for GetEmbedding<TC> {
         }
         let text = controller.get_text(&self.text);
         let model = model.clone();
-        self.join_handle = Some(tokio::spawn(model.get_embedding(text, *gas)));
+        self.join_handle = Some(tokio::spawn(model.get_embedding(
+            self.embedding_model.clone(),
+            text,
+            *gas,
+        )));
         BarkState::Waiting
     }
 
@@ -1,97 +1,192 @@
-// use crate::prelude::*;
-// use tokio::task::{block_in_place, JoinHandle};
-//
-// pub struct BranchByScore {
-//     compared: TextValue,
-//     compared_embedding: Vec<f32>,
-//     text_values: Vec<TextValue>,
-//     best_index: Option<usize>,
-//     nodes: Vec<Box<dyn BehaviorTree<Model = BarkModel, Controller = BarkController> + Send + Sync>>,
-//     join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
-// }
+use crate::{bt::vector::closest_embedding, prelude::*};
+use tokio::task::JoinHandle;
 
-// impl BranchByScore {
-//     pub fn new(
-//         compared: TextValue,
-//         text_values: Vec<TextValue>,
-//         nodes: Vec<
-//             Box<dyn BehaviorTree<Model = BarkModel, Controller = BarkController> + Send + Sync>,
-//         >,
-//     ) -> Self {
-//         if nodes.len() != text_values.len() {
-//             panic!("BranchByScore nodes and text_values must have the same length");
-//         }
-//         Self {
-//             compared,
-//             compared_embedding: vec![],
-//             text_values,
-//             best_index: None,
-//             nodes,
-//             join_handle: None,
-//         }
-//     }
-// }
+pub struct BranchByScore<TC: ToolCaller> {
+    compared: TextValue,
+    text_values: Vec<TextValue>,
+    threshold: VectorThreshold,
+    embedding_model: Option<String>,
+    /// Option embeddings are kept across resets, so each option is only embedded once.
+    option_embeddings: HashMap<String, Vec<f32>>,
+    /// The option texts, resolved when their embeddings are requested.
+    options: Vec<String>,
+    embedding_texts: Vec<String>,
+    best_index: Option<usize>,
+    nodes: Vec<
+        Box<dyn BehaviorTree<Model = BarkModel<TC>, Controller = BarkController> + Send + Sync>,
+    >,
+    join_handle: Option<JoinHandle<Result<(Vec<Vec<f32>>, Option<i32>), String>>>,
+}
 
-// impl BehaviorTree for BranchByScore {
-//     type Controller = BarkController;
-//     type Model = BarkModel;
+impl<TC: ToolCaller> BranchByScore<TC> {
+    pub fn new(
+        compared: TextValue,
+        text_values: Vec<TextValue>,
+        nodes: Vec<
+            Box<dyn BehaviorTree<Model = BarkModel<TC>, Controller = BarkController> + Send + Sync>,
+        >,
+    ) -> Self {
+        if nodes.len() != text_values.len() {
+            panic!("BranchByScore nodes and text_values must have the same length");
+        }
+        Self {
+            compared,
+            text_values,
+            threshold: VectorThreshold::default(),
+            embedding_model: None,
+            option_embeddings: HashMap::new(),
+            options: vec![],
+            embedding_texts: vec![],
+            best_index: None,
+            nodes,
+            join_handle: None,
+        }
+    }
 
-//     fn resume_with(
-//         self: &mut Self,
-//         model: &Self::Model,
-//         controller: &mut Self::Controller,
-//         gas: &mut Option<i32>,
-//         mut _audit: &mut Option<BehaviorTreeAudit>,
-//     ) -> BarkState {
-//         if self.compared_embedding.is_empty() && self.join_handle.is_none() {
-//             let compared_text = controller.get_text(&self.compared);
-//             self.join_handle = Some(tokio::spawn(model.get_embedding(&compared_text, gas)));
-//             return BarkState::Waiting;
-//         } else if let Some(join_handle) = &mut self.join_handle {
-//             if let Ok(result) = try_join(join_handle) {
-//                 let compared_embedding = result.0;
-//                 *gas = result.1;
-//                 check_gas!(gas);
-//                 if let Ok(embedding) = embedding {
-//                     self.compared_embedding = embedding;
-//                 } else {
-//                     return BarkState::Failed;
-//                 }
-//             } else {
-//                 return BarkState::Waiting;
-//             }
-//         }
-//         if self.best_index.is_none() {
-//             let mut best_score = f32::INFINITY;
-//             let mut best_index = 0;
-//             for (index, text_value) in self.text_values.iter().enumerate() {
-//                 let text_value = controller.get_text(text_value);
-//                 let score = block_in_place(model.get_embedding(&text_value, gas))
-//                     .map(|(embedding, _)| score(&self.compared_embedding, &embedding));
-//                 check_gas!(gas);
-//                 if let Ok(score) = score {
-//                     if score < best_score {
-//                         best_score = score;
-//                         best_index = index;
-//                     }
-//                 }
-//             }
-//             self.best_index = Some(best_index);
-//         }
-//         if let Some(best_index) = self.best_index {
-//             let matched = controller.get_text(&self.text_values[best_index]);
-//             controller
-//                 .text_variables
-//                 .insert(VariableId::LoopValue, matched);
-//             let node = self.nodes.get_mut(best_index).unwrap();
-//             node.resume_with(model, controller, gas, _audit)
-//         } else {
-//             BarkState::Failed
-//         }
-//     }
+    /// Fails instead of branching when even the closest option is outside the threshold.
+    pub fn with_threshold(mut self, threshold: VectorThreshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
 
-//     fn reset(self: &mut Self, _model: &Self::Model) {
-//         self.compared_embedding = vec![];
-//         self.best_index = None;
-//     }
-// }
+    /// Embeds the texts with the named embedding model instead of the default one.
+    pub fn with_embedding_model(mut self, embedding_model: Option<String>) -> Self {
+        self.embedding_model = embedding_model;
+        self
+    }
+
+    /// Binds the option closest to the compared embedding, if it is within the threshold.
+    fn choose(
+        &mut self,
+        model: &BarkModel<TC>,
+        controller: &mut BarkController,
+        compared_embedding: &[f32],
+        mut audit: &mut Option<BehaviorTreeAudit>,
+    ) -> bool {
+        let Some(embeddings) = self
+            .options
+            .iter()
+            .map(|option| self.option_embeddings.get(option).cloned())
+            .collect::<Option<Vec<_>>>()
+        else {
+            audit.mark(&"Missing an option embedding");
+            return false;
+        };
+        let metric = model.embedding_metric(self.embedding_model.as_deref());
+        let Some((best_index, distance)) =
+            closest_embedding(compared_embedding, &embeddings, metric)
+        else {
+            return false;
+        };
+        let best = self.options[best_index].clone();
+        if !self.threshold.accepts_distance(distance, metric) {
+            audit.mark(&format!("No option close enough, best was: {}", best));
+            return false;
+        }
+        controller.text_variables.insert(
+            VariableId::User("distance".to_string()),
+            distance.to_string(),
+        );
+        controller.text_variables.insert(
+            VariableId::User("similarity".to_string()),
+            metric.similarity_from_distance(distance).to_string(),
+        );
+        controller
+            .text_variables
+            .insert(VariableId::LoopValue, best);
+        self.best_index = Some(best_index);
+        true
+    }
+}
+
+impl<TC: ToolCaller> BehaviorTree for BranchByScore<TC> {
+    type Controller = BarkController;
+    type Model = BarkModel<TC>;
+
+    fn resume_with(
+        self: &mut Self,
+        model: &Self::Model,
+        controller: &mut Self::Controller,
+        gas: &mut Option<i32>,
+        mut _audit: &mut Option<BehaviorTreeAudit>,
+    ) -> BarkState {
+        if self.best_index.is_none() && self.join_handle.is_none() {
+            let compared_text = controller.get_text(&self.compared);
+            self.options = self
+                .text_values
+                .iter()
+                .map(|text_value| controller.get_text(text_value))
+                .collect();
+            let mut texts = vec![compared_text];
+            for option in &self.options {
+                if !self.option_embeddings.contains_key(option) && !texts[1..].contains(option) {
+                    texts.push(option.clone());
+                }
+            }
+            self.embedding_texts = texts.clone();
+            self.join_handle = Some(tokio::spawn(model.clone().get_embeddings(
+                self.embedding_model.clone(),
+                texts,
+                *gas,
+            )));
+            return BarkState::Waiting;
+        } else if let Some(join_handle) = &mut self.join_handle {
+            match try_join(join_handle) {
+                Ok(result) => {
+                    self.join_handle = None;
+                    match result {
+                        Ok((embeddings, new_gas)) => {
+                            *gas = new_gas;
+                            check_gas!(gas);
+                            if embeddings.len() != self.embedding_texts.len() {
+                                _audit.mark(&format!(
+                                    "Expected {} embeddings, got {}",
+                                    self.embedding_texts.len(),
+                                    embeddings.len()
+                                ));
+                                return BarkState::Failed;
+                            }
+                            let mut embeddings = embeddings.into_iter();
+                            let Some(compared_embedding) = embeddings.next() else {
+                                return BarkState::Failed;
+                            };
+                            for (option, embedding) in
+                                self.embedding_texts.drain(..).skip(1).zip(embeddings)
+                            {
+                                self.option_embeddings.insert(option, embedding);
+                            }
+                            if !self.choose(model, controller, &compared_embedding, _audit) {
+                                return BarkState::Failed;
+                            }
+                        }
+                        Err(err) => {
+                            _audit.mark(&format!("Failed to embed options: {}", err));
+                            return BarkState::Failed;
+                        }
+                    }
+                }
+                Err(join_failed) => {
+                    if join_failed {
+                        self.join_handle = None;
+                        return BarkState::Failed;
+                    } else {
+                        return BarkState::Waiting;
+                    }
+                }
+            }
+        }
+        match self.best_index {
+            Some(best_index) => self.nodes[best_index].resume_with(model, controller, gas, _audit),
+            None => BarkState::Failed,
+        }
+    }
+
+    fn reset(self: &mut Self, model: &Self::Model) {
+        if let Some(join_handle) = self.join_handle.take() {
+            join_handle.abort();
+        }
+        if let Some(best_index) = self.best_index.take() {
+            self.nodes[best_index].reset(model);
+        }
+    }
+}
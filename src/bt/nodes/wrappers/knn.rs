@@ -1,14 +1,28 @@
-use crate::prelude::*;
+use crate::{
+    bt::{
+        rerank::{rerank, RerankConfig},
+        vector::{MmrOptions, VectorMatch},
+    },
+    prelude::*,
+};
 use tokio::task::JoinHandle;
 
 pub struct Knn<TC: ToolCaller> {
     path: String,
     compared: TextValue,
     k: usize,
+    filters: Vec<KeyValueFilter>,
+    threshold: VectorThreshold,
+    embedding_model: Option<String>,
+    search: SearchMode,
+    mmr: Option<MmrOptions>,
+    rerank: Option<RerankConfig>,
     current: usize,
-    results: Vec<String>,
+    results: Vec<VectorMatch>,
+    rerank_scores: Vec<f32>,
     node: Box<dyn BehaviorTree<Model = BarkModel<TC>, Controller = BarkController> + Send + Sync>,
-    join_handle: Option<JoinHandle<Result<(Vec<f32>, Option<i32>), String>>>,
+    join_handle: Option<JoinHandle<Result<(Vec<VectorMatch>, Option<i32>), String>>>,
+    rerank_handle: Option<JoinHandle<Result<(Vec<(VectorMatch, f32)>, Option<i32>), String>>>,
 }
 
 impl<TC: ToolCaller> Knn<TC> {
@@ -24,12 +38,62 @@ impl<TC: ToolCaller> Knn<TC> {
             path,
             compared,
             k,
+            filters: vec![],
+            threshold: VectorThreshold::default(),
+            embedding_model: None,
+            search: SearchMode::Vector,
+            mmr: None,
+            rerank: None,
             current: 0,
             results: vec![],
+            rerank_scores: vec![],
             node: nodes.pop().unwrap(),
             join_handle: None,
+            rerank_handle: None,
         }
     }
+
+    /// Only iterates over entries whose key/values pass every filter.
+    pub fn with_filters(mut self, filters: Vec<KeyValueFilter>) -> Self {
+        self.filters = filters;
+        self
+    }
+
+    /// Only iterates over entries within the threshold, failing if there are none.
+    pub fn with_threshold(mut self, threshold: VectorThreshold) -> Self {
+        self.threshold = threshold;
+        self
+    }
+
+    /// Embeds the compared text with the named embedding model instead of the default one.
+    pub fn with_embedding_model(mut self, embedding_model: Option<String>) -> Self {
+        self.embedding_model = embedding_model;
+        self
+    }
+
+    pub fn with_search(mut self, search: SearchMode) -> Self {
+        self.search = search;
+        self
+    }
+
+    /// Trades relevance against diversity, so that near-duplicates are not all iterated over.
+    pub fn with_mmr(mut self, mmr: Option<MmrOptions>) -> Self {
+        self.mmr = mmr;
+        self
+    }
+
+    /// Over-fetches candidates and keeps the `k` a chat model finds most relevant.
+    pub fn with_rerank(mut self, rerank: Option<RerankConfig>) -> Self {
+        self.rerank = rerank;
+        self
+    }
+
+    fn candidates(&self) -> usize {
+        self.rerank
+            .as_ref()
+            .map(|rerank| rerank.candidates(self.k))
+            .unwrap_or(self.k)
+    }
 }
 
 impl<TC: ToolCaller> BehaviorTree for Knn<TC> {
@@ -43,33 +107,55 @@ impl<TC: ToolCaller> BehaviorTree for Knn<TC> {
         gas: &mut Option<i32>,
         mut _audit: &mut Option<BehaviorTreeAudit>,
     ) -> BarkState {
-        if self.results.is_empty() && self.join_handle.is_none() {
+        if self.results.is_empty() && self.join_handle.is_none() && self.rerank_handle.is_none() {
             let compared_text = controller.get_text(&self.compared);
+            let predicates = controller.get_key_value_predicates(&self.filters);
+            let path = controller.scoped_db_path(model, &self.path);
             let model = model.clone();
-            self.join_handle = Some(tokio::spawn(model.get_embedding(compared_text, *gas)));
+            self.join_handle = Some(tokio::spawn(model.search_text(
+                self.embedding_model.clone(),
+                path,
+                compared_text,
+                self.candidates(),
+                predicates,
+                self.search,
+                self.mmr,
+                *gas,
+            )));
             return BarkState::Waiting;
         } else if let Some(join_handle) = &mut self.join_handle {
             match try_join(join_handle) {
                 Ok(result) => {
                     self.join_handle = None; // Clear the join handle after completion
-                    if let Ok(result) = result {
-                        let compared_embedding = result.0;
-                        *gas = result.1;
-                        check_gas!(gas);
-                        match model.pull_best_matches(&self.path, compared_embedding, self.k) {
-                            Ok(results) => {
-                                if results.is_empty() {
-                                    return BarkState::Failed;
-                                }
-                                self.results = results;
-                                self.current = 0;
-                            }
-                            Err(_) => {
+                    match result {
+                        Ok((mut results, new_gas)) => {
+                            *gas = new_gas;
+                            check_gas!(gas);
+                            results.retain(|result| self.threshold.accepts(result));
+                            if results.is_empty() {
                                 return BarkState::Failed;
                             }
+                            if let Some(config) = &self.rerank {
+                                let compared_text = controller.get_text(&self.compared);
+                                self.rerank_handle = Some(tokio::spawn(rerank(
+                                    model.clone(),
+                                    config.clone(),
+                                    compared_text,
+                                    results,
+                                    self.k,
+                                    *gas,
+                                )));
+                                return BarkState::Waiting;
+                            }
+                            results.truncate(self.k);
+                            self.results = results;
+                            self.rerank_scores = vec![];
+                            self.current = 0;
+                        }
+                        Err(err) => {
+                            _audit.mark(&err);
+                            return BarkState::Failed;
                         }
-                    } else {
-                        return BarkState::Failed;
                     }
                 }
                 Err(join_failed) => {
@@ -81,9 +167,49 @@ impl<TC: ToolCaller> BehaviorTree for Knn<TC> {
                     }
                 }
             }
+        } else if let Some(rerank_handle) = &mut self.rerank_handle {
+            match try_join(rerank_handle) {
+                Ok(result) => {
+                    self.rerank_handle = None;
+                    match result {
+                        Ok((reranked, new_gas)) => {
+                            *gas = new_gas;
+                            check_gas!(gas);
+                            if reranked.is_empty() {
+                                return BarkState::Failed;
+                            }
+                            (self.results, self.rerank_scores) = reranked.into_iter().unzip();
+                            self.current = 0;
+                        }
+                        Err(err) => {
+                            _audit.mark(&format!("Failed to rerank: {}", err));
+                            return BarkState::Failed;
+                        }
+                    }
+                }
+                Err(join_failed) => {
+                    if join_failed {
+                        self.rerank_handle = None;
+                        return BarkState::Failed;
+                    } else {
+                        return BarkState::Waiting;
+                    }
+                }
+            }
         }
         while self.current < self.results.len() {
-            let text_value = self.results[self.current].clone();
+            let previous = self
+                .current
+                .checked_sub(1)
+                .map(|index| &self.results[index]);
+            controller.bind_vector_match(previous, &self.results[self.current]);
+            if let Some(score) = self.rerank_scores.get(self.current) {
+                controller.text_variables.insert(
+                    VariableId::User("rerank_score".to_string()),
+                    score.to_string(),
+                );
+            }
+            let text_value = self.results[self.current].text.clone();
             controller
                 .text_variables
                 .insert(VariableId::LoopValue, text_value);
@@ -109,6 +235,7 @@ impl<TC: ToolCaller> BehaviorTree for Knn<TC> {
     fn reset(self: &mut Self, model: &Self::Model) {
         self.current = 0;
         self.results = vec![];
+        self.rerank_scores = vec![];
         self.node.reset(model);
     }
 }
@@ -1,7 +1,9 @@
+use super::query_text;
+use crate::bt::rerank::RerankConfig;
 use crate::prelude::*;
 
 mod branch_by_score;
-// pub use branch_by_score::BranchByScore;
+pub use branch_by_score::BranchByScore;
 mod interrogate;
 pub use interrogate::Interrogate;
 mod knn;
@@ -14,9 +16,52 @@ pub use repeat_until::RepeatUntil;
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum BarkWrapper<TC: ToolCaller> {
     Interrogate(TextValue),
-    // BranchByScore(TextValue, Vec<TextValue>),
-    Knn(String, TextValue, usize),
-    KnnQuery(String, TextValue, usize),
+    /// Runs the child whose option is closest to the compared text.
+    BranchByScore(
+        TextValue,
+        Vec<TextValue>,
+        #[serde(default)] VectorThreshold,
+        #[serde(default)] Option<String>,
+    ),
+    Knn(
+        String,
+        TextValue,
+        usize,
+        #[serde(default)] VectorThreshold,
+        #[serde(default)] Option<String>,
+        #[serde(default)] Option<MmrOptions>,
+    ),
+    KnnQuery(
+        String,
+        TextValue,
+        usize,
+        #[serde(default)] VectorThreshold,
+        #[serde(default)] Option<String>,
+        #[serde(default)] Option<MmrOptions>,
+    ),
+    KnnWith {
+        path: String,
+        compared: TextValue,
+        k: usize,
+        /// Prefixes the compared text with the `PreEmbed` variable, as in `KnnQuery`.
+        #[serde(default)]
+        query: bool,
+        #[serde(default)]
+        filters: Vec<KeyValueFilter>,
+        /// Skips entries too far from the compared text.
+        #[serde(default)]
+        threshold: VectorThreshold,
+        #[serde(default)]
+        embedding_model: Option<String>,
+        #[serde(default)]
+        search: SearchMode,
+        /// Diversifies the entries by maximal marginal relevance.
+        #[serde(default)]
+        mmr: Option<MmrOptions>,
+        /// Reorders over-fetched candidates with a chat model before iterating.
+        #[serde(default)]
+        rerank: Option<RerankConfig>,
+    },
     Repl(Option<TextValue>, Vec<TextValue>),
     RepeatUntil,
     Phantom(std::marker::PhantomData<TC>),
@@ -34,21 +79,51 @@ impl<TC: ToolCaller> UserWrapperDefinition<BarkNode<TC>> for BarkWrapper<TC> {
             BarkWrapper::Interrogate(text_value) => {
                 Box::new(Interrogate::<TC>::new(text_value.clone(), nodes))
             }
-            // BarkWrapper::BranchByScore(compared, options) => {
-            //     Box::new(BranchByScore::new(compared.clone(), options.clone(), nodes))
-            // }
-            BarkWrapper::Knn(path, compared, k) => {
-                Box::new(Knn::<TC>::new(path.clone(), compared.clone(), *k, nodes))
-            }
-            BarkWrapper::KnnQuery(path, compared, k) => Box::new(Knn::<TC>::new(
-                path.clone(),
-                TextValue::Multi(vec![
-                    TextValue::Variable(VariableId::PreEmbed),
-                    compared.clone(),
-                ]),
-                *k,
-                nodes,
-            )),
+            BarkWrapper::BranchByScore(compared, options, threshold, embedding_model) => Box::new(
+                BranchByScore::<TC>::new(compared.clone(), options.clone(), nodes)
+                    .with_threshold(*threshold)
+                    .with_embedding_model(embedding_model.clone()),
+            ),
+            BarkWrapper::Knn(path, compared, k, threshold, embedding_model, mmr) => Box::new(
+                Knn::<TC>::new(path.clone(), compared.clone(), *k, nodes)
+                    .with_threshold(*threshold)
+                    .with_embedding_model(embedding_model.clone())
+                    .with_mmr(*mmr),
+            ),
+            BarkWrapper::KnnQuery(path, compared, k, threshold, embedding_model, mmr) => Box::new(
+                Knn::<TC>::new(
+                    path.clone(),
+                    TextValue::Multi(vec![
+                        TextValue::Variable(VariableId::PreEmbed),
+                        compared.clone(),
+                    ]),
+                    *k,
+                    nodes,
+                )
+                .with_threshold(*threshold)
+                .with_embedding_model(embedding_model.clone())
+                .with_mmr(*mmr),
+            ),
+            BarkWrapper::KnnWith {
+                path,
+                compared,
+                k,
+                query,
+                filters,
+                threshold,
+                embedding_model,
+                search,
+                mmr,
+                rerank,
+            } => Box::new(
+                Knn::<TC>::new(path.clone(), query_text(*query, compared), *k, nodes)
+                    .with_filters(filters.clone())
+                    .with_threshold(*threshold)
+                    .with_embedding_model(embedding_model.clone())
+                    .with_search(*search)
+                    .with_mmr(*mmr)
+                    .with_rerank(rerank.clone()),
+            ),
             BarkWrapper::Repl(prompt, options) => {
                 Box::new(Repl::<TC>::new(prompt.clone(), options.clone(), nodes))
             }
@@ -0,0 +1,189 @@
+use serde::{Deserialize, Serialize};
+
+use super::{strip_thoughts, vector::VectorMatch, BarkModel};
+use crate::clients::{BarkResponse, ToolCaller};
+use crate::prelude::{system, user};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum RerankMode {
+    /// Asks the model to rate each candidate on its own.
+    #[default]
+    Pointwise,
+    /// Asks the model to order all of the candidates at once.
+    Listwise,
+}
+
+fn default_oversample() -> usize {
+    3
+}
+
+/// Reorders retrieved candidates by asking a chat model how relevant they are to the query.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct RerankConfig {
+    pub ai_model: String,
+    #[serde(default)]
+    pub mode: RerankMode,
+    /// How many candidates are retrieved per result kept.
+    #[serde(default = "default_oversample")]
+    pub oversample: usize,
+}
+
+impl RerankConfig {
+    pub fn candidates(&self, k: usize) -> usize {
+        k * self.oversample.max(1)
+    }
+}
+
+const POINTWISE_PROMPT: &str = "Rate how relevant the passage is to the query, from 0 (irrelevant) to 10 (answers it exactly). Reply with only the number.";
+const LISTWISE_PROMPT: &str = "Order the numbered passages by how relevant they are to the query, most relevant first. Reply with only the passage numbers, separated by commas.";
+
+/// Reads a 0-10 rating, scaled to 0-1.
+pub fn parse_relevance_score(response: &str) -> Option<f32> {
+    let response = strip_thoughts(&response.to_string());
+    let start = response.find(|c: char| c.is_ascii_digit())?;
+    let number = response[start..]
+        .chars()
+        .take_while(|c| c.is_ascii_digit() || *c == '.')
+        .collect::<String>();
+    number
+        .trim_end_matches('.')
+        .parse::<f32>()
+        .ok()
+        .map(|score| (score / 10.0).clamp(0.0, 1.0))
+}
+
+/// Reads an ordering of the passages numbered `1..=count`, as indices into the passages.
+/// Passages the model left out follow in their original order.
+pub fn parse_ranking(response: &str, count: usize) -> Vec<usize> {
+    let response = strip_thoughts(&response.to_string());
+    let mut ranking = vec![];
+    for number in response
+        .split(|c: char| !c.is_ascii_digit())
+        .filter_map(|number| number.parse::<usize>().ok())
+    {
+        if number >= 1 && number <= count && !ranking.contains(&(number - 1)) {
+            ranking.push(number - 1);
+        }
+    }
+    for index in 0..count {
+        if !ranking.contains(&index) {
+            ranking.push(index);
+        }
+    }
+    ranking
+}
+
+async fn complete<TC: ToolCaller>(
+    model: &BarkModel<TC>,
+    ai_model: &str,
+    instructions: &str,
+    prompt: String,
+    gas: &mut Option<i32>,
+) -> Result<String, String> {
+    let chat = vec![system(&instructions), user(&prompt)];
+    match model
+        .clone()
+        .chat_completion_create(Some(ai_model.to_string()), chat.into(), vec![])
+        .await?
+    {
+        BarkResponse::Chat { mut choices, usage } if !choices.is_empty() => {
+            if let Some(gas) = gas {
+                *gas -= usage.unwrap_or(1000) as i32;
+            }
+            Ok(choices.remove(0).value)
+        }
+        response => Err(format!("Unexpected rerank response: {:?}", response)),
+    }
+}
+
+/// Scores each candidate against `query`, returning the best `k` with their scores, best first.
+pub async fn rerank<TC: ToolCaller>(
+    model: BarkModel<TC>,
+    config: RerankConfig,
+    query: String,
+    candidates: Vec<VectorMatch>,
+    k: usize,
+    mut gas: Option<i32>,
+) -> Result<(Vec<(VectorMatch, f32)>, Option<i32>), String> {
+    let mut scored = match config.mode {
+        RerankMode::Pointwise => {
+            let mut scored = vec![];
+            for candidate in candidates {
+                let prompt = format!("Query: {}\n\nPassage: {}", query, candidate.text);
+                let response =
+                    complete(&model, &config.ai_model, POINTWISE_PROMPT, prompt, &mut gas).await?;
+                let score = parse_relevance_score(&response).unwrap_or(0.0);
+                scored.push((candidate, score));
+            }
+            scored
+        }
+        RerankMode::Listwise => {
+            let passages = candidates
+                .iter()
+                .enumerate()
+                .map(|(index, candidate)| format!("[{}] {}", index + 1, candidate.text))
+                .collect::<Vec<_>>()
+                .join("\n\n");
+            let prompt = format!("Query: {}\n\nPassages:\n{}", query, passages);
+            let response =
+                complete(&model, &config.ai_model, LISTWISE_PROMPT, prompt, &mut gas).await?;
+            let count = candidates.len();
+            let ranking = parse_ranking(&response, count);
+            let mut candidates = candidates.into_iter().map(Some).collect::<Vec<_>>();
+            ranking
+                .into_iter()
+                .enumerate()
+                .filter_map(|(position, index)| {
+                    let score = (count - position) as f32 / count as f32;
+                    candidates[index].take().map(|candidate| (candidate, score))
+                })
+                .collect()
+        }
+    };
+    // A stable sort keeps the retrieval order between equally rated candidates.
+    scored.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    scored.truncate(k);
+    Ok((scored, gas))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_relevance_score() {
+        assert_eq!(parse_relevance_score("7"), Some(0.7));
+        assert_eq!(parse_relevance_score("Score: 10."), Some(1.0));
+        assert_eq!(
+            parse_relevance_score("<think>maybe 2</think>8.5"),
+            Some(0.85)
+        );
+        assert_eq!(parse_relevance_score("42"), Some(1.0));
+        assert_eq!(parse_relevance_score("not relevant"), None);
+    }
+
+    #[test]
+    fn test_parse_ranking() {
+        assert_eq!(parse_ranking("3, 1, 2", 3), vec![2, 0, 1]);
+        assert_eq!(parse_ranking("[2] > [2] > [9]", 3), vec![1, 0, 2]);
+        assert_eq!(parse_ranking("none of them", 2), vec![0, 1]);
+    }
+
+    #[test]
+    fn test_candidates() {
+        let config = RerankConfig {
+            ai_model: "default".to_string(),
+            mode: RerankMode::Listwise,
+            oversample: 0,
+        };
+        assert_eq!(config.candidates(4), 4);
+        assert_eq!(
+            RerankConfig {
+                oversample: default_oversample(),
+                ..config
+            }
+            .candidates(4),
+            12
+        );
+    }
+}
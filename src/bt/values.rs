@@ -55,6 +55,13 @@ pub enum PromptValue {
     Quick(String),
     Chat(Vec<MessageValue>),
     Joined(Vec<PromptValue>),
+    /// A prompt published by an MCP service, fetched by name.
+    McpPrompt {
+        service: String,
+        name: String,
+        #[serde(default)]
+        arguments: HashMap<String, TextValue>,
+    },
 }
 
 impl From<Vec<MessageValue>> for PromptValue {
@@ -72,6 +79,11 @@ pub enum TextValue {
     Default(VariableId, String),
     Multi(Vec<TextValue>),
     Structured(HashMap<String, TextValue>),
+    /// The text contents of a resource published by an MCP service.
+    McpResource {
+        service: String,
+        uri: Box<TextValue>,
+    },
 }
 
 impl<'de> Deserialize<'de> for TextValue {
@@ -88,6 +100,10 @@ impl<'de> Deserialize<'de> for TextValue {
             Simple(String),
             Multi(Vec<TextValue>),
             Structured(HashMap<String, TextValue>),
+            McpResource {
+                service: String,
+                uri: Box<TextValue>,
+            },
             #[serde(untagged)]
             Untagged(String),
             #[serde(untagged)]
@@ -104,6 +120,9 @@ impl<'de> Deserialize<'de> for TextValue {
                 TextValueHelper::Simple(s) => Ok(TextValue::Simple(s)),
                 TextValueHelper::Multi(m) => Ok(TextValue::Multi(m)),
                 TextValueHelper::Structured(s) => Ok(TextValue::Structured(s)),
+                TextValueHelper::McpResource { service, uri } => {
+                    Ok(TextValue::McpResource { service, uri })
+                }
                 TextValueHelper::Untagged(s) => Ok(TextValue::Simple(s)),
                 TextValueHelper::UntaggedDefault(v, d) => Ok(TextValue::Default(v, d)),
             },
@@ -124,6 +143,24 @@ pub enum TextMatcher {
     All(Vec<TextMatcher>),
 }
 
+/// A key/value condition on vector database entries, e.g. `Equals("source", "handbook")`.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum KeyValueFilter {
+    Equals(TextValue, TextValue),
+    In(TextValue, Vec<TextValue>),
+    Prefix(TextValue, TextValue),
+}
+
+/// Which vector database entries a deletion applies to, e.g. `Text("stale fact")` or
+/// `OlderThan(86400)` for entries not updated within a day.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub enum EntryFilter {
+    Text(TextValue),
+    Id(TextValue),
+    KeyValues(Vec<KeyValueFilter>),
+    OlderThan(u64),
+}
+
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub enum MessageValue {
     User(String),
@@ -0,0 +1,2200 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use rusqlite::{
+    ffi::sqlite3_auto_extension, functions::FunctionFlags, Connection, OpenFlags,
+    OptionalExtension, ToSql,
+};
+use serde::{Deserialize, Serialize};
+use sqlite_vec::sqlite3_vec_init;
+use zerocopy::AsBytes;
+
+/// The collection used when a path names none. Its tables keep their unprefixed names,
+/// so databases written before collections existed are its contents.
+pub const DEFAULT_COLLECTION: &str = "default";
+
+#[derive(Debug)]
+pub enum VectorError {
+    Sqlite(rusqlite::Error),
+    InvalidCollection(String),
+    MissingDatabase(String),
+    MissingCollection(String),
+    UnknownModel(String),
+    ModelMismatch {
+        collection: String,
+        expected: String,
+        found: String,
+    },
+    DimensionMismatch {
+        collection: String,
+        expected: usize,
+        found: usize,
+    },
+}
+
+impl std::fmt::Display for VectorError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VectorError::Sqlite(err) => write!(f, "SQLite error: {}", err),
+            VectorError::InvalidCollection(name) => write!(
+                f,
+                "Invalid collection name {:?}: only letters, digits and '_' are allowed",
+                name
+            ),
+            VectorError::MissingDatabase(path) => write!(f, "Database {:?} does not exist", path),
+            VectorError::MissingCollection(name) => {
+                write!(f, "Collection {:?} does not exist", name)
+            }
+            VectorError::UnknownModel(name) => {
+                write!(f, "Embedding model {} not found", name)
+            }
+            VectorError::ModelMismatch {
+                collection,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Collection {:?} holds embeddings from model {:?}, not {:?}",
+                collection, expected, found
+            ),
+            VectorError::DimensionMismatch {
+                collection,
+                expected,
+                found,
+            } => write!(
+                f,
+                "Collection {:?} holds embeddings of dimension {}, not {}",
+                collection, expected, found
+            ),
+        }
+    }
+}
+
+impl std::error::Error for VectorError {}
+
+impl From<rusqlite::Error> for VectorError {
+    fn from(err: rusqlite::Error) -> Self {
+        VectorError::Sqlite(err)
+    }
+}
+
+/// A named set of embeddings, texts and key/values within one database file.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Collection {
+    pub name: String,
+}
+
+impl Default for Collection {
+    fn default() -> Self {
+        Self {
+            name: DEFAULT_COLLECTION.to_string(),
+        }
+    }
+}
+
+impl Collection {
+    pub fn new(name: &str) -> Result<Self, VectorError> {
+        if name.is_empty() || !name.chars().all(|c| c.is_ascii_alphanumeric() || c == '_') {
+            return Err(VectorError::InvalidCollection(name.to_string()));
+        }
+        Ok(Self {
+            name: name.to_string(),
+        })
+    }
+
+    fn table(&self, table: &str) -> String {
+        if self.name == DEFAULT_COLLECTION {
+            table.to_string()
+        } else {
+            format!("{}_{}", self.name, table)
+        }
+    }
+
+    pub fn embeddings_table(&self) -> String {
+        self.table("embeddings")
+    }
+
+    pub fn texts_table(&self) -> String {
+        self.table("texts")
+    }
+
+    pub fn key_values_table(&self) -> String {
+        self.table("key_values")
+    }
+
+    pub fn fts_table(&self) -> String {
+        self.table("texts_fts")
+    }
+}
+
+/// Splits a `path#collection` address into its file path and collection.
+pub fn parse_vector_path(path: &str) -> Result<(String, Collection), VectorError> {
+    match path.rsplit_once('#') {
+        Some((file, name)) => Ok((file.to_string(), Collection::new(name)?)),
+        None => Ok((path.to_string(), Collection::default())),
+    }
+}
+
+/// How the distance between two embeddings is measured. Smaller is always closer.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DistanceMetric {
+    /// Euclidean distance, sqlite-vec's default.
+    #[default]
+    L2,
+    /// One minus the cosine similarity, which ignores the embeddings' lengths.
+    Cosine,
+    /// The negated dot product.
+    Dot,
+}
+
+impl std::str::FromStr for DistanceMetric {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_ascii_lowercase().as_str() {
+            "l2" => Ok(DistanceMetric::L2),
+            "cosine" => Ok(DistanceMetric::Cosine),
+            "dot" => Ok(DistanceMetric::Dot),
+            _ => Err(format!("Unknown distance metric: {}", s)),
+        }
+    }
+}
+
+impl DistanceMetric {
+    pub fn name(&self) -> &'static str {
+        match self {
+            DistanceMetric::L2 => "l2",
+            DistanceMetric::Cosine => "cosine",
+            DistanceMetric::Dot => "dot",
+        }
+    }
+
+    /// The SQL function computing this distance between two embedding blobs.
+    fn sql_function(&self) -> &'static str {
+        match self {
+            DistanceMetric::L2 => "vec_distance_l2",
+            DistanceMetric::Cosine => "vec_distance_cosine",
+            DistanceMetric::Dot => "bark_distance_dot",
+        }
+    }
+
+    /// The `vec0` column option ranking by this metric. sqlite-vec has no dot product
+    /// metric, so dot product collections are scanned instead.
+    fn vec0_option(&self) -> Option<&'static str> {
+        match self {
+            DistanceMetric::L2 => Some(""),
+            DistanceMetric::Cosine => Some(" distance_metric=cosine"),
+            DistanceMetric::Dot => None,
+        }
+    }
+
+    pub fn distance(&self, a: &[f32], b: &[f32]) -> f32 {
+        match self {
+            DistanceMetric::L2 => a
+                .iter()
+                .zip(b)
+                .map(|(a, b)| (a - b) * (a - b))
+                .sum::<f32>()
+                .sqrt(),
+            DistanceMetric::Cosine => 1.0 - cosine_similarity(a, b),
+            DistanceMetric::Dot => -a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>(),
+        }
+    }
+
+    /// Converts a distance into a similarity, where larger is closer. For normalized
+    /// embeddings, every metric gives their cosine similarity.
+    pub fn similarity_from_distance(&self, distance: f32) -> f32 {
+        match self {
+            DistanceMetric::L2 => 1.0 - distance * distance / 2.0,
+            DistanceMetric::Cosine => 1.0 - distance,
+            DistanceMetric::Dot => -distance,
+        }
+    }
+
+    pub fn similarity(&self, a: &[f32], b: &[f32]) -> f32 {
+        self.similarity_from_distance(self.distance(a, b))
+    }
+}
+
+/// Registers the distance functions which sqlite-vec lacks on a connection.
+pub fn register_distance_functions(db: &Connection) -> rusqlite::Result<()> {
+    db.create_scalar_function(
+        DistanceMetric::Dot.sql_function(),
+        2,
+        FunctionFlags::SQLITE_UTF8 | FunctionFlags::SQLITE_DETERMINISTIC,
+        |context| {
+            let a = decode_embedding(&context.get::<Vec<u8>>(0)?);
+            let b = decode_embedding(&context.get::<Vec<u8>>(1)?);
+            Ok(DistanceMetric::Dot.distance(&a, &b) as f64)
+        },
+    )
+}
+
+/// What a collection's embeddings were made with. `model` is unknown for migrated databases.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CollectionMetadata {
+    pub model: Option<String>,
+    pub dimension: usize,
+    pub metric: DistanceMetric,
+}
+
+/// Loads sqlite-vec into every connection opened after this.
+pub fn register_vec_extension() {
+    unsafe {
+        sqlite3_auto_extension(Some(std::mem::transmute(sqlite3_vec_init as *const ())));
+    }
+}
+
+/// Reads the dimension from a `vec0(embedding float[N])` table definition.
+fn parse_vec0_dimension(sql: &str) -> Option<usize> {
+    let start = sql.find("float[")? + "float[".len();
+    let end = start + sql[start..].find(']')?;
+    sql[start..end].trim().parse().ok()
+}
+
+/// The default collection of a database written before the metadata table existed, if any.
+fn legacy_metadata(db: &Connection) -> rusqlite::Result<Option<CollectionMetadata>> {
+    let legacy_sql: Option<String> = db
+        .query_row(
+            "select sql from sqlite_master where name = 'embeddings'",
+            [],
+            |row| row.get(0),
+        )
+        .optional()?;
+    Ok(legacy_sql
+        .as_deref()
+        .and_then(parse_vec0_dimension)
+        .map(|dimension| CollectionMetadata {
+            model: None,
+            dimension,
+            metric: DistanceMetric::default(),
+        }))
+}
+
+/// Creates the metadata table, recording the default collection of databases which predate it.
+fn ensure_metadata_table(db: &Connection) -> rusqlite::Result<()> {
+    if table_exists(db, "vector_collections")? {
+        if !has_column(db, "vector_collections", "metric")? {
+            db.execute("alter table vector_collections add column metric text", [])?;
+        }
+        return Ok(());
+    }
+    db.execute(
+        "create table vector_collections (name text primary key, model text, dimension integer not null, metric text)",
+        [],
+    )?;
+    if let Some(metadata) = legacy_metadata(db)? {
+        db.execute(
+            "insert into vector_collections (name, model, dimension) values (?1, null, ?2)",
+            rusqlite::params![DEFAULT_COLLECTION, metadata.dimension],
+        )?;
+    }
+    Ok(())
+}
+
+/// The recorded collections, or only the one named `name`. Reads databases whose metadata
+/// table predates metrics, or which have none, as they are instead of migrating them.
+fn select_metadata(
+    db: &Connection,
+    name: Option<&str>,
+) -> rusqlite::Result<Vec<(String, CollectionMetadata)>> {
+    if !table_exists(db, "vector_collections")? {
+        return Ok(legacy_metadata(db)?
+            .filter(|_| name.map_or(true, |name| name == DEFAULT_COLLECTION))
+            .map(|metadata| (DEFAULT_COLLECTION.to_string(), metadata))
+            .into_iter()
+            .collect());
+    }
+    let metric = if has_column(db, "vector_collections", "metric")? {
+        "metric"
+    } else {
+        "null"
+    };
+    let mut stmt = db.prepare_cached(&format!(
+        "select name, model, dimension, {} from vector_collections where ?1 is null or name = ?1 order by name",
+        metric
+    ))?;
+    let collections = stmt
+        .query_map([name], |row| {
+            Ok((
+                row.get(0)?,
+                CollectionMetadata {
+                    model: row.get(1)?,
+                    dimension: row.get(2)?,
+                    metric: parse_metric(row.get(3)?),
+                },
+            ))
+        })?
+        .collect();
+    collections
+}
+
+/// Collections which predate metrics were created with sqlite-vec's default.
+fn parse_metric(metric: Option<String>) -> DistanceMetric {
+    metric
+        .and_then(|metric| metric.parse().ok())
+        .unwrap_or_default()
+}
+
+/// The metric a collection's embeddings are compared with.
+pub fn collection_metric(
+    db: &Connection,
+    collection: &Collection,
+) -> rusqlite::Result<DistanceMetric> {
+    Ok(select_metadata(db, Some(&collection.name))?
+        .into_iter()
+        .next()
+        .map(|(_, metadata)| metadata.metric)
+        .unwrap_or_default())
+}
+
+pub fn read_metadata(
+    db: &Connection,
+    collection: &Collection,
+) -> Result<Option<CollectionMetadata>, VectorError> {
+    Ok(select_metadata(db, Some(&collection.name))?
+        .into_iter()
+        .next()
+        .map(|(_, metadata)| metadata))
+}
+
+fn check_metadata(
+    collection: &Collection,
+    metadata: &CollectionMetadata,
+    model: &str,
+    dimension: usize,
+) -> Result<(), VectorError> {
+    if let Some(expected) = &metadata.model {
+        if expected != model {
+            return Err(VectorError::ModelMismatch {
+                collection: collection.name.clone(),
+                expected: expected.clone(),
+                found: model.to_string(),
+            });
+        }
+    }
+    if metadata.dimension != dimension {
+        return Err(VectorError::DimensionMismatch {
+            collection: collection.name.clone(),
+            expected: metadata.dimension,
+            found: dimension,
+        });
+    }
+    Ok(())
+}
+
+/// Checks that `collection` holds embeddings comparable to one of `dimension` from `model`.
+pub fn check_collection(
+    db: &Connection,
+    collection: &Collection,
+    model: &str,
+    dimension: usize,
+) -> Result<(), VectorError> {
+    match read_metadata(db, collection)? {
+        Some(metadata) => check_metadata(collection, &metadata, model, dimension),
+        None => Err(VectorError::MissingCollection(collection.name.clone())),
+    }
+}
+
+/// Like [`check_collection`], but creates the collection if it does not exist yet,
+/// comparing its embeddings by `metric`. An existing collection keeps its own metric.
+pub fn prepare_collection(
+    db: &Connection,
+    collection: &Collection,
+    model: &str,
+    dimension: usize,
+    metric: DistanceMetric,
+) -> Result<(), VectorError> {
+    ensure_metadata_table(db)?;
+    if let Some(metadata) = read_metadata(db, collection)? {
+        check_metadata(collection, &metadata, model, dimension)?;
+        ensure_timestamps(db, collection)?;
+        return ensure_fts(db, collection);
+    }
+    db.execute(
+        &format!(
+            "create virtual table if not exists {} using vec0(embedding float[{}]{})",
+            collection.embeddings_table(),
+            dimension,
+            metric.vec0_option().unwrap_or_default(),
+        ),
+        [],
+    )?;
+    db.execute(
+        &format!(
+            "create table if not exists {} (rowid integer primary key, value text unique, created_at integer, updated_at integer)",
+            collection.texts_table()
+        ),
+        [],
+    )?;
+    db.execute(
+        "insert into vector_collections (name, model, dimension, metric) values (?1, ?2, ?3, ?4)",
+        rusqlite::params![collection.name, model, dimension, metric.name()],
+    )?;
+    ensure_fts(db, collection)
+}
+
+/// Seconds since the Unix epoch, as stored in `created_at` and `updated_at`.
+pub fn timestamp_now() -> i64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|elapsed| elapsed.as_secs() as i64)
+        .unwrap_or(0)
+}
+
+/// Adds the timestamp columns to texts stored before they existed, dating those texts to now.
+pub fn ensure_timestamps(db: &Connection, collection: &Collection) -> rusqlite::Result<()> {
+    let texts = collection.texts_table();
+    if has_column(db, &texts, "updated_at")? {
+        return Ok(());
+    }
+    db.execute_batch(&format!(
+        "alter table {texts} add column created_at integer;
+        alter table {texts} add column updated_at integer;",
+        texts = texts,
+    ))?;
+    db.execute(
+        &format!("update {} set created_at = ?1, updated_at = ?1", texts),
+        [timestamp_now()],
+    )?;
+    Ok(())
+}
+
+/// Mirrors a collection's texts into an FTS5 index, kept in sync by triggers.
+/// Texts stored before the index existed are indexed when it is created.
+pub fn ensure_fts(db: &Connection, collection: &Collection) -> Result<(), VectorError> {
+    let fts = collection.fts_table();
+    if table_exists(db, &fts)? {
+        return Ok(());
+    }
+    let texts = collection.texts_table();
+    db.execute_batch(&format!(
+        "create virtual table {fts} using fts5(value, content='{texts}', content_rowid='rowid');
+        create trigger if not exists {texts}_fts_insert after insert on {texts} begin
+            insert into {fts} (rowid, value) values (new.rowid, new.value);
+        end;
+        create trigger if not exists {texts}_fts_delete after delete on {texts} begin
+            insert into {fts} ({fts}, rowid, value) values ('delete', old.rowid, old.value);
+        end;
+        create trigger if not exists {texts}_fts_update after update on {texts} begin
+            insert into {fts} ({fts}, rowid, value) values ('delete', old.rowid, old.value);
+            insert into {fts} (rowid, value) values (new.rowid, new.value);
+        end;
+        insert into {fts} ({fts}) values ('rebuild');",
+        fts = fts,
+        texts = texts,
+    ))?;
+    Ok(())
+}
+
+/// Opens the existing database file at `path` read-only, so that inspecting it
+/// neither creates nor migrates it.
+pub fn open_read_only(path: &str) -> Result<Connection, VectorError> {
+    if !std::path::Path::new(path).exists() {
+        return Err(VectorError::MissingDatabase(path.to_string()));
+    }
+    let db = Connection::open_with_flags(
+        path,
+        OpenFlags::SQLITE_OPEN_READ_ONLY | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+    )?;
+    register_distance_functions(&db)?;
+    Ok(db)
+}
+
+/// Opens the database at `path` read-only. It must already hold the collection it names.
+pub fn open_collection(path: &str) -> Result<(Connection, Collection), VectorError> {
+    let (path, collection) = parse_vector_path(path)?;
+    let db = open_read_only(&path)?;
+    if read_metadata(&db, &collection)?.is_none() {
+        return Err(VectorError::MissingCollection(collection.name));
+    }
+    Ok((db, collection))
+}
+
+/// Prefix of paths naming an in-memory database, e.g. `mem:scratch#facts`. Trees get their
+/// own per run, through [`MemoryScope`].
+pub const MEMORY_SCHEME: &str = "mem:";
+
+/// How many prepared statements each pooled connection keeps.
+const STATEMENT_CACHE_CAPACITY: usize = 64;
+
+/// A pool of vector databases, keyed by path, with one long-lived connection each so that
+/// repeated operations reuse it and its cached statements. In-memory databases live as long
+/// as the pool, unless a [`MemoryScope`] forgets them.
+#[derive(Clone, Default)]
+pub struct VectorStores {
+    connections: Arc<Mutex<HashMap<String, Arc<Mutex<Connection>>>>>,
+}
+
+impl VectorStores {
+    /// The pooled connection to `path`, opened if need be. Unless `create` is set,
+    /// a database which does not exist yet is not opened.
+    fn connection(
+        &self,
+        path: &str,
+        create: bool,
+    ) -> rusqlite::Result<Option<Arc<Mutex<Connection>>>> {
+        let mut connections = self.connections.lock().unwrap();
+        if let Some(connection) = connections.get(path) {
+            return Ok(Some(connection.clone()));
+        }
+        let in_memory = path.starts_with(MEMORY_SCHEME);
+        if !create && (in_memory || !std::path::Path::new(path).exists()) {
+            return Ok(None);
+        }
+        let db = if in_memory {
+            Connection::open_in_memory()?
+        } else {
+            Connection::open(path)?
+        };
+        db.set_prepared_statement_cache_capacity(STATEMENT_CACHE_CAPACITY);
+        register_distance_functions(&db)?;
+        let connection = Arc::new(Mutex::new(db));
+        connections.insert(path.to_string(), connection.clone());
+        Ok(Some(connection))
+    }
+
+    /// Runs `f` on the database and collection named by `path`. Unless `create` is set,
+    /// a database which does not exist yet is reported as a missing collection.
+    pub fn with_db<T>(
+        &self,
+        path: &str,
+        create: bool,
+        f: impl FnOnce(&Connection, &Collection) -> Result<T, VectorError>,
+    ) -> Result<T, VectorError> {
+        let (path, collection) = parse_vector_path(path)?;
+        let Some(connection) = self.connection(&path, create)? else {
+            return Err(VectorError::MissingCollection(collection.name));
+        };
+        let db = connection.lock().unwrap();
+        f(&db, &collection)
+    }
+
+    /// Closes the connection to a database, dropping it if it is in memory.
+    /// Returns whether it was open.
+    pub fn forget(&self, path: &str) -> bool {
+        self.connections.lock().unwrap().remove(path).is_some()
+    }
+}
+
+static MEMORY_SCOPE_IDS: AtomicUsize = AtomicUsize::new(0);
+
+/// The in-memory databases of one run. Their paths are made unique to the run, so that runs
+/// sharing a pool never see each other's, and they are forgotten once the last clone of the
+/// scope is dropped.
+#[derive(Clone, Default)]
+pub struct MemoryScope(Arc<ScopedDatabases>);
+
+struct ScopedDatabases {
+    id: usize,
+    opened: Mutex<Vec<(VectorStores, String)>>,
+}
+
+impl Default for ScopedDatabases {
+    fn default() -> Self {
+        Self {
+            id: MEMORY_SCOPE_IDS.fetch_add(1, Ordering::Relaxed),
+            opened: Mutex::new(vec![]),
+        }
+    }
+}
+
+impl Drop for ScopedDatabases {
+    fn drop(&mut self) {
+        for (stores, path) in self.opened.get_mut().unwrap().drain(..) {
+            stores.forget(&path);
+        }
+    }
+}
+
+impl std::fmt::Debug for MemoryScope {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_tuple("MemoryScope").field(&self.0.id).finish()
+    }
+}
+
+impl MemoryScope {
+    /// The path `stores` knows an in-memory database of this scope by. Other paths are kept.
+    pub fn scoped_path(&self, stores: &VectorStores, path: &str) -> String {
+        let Some(name) = path.strip_prefix(MEMORY_SCHEME) else {
+            return path.to_string();
+        };
+        let scoped = format!("{}{}/{}", MEMORY_SCHEME, self.0.id, name);
+        let file = scoped
+            .rsplit_once('#')
+            .map_or(scoped.as_str(), |(file, _)| file)
+            .to_string();
+        let mut opened = self.0.opened.lock().unwrap();
+        if !opened.iter().any(|(_, opened)| *opened == file) {
+            opened.push((stores.clone(), file));
+        }
+        scoped
+    }
+}
+
+/// Every collection in the database, by name.
+pub fn list_collections(db: &Connection) -> Result<Vec<(String, CollectionMetadata)>, VectorError> {
+    Ok(select_metadata(db, None)?)
+}
+
+/// Upgrades a database written before collections existed, recording `model` for every
+/// collection whose embedding model is unknown.
+pub fn migrate_vector_db(path: &str, model: &str) -> Result<(), VectorError> {
+    let db = Connection::open(path)?;
+    ensure_metadata_table(&db)?;
+    db.execute(
+        "update vector_collections set model = ?1 where model is null",
+        [model],
+    )?;
+    Ok(())
+}
+
+/// A restriction on the key/values stored alongside an embedding.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum KeyValuePredicate {
+    Equals { key: String, value: String },
+    In { key: String, values: Vec<String> },
+    Prefix { key: String, prefix: String },
+    HasKey { key: String },
+}
+
+impl KeyValuePredicate {
+    /// Renders the predicate as a condition on the row `t`, binding its values after `params`.
+    fn sql(&self, key_values_table: &str, params: &mut Vec<Box<dyn ToSql>>) -> String {
+        let mut bind = |value: &String| {
+            params.push(Box::new(value.clone()));
+            format!("?{}", params.len())
+        };
+        let condition = match self {
+            KeyValuePredicate::Equals { key, value } => {
+                format!("kv.key = {} and kv.value = {}", bind(key), bind(value))
+            }
+            KeyValuePredicate::In { key, values } => {
+                if values.is_empty() {
+                    return "0".to_string();
+                }
+                format!(
+                    "kv.key = {} and kv.value in ({})",
+                    bind(key),
+                    values.iter().map(&mut bind).collect::<Vec<_>>().join(", ")
+                )
+            }
+            KeyValuePredicate::Prefix { key, prefix } => {
+                let key = bind(key);
+                let prefix = bind(prefix);
+                format!(
+                    "kv.key = {} and substr(kv.value, 1, length({})) = {}",
+                    key, prefix, prefix
+                )
+            }
+            KeyValuePredicate::HasKey { key } => format!("kv.key = {}", bind(key)),
+        };
+        format!(
+            "exists (select 1 from {} kv where kv.embeddingid = t.rowid and {})",
+            key_values_table, condition
+        )
+    }
+}
+
+/// One stored text returned by a vector query.
+#[derive(Debug, Clone, PartialEq)]
+pub struct VectorMatch {
+    pub rowid: i64,
+    pub text: String,
+    pub key_values: Vec<(String, String)>,
+    pub distance: f32,
+    pub metric: DistanceMetric,
+}
+
+/// Bounds on how far a match may be from the query.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub struct VectorThreshold {
+    #[serde(default)]
+    pub max_distance: Option<f32>,
+    #[serde(default)]
+    pub min_similarity: Option<f32>,
+}
+
+impl VectorThreshold {
+    pub fn accepts(&self, found: &VectorMatch) -> bool {
+        self.accepts_distance(found.distance, found.metric)
+    }
+
+    /// Like [`Self::accepts`], for a distance between embeddings which are not stored entries.
+    pub fn accepts_distance(&self, distance: f32, metric: DistanceMetric) -> bool {
+        self.max_distance
+            .map_or(true, |max_distance| distance <= max_distance)
+            && self.min_similarity.map_or(true, |min_similarity| {
+                metric.similarity_from_distance(distance) >= min_similarity
+            })
+    }
+}
+
+fn default_fusion_weight() -> f32 {
+    1.0
+}
+
+fn default_rrf_k() -> f32 {
+    60.0
+}
+
+/// How neighbors are counted when classifying by their labels.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VoteWeighting {
+    /// Every neighbor counts once.
+    #[default]
+    Majority,
+    /// Neighbors count by their similarity, so that closer ones outweigh farther ones.
+    Distance,
+}
+
+/// Picks the label with the most votes among `neighbors`, closest first, along with its
+/// share of the votes. Ties go to the label of the closer neighbor.
+pub fn vote(
+    neighbors: &[VectorMatch],
+    label_key: &str,
+    weighting: VoteWeighting,
+) -> Option<(String, f32)> {
+    let mut tallies: Vec<(&str, f32)> = vec![];
+    for neighbor in neighbors {
+        let Some((_, label)) = neighbor.key_values.iter().find(|(key, _)| key == label_key) else {
+            continue;
+        };
+        let weight = match weighting {
+            VoteWeighting::Majority => 1.0,
+            VoteWeighting::Distance => neighbor.similarity().max(0.0),
+        };
+        match tallies.iter_mut().find(|(tallied, _)| tallied == label) {
+            Some((_, tally)) => *tally += weight,
+            None => tallies.push((label, weight)),
+        }
+    }
+    let total = tallies.iter().map(|(_, tally)| tally).sum::<f32>();
+    if total <= 0.0 {
+        return None;
+    }
+    tallies
+        .into_iter()
+        .fold(
+            None,
+            |best: Option<(&str, f32)>, (label, tally)| match best {
+                Some((_, best_tally)) if best_tally >= tally => best,
+                _ => Some((label, tally)),
+            },
+        )
+        .map(|(label, tally)| (label.to_string(), tally / total))
+}
+
+/// How lexical and vector rankings are fused in a hybrid search.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct HybridWeights {
+    #[serde(default = "default_fusion_weight")]
+    pub vector_weight: f32,
+    #[serde(default = "default_fusion_weight")]
+    pub lexical_weight: f32,
+    /// Damps the lead of the first ranks; 60 is the usual choice.
+    #[serde(default = "default_rrf_k")]
+    pub rrf_k: f32,
+}
+
+impl Default for HybridWeights {
+    fn default() -> Self {
+        Self {
+            vector_weight: default_fusion_weight(),
+            lexical_weight: default_fusion_weight(),
+            rrf_k: default_rrf_k(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum SearchMode {
+    #[default]
+    Vector,
+    /// Fuses the vector ranking with a BM25 ranking of the texts by reciprocal rank.
+    Hybrid(HybridWeights),
+}
+
+fn default_mmr_lambda() -> f32 {
+    0.5
+}
+
+fn default_mmr_oversample() -> usize {
+    4
+}
+
+/// Maximal marginal relevance: picks matches which are close to the query but far from each other.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub struct MmrOptions {
+    /// 1 ranks by relevance alone, 0 by diversity alone.
+    #[serde(default = "default_mmr_lambda")]
+    pub lambda: f32,
+    /// How many candidates are retrieved per match kept.
+    #[serde(default = "default_mmr_oversample")]
+    pub oversample: usize,
+}
+
+impl Default for MmrOptions {
+    fn default() -> Self {
+        Self {
+            lambda: default_mmr_lambda(),
+            oversample: default_mmr_oversample(),
+        }
+    }
+}
+
+impl MmrOptions {
+    pub fn candidates(&self, k: usize) -> usize {
+        k * self.oversample.max(1)
+    }
+}
+
+impl VectorMatch {
+    pub fn similarity(&self) -> f32 {
+        self.metric.similarity_from_distance(self.distance)
+    }
+
+    /// The key/values grouped by key, with repeated keys joined by `", "`.
+    pub fn variables(&self) -> Vec<(String, String)> {
+        let mut variables: Vec<(String, String)> = vec![];
+        for (key, value) in &self.key_values {
+            match variables.iter_mut().find(|(existing, _)| existing == key) {
+                Some((_, existing)) => {
+                    existing.push_str(", ");
+                    existing.push_str(value);
+                }
+                None => variables.push((key.clone(), value.clone())),
+            }
+        }
+        variables
+    }
+}
+
+pub fn decode_embedding(bytes: &[u8]) -> Vec<f32> {
+    bytes
+        .chunks_exact(4)
+        .map(|chunk| f32::from_le_bytes([chunk[0], chunk[1], chunk[2], chunk[3]]))
+        .collect()
+}
+
+pub fn cosine_similarity(a: &[f32], b: &[f32]) -> f32 {
+    let dot = a.iter().zip(b).map(|(a, b)| a * b).sum::<f32>();
+    let norms =
+        a.iter().map(|a| a * a).sum::<f32>().sqrt() * b.iter().map(|b| b * b).sum::<f32>().sqrt();
+    if norms == 0.0 {
+        0.0
+    } else {
+        dot / norms
+    }
+}
+
+/// The index of the option closest to `query` and its distance, preferring earlier options on ties.
+pub fn closest_embedding(
+    query: &[f32],
+    options: &[Vec<f32>],
+    metric: DistanceMetric,
+) -> Option<(usize, f32)> {
+    options
+        .iter()
+        .map(|option| metric.distance(query, option))
+        .enumerate()
+        .fold(None, |best, (index, distance)| match best {
+            Some((_, best_distance)) if best_distance <= distance => best,
+            _ => Some((index, distance)),
+        })
+}
+
+pub fn table_exists(db: &Connection, table: &str) -> rusqlite::Result<bool> {
+    db.prepare_cached("select count(*) from sqlite_master where type = 'table' and name = ?")?
+        .query_row([table], |row| row.get::<_, i64>(0))
+        .map(|count| count > 0)
+}
+
+fn has_column(db: &Connection, table: &str, column: &str) -> rusqlite::Result<bool> {
+    db.prepare_cached("select 1 from pragma_table_info(?1) where name = ?2")?
+        .exists([table, column])
+}
+
+pub fn create_key_values_table(db: &Connection, collection: &Collection) -> rusqlite::Result<()> {
+    db.execute(
+        &format!(
+            "create table if not exists {} (rowid integer primary key, embeddingid integer, key text, value text)",
+            collection.key_values_table()
+        ),
+        [],
+    )?;
+    Ok(())
+}
+
+pub fn read_key_values(
+    db: &Connection,
+    collection: &Collection,
+    rowid: i64,
+) -> rusqlite::Result<Vec<(String, String)>> {
+    let table = collection.key_values_table();
+    if !table_exists(db, &table)? {
+        return Ok(vec![]);
+    }
+    let mut stmt = db.prepare_cached(&format!(
+        "select key, value from {} where embeddingid = ? order by rowid",
+        table
+    ))?;
+    let key_values = stmt
+        .query_map([rowid], |row| Ok((row.get(0)?, row.get(1)?)))?
+        .collect();
+    key_values
+}
+
+/// One stored text with its metadata, as listed, exported and imported.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct StoredEntry {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<i64>,
+    pub text: String,
+    #[serde(default)]
+    pub key_values: Vec<(String, String)>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub created_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub updated_at: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub embedding: Option<Vec<f32>>,
+}
+
+pub fn count_entries(db: &Connection, collection: &Collection) -> rusqlite::Result<usize> {
+    db.query_row(
+        &format!("select count(*) from {}", collection.texts_table()),
+        [],
+        |row| row.get(0),
+    )
+}
+
+/// Lists the stored entries in the order they were stored, skipping the first `offset`.
+/// Texts stored before timestamps existed are listed without them.
+pub fn list_entries(
+    db: &Connection,
+    collection: &Collection,
+    offset: usize,
+    limit: Option<usize>,
+    with_embeddings: bool,
+) -> rusqlite::Result<Vec<StoredEntry>> {
+    let texts = collection.texts_table();
+    let timestamps = if has_column(db, &texts, "updated_at")? {
+        "created_at, updated_at"
+    } else {
+        "null, null"
+    };
+    let mut stmt = db.prepare_cached(&format!(
+        "select rowid, value, {} from {} order by rowid limit ?1 offset ?2",
+        timestamps, texts
+    ))?;
+    let rows = stmt
+        .query_map(
+            rusqlite::params![limit.map_or(-1, |limit| limit as i64), offset as i64],
+            |row| {
+                Ok((
+                    row.get::<_, i64>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get(2)?,
+                    row.get(3)?,
+                ))
+            },
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let embeddings = if with_embeddings {
+        let rowids = rows.iter().map(|(rowid, ..)| *rowid).collect::<Vec<_>>();
+        read_embeddings(db, collection, &rowids)?
+            .into_iter()
+            .map(Some)
+            .collect()
+    } else {
+        vec![None; rows.len()]
+    };
+    rows.into_iter()
+        .zip(embeddings)
+        .map(|((rowid, text, created_at, updated_at), embedding)| {
+            Ok(StoredEntry {
+                id: Some(rowid),
+                key_values: read_key_values(db, collection, rowid)?,
+                text,
+                created_at,
+                updated_at,
+                embedding,
+            })
+        })
+        .collect()
+}
+
+/// Which stored entries a deletion applies to.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub enum EntrySelector {
+    Text(String),
+    Id(i64),
+    /// Entries whose key/values pass every predicate. Without predicates, no entries.
+    KeyValues(Vec<KeyValuePredicate>),
+    /// Entries neither stored nor replaced within this many seconds.
+    OlderThan(u64),
+}
+
+pub fn select_rowids(
+    db: &Connection,
+    collection: &Collection,
+    selector: &EntrySelector,
+) -> rusqlite::Result<Vec<i64>> {
+    let mut params: Vec<Box<dyn ToSql>> = vec![];
+    let condition = match selector {
+        EntrySelector::Text(text) => {
+            params.push(Box::new(text.clone()));
+            "t.value = ?1".to_string()
+        }
+        EntrySelector::Id(rowid) => {
+            params.push(Box::new(*rowid));
+            "t.rowid = ?1".to_string()
+        }
+        EntrySelector::KeyValues(predicates) => {
+            let key_values_table = collection.key_values_table();
+            if predicates.is_empty() || !table_exists(db, &key_values_table)? {
+                return Ok(vec![]);
+            }
+            predicates
+                .iter()
+                .map(|predicate| predicate.sql(&key_values_table, &mut params))
+                .collect::<Vec<_>>()
+                .join(" and ")
+        }
+        EntrySelector::OlderThan(seconds) => {
+            params.push(Box::new(timestamp_now() - *seconds as i64));
+            "t.updated_at < ?1".to_string()
+        }
+    };
+    let mut stmt = db.prepare_cached(&format!(
+        "select t.rowid from {} t where {}",
+        collection.texts_table(),
+        condition
+    ))?;
+    let rowids = stmt
+        .query_map(
+            rusqlite::params_from_iter(params.iter().map(|param| param.as_ref())),
+            |row| row.get(0),
+        )?
+        .collect();
+    rowids
+}
+
+/// Deletes the selected entries with their embeddings and key/values, returning how many there were.
+pub fn delete_entries(
+    db: &Connection,
+    collection: &Collection,
+    selector: &EntrySelector,
+) -> rusqlite::Result<usize> {
+    ensure_timestamps(db, collection)?;
+    let rowids = select_rowids(db, collection, selector)?;
+    let key_values_table = collection.key_values_table();
+    let has_key_values = table_exists(db, &key_values_table)?;
+    let transaction = db.unchecked_transaction()?;
+    for rowid in &rowids {
+        db.execute(
+            &format!(
+                "delete from {} where rowid = ?",
+                collection.embeddings_table()
+            ),
+            [rowid],
+        )?;
+        if has_key_values {
+            db.execute(
+                &format!("delete from {} where embeddingid = ?", key_values_table),
+                [rowid],
+            )?;
+        }
+        db.execute(
+            &format!("delete from {} where rowid = ?", collection.texts_table()),
+            [rowid],
+        )?;
+    }
+    transaction.commit()?;
+    Ok(rowids.len())
+}
+
+/// Stores a text like [`insert_text`], but replaces the embedding and key/values of an
+/// entry with the same text instead of keeping them. Returns false if the text was already stored.
+pub fn upsert_text(
+    db: &Connection,
+    collection: &Collection,
+    text: &str,
+    embedding: &[f32],
+    key_values: &[(String, String)],
+) -> rusqlite::Result<bool> {
+    if insert_text(db, collection, text, embedding, Some(key_values))? {
+        return Ok(true);
+    }
+    let texts = collection.texts_table();
+    let rowid: i64 = db.query_row(
+        &format!("select rowid from {} where value = ?", texts),
+        [text],
+        |row| row.get(0),
+    )?;
+    db.execute(
+        &format!("update {} set updated_at = ? where rowid = ?", texts),
+        rusqlite::params![timestamp_now(), rowid],
+    )?;
+    let embeddings = collection.embeddings_table();
+    db.execute(
+        &format!("delete from {} where rowid = ?", embeddings),
+        [rowid],
+    )?;
+    db.execute(
+        &format!(
+            "insert into {} (rowid, embedding) values (?, ?)",
+            embeddings
+        ),
+        rusqlite::params![rowid, embedding.as_bytes()],
+    )?;
+    let key_values_table = collection.key_values_table();
+    db.execute(
+        &format!("delete from {} where embeddingid = ?", key_values_table),
+        [rowid],
+    )?;
+    let mut kv_stmt = db.prepare_cached(&format!(
+        "insert into {} (embeddingid, key, value) values (?, ?, ?)",
+        key_values_table
+    ))?;
+    for (key, value) in key_values {
+        kv_stmt.execute(rusqlite::params![rowid, key, value])?;
+    }
+    Ok(false)
+}
+
+/// Stores a text and its embedding, returning false if the text was already stored.
+pub fn insert_text(
+    db: &Connection,
+    collection: &Collection,
+    text: &str,
+    embedding: &[f32],
+    key_values: Option<&[(String, String)]>,
+) -> rusqlite::Result<bool> {
+    if key_values.is_some() {
+        create_key_values_table(db, collection)?;
+    }
+    let mut v_stmt = db.prepare_cached(&format!(
+        "insert into {} (value, created_at, updated_at) values (?1, ?2, ?2)",
+        collection.texts_table()
+    ))?;
+    match v_stmt.execute(rusqlite::params![text, timestamp_now()]) {
+        Ok(_) => {}
+        Err(rusqlite::Error::SqliteFailure(e, Some(msg))) => {
+            if msg.starts_with("UNIQUE constraint failed") {
+                return Ok(false);
+            }
+            Err(rusqlite::Error::SqliteFailure(e, Some(msg)))?;
+        }
+        Err(err) => return Err(err),
+    }
+    let row_id = db.last_insert_rowid();
+    let mut stmt = db.prepare_cached(&format!(
+        "insert into {} (rowid, embedding) values (?, ?)",
+        collection.embeddings_table()
+    ))?;
+    stmt.execute(rusqlite::params![row_id, embedding.as_bytes()])?;
+    if let Some(key_values) = key_values {
+        let mut kv_stmt = db.prepare_cached(&format!(
+            "insert into {} (embeddingid, key, value) values (?, ?, ?)",
+            collection.key_values_table()
+        ))?;
+        for (key, value) in key_values {
+            kv_stmt.execute(rusqlite::params![row_id, key, value])?;
+        }
+    }
+    Ok(true)
+}
+
+/// The `k` stored texts nearest to `embedding` which satisfy every predicate.
+///
+/// Without predicates this is a plain KNN query on the vector index. With predicates the
+/// candidates are filtered first and ranked by exact distance, so a filter never hides
+/// matches which the index would have ranked below the first `k`.
+/// Collections whose metric the index cannot rank by are always ranked by exact distance.
+pub fn query_matches(
+    db: &Connection,
+    collection: &Collection,
+    embedding: &[f32],
+    k: usize,
+    predicates: &[KeyValuePredicate],
+) -> rusqlite::Result<Vec<VectorMatch>> {
+    let metric = collection_metric(db, collection)?;
+    let rows = if predicates.is_empty() && metric.vec0_option().is_some() {
+        let mut stmt = db.prepare_cached(&format!(
+            "select e.rowid, t.value, e.distance from (select rowid, distance from {} where embedding match ?1 and k = ?2) e join {} t on t.rowid = e.rowid order by e.distance",
+            collection.embeddings_table(),
+            collection.texts_table()
+        ))?;
+        let rows = stmt
+            .query_map(rusqlite::params![embedding.as_bytes(), k], |row| {
+                Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?))
+            })?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    } else {
+        let key_values_table = collection.key_values_table();
+        if !predicates.is_empty() && !table_exists(db, &key_values_table)? {
+            return Ok(vec![]);
+        }
+        let mut params: Vec<Box<dyn ToSql>> =
+            vec![Box::new(embedding.as_bytes().to_vec()), Box::new(k as i64)];
+        let conditions = predicates
+            .iter()
+            .map(|predicate| format!(" and {}", predicate.sql(&key_values_table, &mut params)))
+            .collect::<String>();
+        let mut stmt = db.prepare_cached(&format!(
+            "select t.rowid, t.value, {}(e.embedding, ?1) as distance from {} t join {} e on e.rowid = t.rowid where 1{} order by distance limit ?2",
+            metric.sql_function(),
+            collection.texts_table(),
+            collection.embeddings_table(),
+            conditions
+        ))?;
+        let rows = stmt
+            .query_map(
+                rusqlite::params_from_iter(params.iter().map(|param| param.as_ref())),
+                |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+            )?
+            .collect::<rusqlite::Result<Vec<_>>>()?;
+        rows
+    };
+    rows.into_iter()
+        .map(|(rowid, text, distance)| {
+            Ok(VectorMatch {
+                rowid,
+                key_values: read_key_values(db, collection, rowid)?,
+                text,
+                distance,
+                metric,
+            })
+        })
+        .collect()
+}
+
+/// Quotes each word of a free text query, so that FTS5 matches any of them literally.
+fn fts_query(text: &str) -> Option<String> {
+    let words = text
+        .split(|c: char| !c.is_alphanumeric() && c != '_')
+        .filter(|word| !word.is_empty())
+        .map(|word| format!("\"{}\"", word))
+        .collect::<Vec<_>>();
+    if words.is_empty() {
+        None
+    } else {
+        Some(words.join(" OR "))
+    }
+}
+
+/// The `k` stored texts ranked best by BM25 against `text`, which satisfy every predicate.
+pub fn lexical_matches(
+    db: &Connection,
+    collection: &Collection,
+    text: &str,
+    embedding: &[f32],
+    k: usize,
+    predicates: &[KeyValuePredicate],
+) -> Result<Vec<VectorMatch>, VectorError> {
+    ensure_fts(db, collection)?;
+    let Some(query) = fts_query(text) else {
+        return Ok(vec![]);
+    };
+    let key_values_table = collection.key_values_table();
+    if !predicates.is_empty() && !table_exists(db, &key_values_table)? {
+        return Ok(vec![]);
+    }
+    let metric = collection_metric(db, collection)?;
+    let fts = collection.fts_table();
+    let mut params: Vec<Box<dyn ToSql>> = vec![
+        Box::new(embedding.as_bytes().to_vec()),
+        Box::new(k as i64),
+        Box::new(query),
+    ];
+    let conditions = predicates
+        .iter()
+        .map(|predicate| format!(" and {}", predicate.sql(&key_values_table, &mut params)))
+        .collect::<String>();
+    let mut stmt = db.prepare_cached(&format!(
+        "select t.rowid, t.value, {distance}(e.embedding, ?1) as distance from {fts} join {texts} t on t.rowid = {fts}.rowid join {embeddings} e on e.rowid = t.rowid where {fts} match ?3{conditions} order by bm25({fts}) limit ?2",
+        distance = metric.sql_function(),
+        fts = fts,
+        texts = collection.texts_table(),
+        embeddings = collection.embeddings_table(),
+        conditions = conditions,
+    ))?;
+    let rows = stmt
+        .query_map(
+            rusqlite::params_from_iter(params.iter().map(|param| param.as_ref())),
+            |row| Ok((row.get::<_, i64>(0)?, row.get::<_, String>(1)?, row.get(2)?)),
+        )?
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    let matches = rows
+        .into_iter()
+        .map(|(rowid, text, distance)| {
+            Ok(VectorMatch {
+                rowid,
+                key_values: read_key_values(db, collection, rowid)?,
+                text,
+                distance,
+                metric,
+            })
+        })
+        .collect::<rusqlite::Result<Vec<_>>>()?;
+    Ok(matches)
+}
+
+/// Fuses weighted rankings of row ids, best first, into one ranking with its scores.
+pub fn reciprocal_rank_fusion(rankings: &[(f32, Vec<i64>)], rrf_k: f32) -> Vec<(i64, f32)> {
+    let mut scores: Vec<(i64, f32)> = vec![];
+    for (weight, ranking) in rankings {
+        for (rank, rowid) in ranking.iter().enumerate() {
+            let score = weight / (rrf_k + rank as f32 + 1.0);
+            match scores.iter_mut().find(|(existing, _)| existing == rowid) {
+                Some((_, total)) => *total += score,
+                None => scores.push((*rowid, score)),
+            }
+        }
+    }
+    scores.sort_by(|(_, a), (_, b)| b.total_cmp(a));
+    scores
+}
+
+/// How many candidates each ranking contributes to a hybrid search for `k` results.
+fn hybrid_candidates(k: usize) -> usize {
+    (k * 4).max(20)
+}
+
+/// Retrieves the `k` best matches for a query, by vector distance or by hybrid search.
+pub fn search_matches(
+    db: &Connection,
+    collection: &Collection,
+    text: &str,
+    embedding: &[f32],
+    k: usize,
+    predicates: &[KeyValuePredicate],
+    search: &SearchMode,
+) -> Result<Vec<VectorMatch>, VectorError> {
+    let weights = match search {
+        SearchMode::Vector => return Ok(query_matches(db, collection, embedding, k, predicates)?),
+        SearchMode::Hybrid(weights) => weights,
+    };
+    let candidates = hybrid_candidates(k);
+    let vector = query_matches(db, collection, embedding, candidates, predicates)?;
+    let lexical = lexical_matches(db, collection, text, embedding, candidates, predicates)?;
+    let fused = reciprocal_rank_fusion(
+        &[
+            (
+                weights.vector_weight,
+                vector.iter().map(|found| found.rowid).collect(),
+            ),
+            (
+                weights.lexical_weight,
+                lexical.iter().map(|found| found.rowid).collect(),
+            ),
+        ],
+        weights.rrf_k,
+    );
+    let mut pool = vector.into_iter().chain(lexical).collect::<Vec<_>>();
+    Ok(fused
+        .into_iter()
+        .take(k)
+        .filter_map(|(rowid, _)| {
+            let index = pool.iter().position(|found| found.rowid == rowid)?;
+            Some(pool.swap_remove(index))
+        })
+        .collect())
+}
+
+/// The stored embedding of each row, in order.
+pub fn read_embeddings(
+    db: &Connection,
+    collection: &Collection,
+    rowids: &[i64],
+) -> rusqlite::Result<Vec<Vec<f32>>> {
+    let mut stmt = db.prepare_cached(&format!(
+        "select embedding from {} where rowid = ?",
+        collection.embeddings_table()
+    ))?;
+    rowids
+        .iter()
+        .map(|rowid| {
+            stmt.query_row([rowid], |row| row.get::<_, Vec<u8>>(0))
+                .map(|bytes| decode_embedding(&bytes))
+        })
+        .collect()
+}
+
+/// Greedily picks `k` of the candidates, each maximizing
+/// `lambda * sim(query, c) - (1 - lambda) * max sim(c, picked)`, returning their indices in pick order.
+pub fn mmr_select(
+    query: &[f32],
+    candidates: &[Vec<f32>],
+    k: usize,
+    lambda: f32,
+    metric: DistanceMetric,
+) -> Vec<usize> {
+    let relevance = candidates
+        .iter()
+        .map(|candidate| metric.similarity(query, candidate))
+        .collect::<Vec<_>>();
+    let mut selected: Vec<usize> = vec![];
+    while selected.len() < k.min(candidates.len()) {
+        let mut best: Option<(usize, f32)> = None;
+        for index in (0..candidates.len()).filter(|index| !selected.contains(index)) {
+            let redundancy = selected
+                .iter()
+                .map(|picked| metric.similarity(&candidates[index], &candidates[*picked]))
+                .reduce(f32::max)
+                .unwrap_or(0.0);
+            let score = lambda * relevance[index] - (1.0 - lambda) * redundancy;
+            // Ties go to the earlier, better retrieved candidate.
+            if best.map_or(true, |(_, best_score)| score > best_score) {
+                best = Some((index, score));
+            }
+        }
+        match best {
+            Some((index, _)) => selected.push(index),
+            None => break,
+        }
+    }
+    selected
+}
+
+/// Keeps `k` of the matches, chosen by maximal marginal relevance against their stored embeddings.
+pub fn diversify_matches(
+    db: &Connection,
+    collection: &Collection,
+    embedding: &[f32],
+    matches: Vec<VectorMatch>,
+    k: usize,
+    mmr: &MmrOptions,
+) -> rusqlite::Result<Vec<VectorMatch>> {
+    let rowids = matches.iter().map(|found| found.rowid).collect::<Vec<_>>();
+    let embeddings = read_embeddings(db, collection, &rowids)?;
+    let metric = collection_metric(db, collection)?;
+    let mut matches = matches.into_iter().map(Some).collect::<Vec<_>>();
+    Ok(mmr_select(embedding, &embeddings, k, mmr.lambda, metric)
+        .into_iter()
+        .filter_map(|index| matches[index].take())
+        .collect())
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+
+    pub(crate) fn open_test_db() -> Connection {
+        register_vec_extension();
+        let db = Connection::open_in_memory().unwrap();
+        register_distance_functions(&db).unwrap();
+        prepare_collection(
+            &db,
+            &Collection::default(),
+            "test-model",
+            2,
+            DistanceMetric::L2,
+        )
+        .unwrap();
+        create_key_values_table(&db, &Collection::default()).unwrap();
+        db
+    }
+
+    pub(crate) fn insert(db: &Connection, text: &str, embedding: [f32; 2], kvs: &[(&str, &str)]) {
+        let kvs = kvs
+            .iter()
+            .map(|(key, value)| (key.to_string(), value.to_string()))
+            .collect::<Vec<_>>();
+        insert_text(db, &Collection::default(), text, &embedding, Some(&kvs)).unwrap();
+    }
+
+    fn texts(matches: Vec<VectorMatch>) -> Vec<String> {
+        matches.into_iter().map(|m| m.text).collect()
+    }
+
+    #[test]
+    fn test_filtered_queries() {
+        let db = open_test_db();
+        insert(
+            &db,
+            "a",
+            [0.0, 0.0],
+            &[("source", "handbook"), ("page", "1")],
+        );
+        insert(&db, "b", [1.0, 0.0], &[("source", "wiki")]);
+        insert(&db, "c", [2.0, 0.0], &[("source", "handbook-v2")]);
+
+        let query = [0.9, 0.0];
+        assert_eq!(
+            texts(query_matches(&db, &Collection::default(), &query, 2, &[]).unwrap()),
+            vec!["b", "a"]
+        );
+        let equals = KeyValuePredicate::Equals {
+            key: "source".to_string(),
+            value: "handbook".to_string(),
+        };
+        let matches = query_matches(&db, &Collection::default(), &query, 2, &[equals]).unwrap();
+        assert_eq!(matches.len(), 1);
+        assert_eq!(
+            matches[0].key_values,
+            vec![
+                ("source".to_string(), "handbook".to_string()),
+                ("page".to_string(), "1".to_string())
+            ]
+        );
+        let prefix = KeyValuePredicate::Prefix {
+            key: "source".to_string(),
+            prefix: "handbook".to_string(),
+        };
+        assert_eq!(
+            texts(query_matches(&db, &Collection::default(), &query, 5, &[prefix]).unwrap()),
+            vec!["a", "c"]
+        );
+        let any = KeyValuePredicate::In {
+            key: "source".to_string(),
+            values: vec!["wiki".to_string(), "handbook-v2".to_string()],
+        };
+        assert_eq!(
+            texts(query_matches(&db, &Collection::default(), &query, 5, &[any]).unwrap()),
+            vec!["b", "c"]
+        );
+        let paged = KeyValuePredicate::HasKey {
+            key: "page".to_string(),
+        };
+        assert_eq!(
+            texts(query_matches(&db, &Collection::default(), &query, 5, &[paged]).unwrap()),
+            vec!["a"]
+        );
+    }
+
+    #[test]
+    fn test_variables_join_repeated_keys() {
+        let matched = VectorMatch {
+            rowid: 1,
+            text: "a".to_string(),
+            key_values: vec![
+                ("tag".to_string(), "x".to_string()),
+                ("tag".to_string(), "y".to_string()),
+            ],
+            distance: 0.0,
+            metric: DistanceMetric::L2,
+        };
+        assert_eq!(
+            matched.variables(),
+            vec![("tag".to_string(), "x, y".to_string())]
+        );
+    }
+
+    #[test]
+    fn test_distances_and_thresholds() {
+        let db = open_test_db();
+        insert(&db, "near", [1.0, 0.0], &[]);
+        insert(&db, "far", [0.0, 1.0], &[]);
+        let matches = query_matches(&db, &Collection::default(), &[1.0, 0.0], 2, &[]).unwrap();
+        assert_eq!(matches[0].distance, 0.0);
+        assert_eq!(matches[0].similarity(), 1.0);
+        assert!((matches[1].distance - 2f32.sqrt()).abs() < 1e-6);
+        assert!(matches[1].similarity().abs() < 1e-6);
+
+        let threshold = VectorThreshold {
+            max_distance: Some(1.0),
+            min_similarity: None,
+        };
+        assert!(threshold.accepts(&matches[0]));
+        assert!(!threshold.accepts(&matches[1]));
+        let threshold = VectorThreshold {
+            max_distance: None,
+            min_similarity: Some(0.5),
+        };
+        assert!(threshold.accepts(&matches[0]));
+        assert!(!threshold.accepts(&matches[1]));
+    }
+
+    #[test]
+    fn test_parse_vector_path() {
+        assert_eq!(
+            parse_vector_path("docs.db").unwrap(),
+            ("docs.db".to_string(), Collection::default())
+        );
+        let (path, collection) = parse_vector_path("data/docs.db#faq_v2").unwrap();
+        assert_eq!(path, "data/docs.db");
+        assert_eq!(collection.texts_table(), "faq_v2_texts");
+        assert!(parse_vector_path("docs.db#bad name").is_err());
+        assert!(parse_vector_path("docs.db#").is_err());
+    }
+
+    #[test]
+    fn test_collections_are_separate() {
+        let db = open_test_db();
+        let faq = Collection::new("faq").unwrap();
+        prepare_collection(&db, &faq, "other-model", 3, DistanceMetric::L2).unwrap();
+        insert(&db, "default text", [1.0, 0.0], &[]);
+        insert_text(&db, &faq, "faq text", &[1.0, 0.0, 0.0], None).unwrap();
+        let matches = query_matches(&db, &faq, &[1.0, 0.0, 0.0], 5, &[]).unwrap();
+        assert_eq!(texts(matches), vec!["faq text"]);
+        let matches = query_matches(&db, &Collection::default(), &[1.0, 0.0], 5, &[]).unwrap();
+        assert_eq!(texts(matches), vec!["default text"]);
+    }
+
+    #[test]
+    fn test_metadata_checks() {
+        let db = open_test_db();
+        let default = Collection::default();
+        assert!(check_collection(&db, &default, "test-model", 2).is_ok());
+        assert!(matches!(
+            check_collection(&db, &default, "other-model", 2),
+            Err(VectorError::ModelMismatch { .. })
+        ));
+        assert!(matches!(
+            prepare_collection(&db, &default, "test-model", 3, DistanceMetric::L2),
+            Err(VectorError::DimensionMismatch {
+                expected: 2,
+                found: 3,
+                ..
+            })
+        ));
+        assert!(matches!(
+            check_collection(&db, &Collection::new("missing").unwrap(), "test-model", 2),
+            Err(VectorError::MissingCollection(_))
+        ));
+    }
+
+    #[test]
+    fn test_migrates_legacy_db() {
+        let path = std::env::temp_dir().join(format!("bark-legacy-{}.db", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        register_vec_extension();
+        {
+            let db = Connection::open(&path).unwrap();
+            db.execute(
+                "create virtual table embeddings using vec0(embedding float[4])",
+                [],
+            )
+            .unwrap();
+            db.execute(
+                "create table texts (rowid integer primary key, value text unique)",
+                [],
+            )
+            .unwrap();
+        }
+        let db = Connection::open(&path).unwrap();
+        assert_eq!(
+            read_metadata(&db, &Collection::default()).unwrap(),
+            Some(CollectionMetadata {
+                model: None,
+                dimension: 4,
+                metric: DistanceMetric::L2,
+            })
+        );
+        assert!(check_collection(&db, &Collection::default(), "any-model", 4).is_ok());
+        drop(db);
+        migrate_vector_db(&path, "nomic-embed-text").unwrap();
+        let db = Connection::open(&path).unwrap();
+        assert!(matches!(
+            check_collection(&db, &Collection::default(), "any-model", 4),
+            Err(VectorError::ModelMismatch { .. })
+        ));
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_inspects_legacy_db_read_only() {
+        let path = std::env::temp_dir().join(format!("bark-read-only-{}.db", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        register_vec_extension();
+        assert!(matches!(
+            open_read_only(&path),
+            Err(VectorError::MissingDatabase(_))
+        ));
+        assert!(!std::path::Path::new(&path).exists());
+        {
+            let db = Connection::open(&path).unwrap();
+            db.execute_batch(
+                "create virtual table embeddings using vec0(embedding float[2]);
+                create table texts (rowid integer primary key, value text unique);
+                insert into texts (value) values ('before');",
+            )
+            .unwrap();
+        }
+        let (db, collection) = open_collection(&path).unwrap();
+        assert_eq!(count_entries(&db, &collection).unwrap(), 1);
+        let entries = list_entries(&db, &collection, 0, None, false).unwrap();
+        assert_eq!(entries[0].text, "before");
+        assert_eq!(entries[0].updated_at, None);
+        assert_eq!(list_collections(&db).unwrap().len(), 1);
+        assert!(!table_exists(&db, "vector_collections").unwrap());
+        assert!(matches!(
+            open_collection(&format!("{}#missing", path)),
+            Err(VectorError::MissingCollection(_))
+        ));
+        drop(db);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_upsert_replaces_embedding_and_key_values() {
+        let db = open_test_db();
+        let collection = Collection::default();
+        insert(&db, "fact", [1.0, 0.0], &[("version", "1")]);
+        let kvs = vec![("version".to_string(), "2".to_string())];
+        assert!(!upsert_text(&db, &collection, "fact", &[0.0, 1.0], &kvs).unwrap());
+        assert!(upsert_text(&db, &collection, "new fact", &[1.0, 0.0], &kvs).unwrap());
+        let found = query_matches(&db, &collection, &[0.0, 1.0], 1, &[]).unwrap();
+        assert_eq!(found[0].text, "fact");
+        assert_eq!(found[0].distance, 0.0);
+        assert_eq!(found[0].key_values, kvs);
+    }
+
+    #[test]
+    fn test_delete_entries() {
+        let db = open_test_db();
+        let collection = Collection::default();
+        insert(&db, "a", [1.0, 0.0], &[("source", "x")]);
+        insert(&db, "b", [0.0, 1.0], &[("source", "y")]);
+        insert(&db, "c", [1.0, 1.0], &[("source", "y")]);
+        insert(&db, "d", [1.0, 1.0], &[]);
+        let text = EntrySelector::Text("a".to_string());
+        assert_eq!(delete_entries(&db, &collection, &text).unwrap(), 1);
+        assert_eq!(delete_entries(&db, &collection, &text).unwrap(), 0);
+        let by_source = EntrySelector::KeyValues(vec![KeyValuePredicate::Equals {
+            key: "source".to_string(),
+            value: "y".to_string(),
+        }]);
+        assert_eq!(delete_entries(&db, &collection, &by_source).unwrap(), 2);
+        let none = EntrySelector::KeyValues(vec![]);
+        assert_eq!(delete_entries(&db, &collection, &none).unwrap(), 0);
+        let rowid =
+            select_rowids(&db, &collection, &EntrySelector::Text("d".to_string())).unwrap()[0];
+        assert_eq!(
+            delete_entries(&db, &collection, &EntrySelector::Id(rowid)).unwrap(),
+            1
+        );
+        assert!(query_matches(&db, &collection, &[1.0, 0.0], 4, &[])
+            .unwrap()
+            .is_empty());
+        let leftover: i64 = db
+            .query_row("select count(*) from key_values", [], |row| row.get(0))
+            .unwrap();
+        assert_eq!(leftover, 0);
+    }
+
+    #[test]
+    fn test_prune_by_age() {
+        let db = open_test_db();
+        let collection = Collection::default();
+        insert(&db, "old", [1.0, 0.0], &[]);
+        insert(&db, "new", [0.0, 1.0], &[]);
+        db.execute(
+            "update texts set updated_at = ? where value = 'old'",
+            [timestamp_now() - 1000],
+        )
+        .unwrap();
+        let prune = EntrySelector::OlderThan(100);
+        assert_eq!(delete_entries(&db, &collection, &prune).unwrap(), 1);
+        assert_eq!(
+            texts(query_matches(&db, &collection, &[1.0, 0.0], 2, &[]).unwrap()),
+            vec!["new"]
+        );
+    }
+
+    #[test]
+    fn test_adds_timestamps_to_old_texts() {
+        register_vec_extension();
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "create virtual table embeddings using vec0(embedding float[2]);
+            create table texts (rowid integer primary key, value text unique);
+            insert into texts (value) values ('before');",
+        )
+        .unwrap();
+        prepare_collection(
+            &db,
+            &Collection::default(),
+            "test-model",
+            2,
+            DistanceMetric::L2,
+        )
+        .unwrap();
+        let created_at: Option<i64> = db
+            .query_row("select created_at from texts", [], |row| row.get(0))
+            .unwrap();
+        assert!(created_at.is_some());
+    }
+
+    #[test]
+    fn test_list_entries() {
+        let db = open_test_db();
+        let collection = Collection::default();
+        insert(&db, "a", [1.0, 0.0], &[("source", "x")]);
+        insert(&db, "b", [0.0, 1.0], &[]);
+        insert(&db, "c", [1.0, 1.0], &[]);
+        assert_eq!(count_entries(&db, &collection).unwrap(), 3);
+        let entries = list_entries(&db, &collection, 0, None, true).unwrap();
+        assert_eq!(texts_of(&entries), vec!["a", "b", "c"]);
+        assert_eq!(
+            entries[0].key_values,
+            vec![("source".to_string(), "x".to_string())]
+        );
+        assert_eq!(entries[1].embedding, Some(vec![0.0, 1.0]));
+        assert!(entries[2].created_at.is_some());
+        let page = list_entries(&db, &collection, 1, Some(1), false).unwrap();
+        assert_eq!(texts_of(&page), vec!["b"]);
+        assert_eq!(page[0].embedding, None);
+        assert_eq!(
+            list_collections(&db).unwrap(),
+            vec![(
+                DEFAULT_COLLECTION.to_string(),
+                CollectionMetadata {
+                    model: Some("test-model".to_string()),
+                    dimension: 2,
+                    metric: DistanceMetric::L2,
+                }
+            )]
+        );
+    }
+
+    fn texts_of(entries: &[StoredEntry]) -> Vec<&str> {
+        entries.iter().map(|entry| entry.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_memory_stores() {
+        register_vec_extension();
+        let stores = VectorStores::default();
+        let missing = stores.with_db("mem:scratch", false, |_, _| Ok(()));
+        assert!(matches!(missing, Err(VectorError::MissingCollection(_))));
+        stores
+            .with_db("mem:scratch#facts", true, |db, collection| {
+                prepare_collection(db, collection, "test-model", 2, DistanceMetric::L2)?;
+                insert_text(db, collection, "remembered", &[1.0, 0.0], None)?;
+                Ok(())
+            })
+            .unwrap();
+        let shared = stores.clone();
+        let found = shared
+            .with_db("mem:scratch#facts", false, |db, collection| {
+                check_collection(db, collection, "test-model", 2)?;
+                Ok(query_matches(db, collection, &[1.0, 0.0], 1, &[])?)
+            })
+            .unwrap();
+        assert_eq!(texts(found), vec!["remembered"]);
+        let other = VectorStores::default().with_db("mem:scratch", false, |_, _| Ok(()));
+        assert!(other.is_err());
+        assert!(stores.forget("mem:scratch"));
+        assert!(!shared.forget("mem:scratch"));
+    }
+
+    #[test]
+    fn test_memory_scopes() {
+        register_vec_extension();
+        let stores = VectorStores::default();
+        let (scope, other) = (MemoryScope::default(), MemoryScope::default());
+        let path = scope.scoped_path(&stores, "mem:scratch#facts");
+        assert_ne!(path, other.scoped_path(&stores, "mem:scratch#facts"));
+        assert_eq!(scope.scoped_path(&stores, "facts.db"), "facts.db");
+        stores
+            .with_db(&path, true, |db, collection| {
+                prepare_collection(db, collection, "test-model", 2, DistanceMetric::L2)?;
+                Ok(())
+            })
+            .unwrap();
+        let (file, _) = parse_vector_path(&path).unwrap();
+        drop(scope);
+        assert!(!stores.forget(&file));
+    }
+
+    #[test]
+    fn test_pooled_file_connections() {
+        register_vec_extension();
+        let path = std::env::temp_dir().join(format!("bark-pool-{}.db", std::process::id()));
+        let path = path.to_string_lossy().to_string();
+        let stores = VectorStores::default();
+        let missing = stores.with_db(&path, false, |_, _| Ok(()));
+        assert!(matches!(missing, Err(VectorError::MissingCollection(_))));
+        assert!(!std::path::Path::new(&path).exists());
+        for text in ["a", "b"] {
+            stores
+                .with_db(&path, true, |db, collection| {
+                    prepare_collection(db, collection, "test-model", 2, DistanceMetric::L2)?;
+                    insert_text(db, collection, text, &[1.0, 0.0], None)?;
+                    Ok(())
+                })
+                .unwrap();
+        }
+        let count = stores
+            .with_db(&path, false, |db, collection| {
+                Ok(count_entries(db, collection)?)
+            })
+            .unwrap();
+        assert_eq!(count, 2);
+        assert!(stores.forget(&path));
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[test]
+    fn test_parse_vec0_dimension() {
+        assert_eq!(
+            parse_vec0_dimension(
+                "CREATE VIRTUAL TABLE embeddings USING vec0(embedding float[768])"
+            ),
+            Some(768)
+        );
+        assert_eq!(
+            parse_vec0_dimension("create table texts (value text)"),
+            None
+        );
+    }
+
+    #[test]
+    fn test_reciprocal_rank_fusion() {
+        let fused = reciprocal_rank_fusion(&[(1.0, vec![1, 2, 3]), (1.0, vec![3, 4])], 60.0);
+        let order = fused.iter().map(|(rowid, _)| *rowid).collect::<Vec<_>>();
+        assert_eq!(order, vec![3, 1, 2, 4]);
+        let fused = reciprocal_rank_fusion(&[(1.0, vec![1, 2]), (5.0, vec![2])], 60.0);
+        assert_eq!(fused[0].0, 2);
+    }
+
+    #[test]
+    fn test_fts_query() {
+        assert_eq!(
+            fts_query("error E-1234?").as_deref(),
+            Some("\"error\" OR \"E\" OR \"1234\"")
+        );
+        assert_eq!(fts_query("?!"), None);
+    }
+
+    #[test]
+    fn test_hybrid_search_finds_identifiers() {
+        let db = open_test_db();
+        insert(&db, "the parser crashed", [1.0, 0.0], &[("kind", "log")]);
+        insert(&db, "unrelated", [0.9, 0.1], &[]);
+        insert(
+            &db,
+            "code E1234 means disk full",
+            [0.0, 1.0],
+            &[("kind", "doc")],
+        );
+        let query = [1.0, 0.0];
+        let default = Collection::default();
+        let vector = search_matches(&db, &default, "E1234", &query, 1, &[], &SearchMode::Vector);
+        assert_eq!(texts(vector.unwrap()), vec!["the parser crashed"]);
+        let lexical = lexical_matches(&db, &default, "E1234", &query, 5, &[]).unwrap();
+        assert_eq!(texts(lexical), vec!["code E1234 means disk full"]);
+        let hybrid = SearchMode::Hybrid(HybridWeights {
+            vector_weight: 1.0,
+            lexical_weight: 2.0,
+            rrf_k: 60.0,
+        });
+        let found = search_matches(&db, &default, "E1234", &query, 1, &[], &hybrid).unwrap();
+        assert_eq!(texts(found), vec!["code E1234 means disk full"]);
+        let log_only = KeyValuePredicate::Equals {
+            key: "kind".to_string(),
+            value: "log".to_string(),
+        };
+        let found = search_matches(&db, &default, "E1234", &query, 3, &[log_only], &hybrid);
+        assert_eq!(texts(found.unwrap()), vec!["the parser crashed"]);
+    }
+
+    #[test]
+    fn test_closest_embedding() {
+        let options = vec![vec![0.0, 1.0], vec![1.0, 0.0], vec![2.0, 0.0]];
+        assert_eq!(
+            closest_embedding(&[1.0, 0.1], &options, DistanceMetric::L2).map(|(index, _)| index),
+            Some(1)
+        );
+        // Both lie in the same direction, so the first is kept.
+        assert_eq!(
+            closest_embedding(&[1.0, 0.0], &options, DistanceMetric::Cosine),
+            Some((1, 0.0))
+        );
+        assert_eq!(
+            closest_embedding(&[1.0, 0.0], &options, DistanceMetric::Dot),
+            Some((2, -2.0))
+        );
+        assert_eq!(
+            closest_embedding(&[1.0, 0.0], &[], DistanceMetric::L2),
+            None
+        );
+    }
+
+    fn labeled(label: &str, distance: f32) -> VectorMatch {
+        VectorMatch {
+            rowid: 0,
+            text: label.to_string(),
+            key_values: vec![("label".to_string(), label.to_string())],
+            distance,
+            metric: DistanceMetric::Cosine,
+        }
+    }
+
+    #[test]
+    fn test_majority_vote() {
+        let neighbors = vec![
+            labeled("spam", 0.1),
+            labeled("ham", 0.2),
+            labeled("ham", 0.3),
+            labeled("spam", 0.4),
+            labeled("ham", 0.5),
+        ];
+        assert_eq!(
+            vote(&neighbors, "label", VoteWeighting::Majority),
+            Some(("ham".to_string(), 0.6))
+        );
+        // Ties go to the closest neighbor's label.
+        assert_eq!(
+            vote(&neighbors[..4], "label", VoteWeighting::Majority),
+            Some(("spam".to_string(), 0.5))
+        );
+        assert_eq!(vote(&neighbors, "topic", VoteWeighting::Majority), None);
+        assert_eq!(vote(&[], "label", VoteWeighting::Majority), None);
+    }
+
+    #[test]
+    fn test_distance_weighted_vote() {
+        let neighbors = vec![
+            labeled("spam", 0.0),
+            labeled("ham", 0.5),
+            labeled("ham", 0.5),
+            labeled("ham", 1.5),
+        ];
+        let (label, confidence) = vote(&neighbors, "label", VoteWeighting::Distance).unwrap();
+        assert_eq!(label, "spam");
+        assert!((confidence - 0.5).abs() < 1e-6);
+        assert_eq!(
+            vote(&neighbors[3..], "label", VoteWeighting::Distance),
+            None
+        );
+    }
+
+    #[test]
+    fn test_mmr_select() {
+        let query = [0.8, 0.6];
+        let candidates = vec![vec![1.0, 0.0], vec![1.0, 0.0], vec![0.0, 1.0]];
+        assert_eq!(
+            mmr_select(&query, &candidates, 2, 1.0, DistanceMetric::Cosine),
+            vec![0, 1]
+        );
+        assert_eq!(
+            mmr_select(&query, &candidates, 2, 0.5, DistanceMetric::Cosine),
+            vec![0, 2]
+        );
+        assert_eq!(
+            mmr_select(&query, &candidates, 5, 0.5, DistanceMetric::Cosine),
+            vec![0, 2, 1]
+        );
+        assert_eq!(
+            mmr_select(&query, &[], 2, 0.5, DistanceMetric::Cosine),
+            Vec::<usize>::new()
+        );
+    }
+
+    #[test]
+    fn test_diversify_skips_duplicates() {
+        let db = open_test_db();
+        insert(&db, "original", [1.0, 0.0], &[]);
+        insert(&db, "copy", [1.0, 0.0], &[]);
+        insert(&db, "other", [0.0, 1.0], &[]);
+        let collection = Collection::default();
+        let mmr = MmrOptions::default();
+        let candidates =
+            query_matches(&db, &collection, &[0.8, 0.6], mmr.candidates(2), &[]).unwrap();
+        assert_eq!(candidates.len(), 3);
+        let diverse =
+            diversify_matches(&db, &collection, &[0.8, 0.6], candidates, 2, &mmr).unwrap();
+        assert_eq!(diverse.len(), 2);
+        assert_eq!(diverse[1].text, "other");
+    }
+
+    #[test]
+    fn test_fts_indexes_existing_texts() {
+        let db = open_test_db();
+        let default = Collection::default();
+        db.execute(&format!("drop table {}", default.fts_table()), [])
+            .unwrap();
+        db.execute_batch(
+            "drop trigger texts_fts_insert; drop trigger texts_fts_delete; drop trigger texts_fts_update;",
+        )
+        .unwrap();
+        insert(&db, "legacy text", [1.0, 0.0], &[]);
+        let found = lexical_matches(&db, &default, "legacy", &[1.0, 0.0], 5, &[]).unwrap();
+        assert_eq!(texts(found), vec!["legacy text"]);
+    }
+
+    #[test]
+    fn test_metric_distances() {
+        let a = [3.0, 4.0];
+        let b = [6.0, 8.0];
+        assert_eq!(DistanceMetric::L2.distance(&a, &b), 5.0);
+        assert!(DistanceMetric::Cosine.distance(&a, &b).abs() < 1e-6);
+        assert_eq!(DistanceMetric::Dot.distance(&a, &b), -50.0);
+        let x = [1.0, 0.0];
+        let y = [0.0, 1.0];
+        for metric in [
+            DistanceMetric::L2,
+            DistanceMetric::Cosine,
+            DistanceMetric::Dot,
+        ] {
+            assert!((metric.similarity(&x, &x) - 1.0).abs() < 1e-6);
+            assert!(metric.similarity(&x, &y).abs() < 1e-6);
+        }
+        assert_eq!(
+            "Cosine".parse::<DistanceMetric>(),
+            Ok(DistanceMetric::Cosine)
+        );
+        assert!("manhattan".parse::<DistanceMetric>().is_err());
+    }
+
+    #[test]
+    fn test_metric_collections() {
+        let db = open_test_db();
+        let cosine = Collection::new("cosine").unwrap();
+        let dot = Collection::new("dot").unwrap();
+        prepare_collection(&db, &cosine, "test-model", 2, DistanceMetric::Cosine).unwrap();
+        prepare_collection(&db, &dot, "test-model", 2, DistanceMetric::Dot).unwrap();
+        for collection in [&Collection::default(), &cosine, &dot] {
+            insert_text(&db, collection, "near", &[1.0, 0.0], None).unwrap();
+            insert_text(&db, collection, "long", &[4.0, 3.0], None).unwrap();
+        }
+        let query = [2.0, 0.0];
+        let l2 = query_matches(&db, &Collection::default(), &query, 2, &[]).unwrap();
+        assert_eq!(texts(l2), vec!["near", "long"]);
+        let found = query_matches(&db, &cosine, &query, 2, &[]).unwrap();
+        assert_eq!(found[0].metric, DistanceMetric::Cosine);
+        assert!((found[0].similarity() - 1.0).abs() < 1e-6);
+        assert!((found[1].similarity() - 0.8).abs() < 1e-6);
+        let found = query_matches(&db, &dot, &query, 2, &[]).unwrap();
+        assert_eq!(texts(found), vec!["long", "near"]);
+        assert_eq!(
+            read_metadata(&db, &dot)
+                .unwrap()
+                .map(|metadata| metadata.metric),
+            Some(DistanceMetric::Dot)
+        );
+        // An existing collection keeps the metric it was created with.
+        prepare_collection(&db, &cosine, "test-model", 2, DistanceMetric::L2).unwrap();
+        assert_eq!(
+            collection_metric(&db, &cosine).unwrap(),
+            DistanceMetric::Cosine
+        );
+    }
+
+    #[test]
+    fn test_metric_column_migration() {
+        register_vec_extension();
+        let db = Connection::open_in_memory().unwrap();
+        db.execute_batch(
+            "create table vector_collections (name text primary key, model text, dimension integer not null);
+            insert into vector_collections values ('default', 'test-model', 2);",
+        )
+        .unwrap();
+        assert_eq!(
+            read_metadata(&db, &Collection::default()).unwrap(),
+            Some(CollectionMetadata {
+                model: Some("test-model".to_string()),
+                dimension: 2,
+                metric: DistanceMetric::L2,
+            })
+        );
+    }
+}
@@ -0,0 +1,930 @@
+use std::{
+    collections::HashSet,
+    sync::{Arc, Mutex, OnceLock},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::prelude::*;
+
+fn add_namespace(namespace: &Option<String>, name: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{}__{}", namespace, name),
+        None => name.to_string(),
+    }
+}
+
+fn strip_namespace<'a>(namespace: &Option<String>, name: &'a str) -> Option<&'a str> {
+    match namespace {
+        Some(namespace) => name
+            .strip_prefix(namespace.as_str())
+            .and_then(|name| name.strip_prefix("__")),
+        None => Some(name),
+    }
+}
+
+fn rename_call(tool_call: &BarkToolCall, function_name: &str) -> BarkToolCall {
+    BarkToolCall {
+        function_name: function_name.to_string(),
+        ..tool_call.clone()
+    }
+}
+
+fn rename_tool(tool: BarkTool, name: String) -> BarkTool {
+    BarkTool { name, ..tool }
+}
+
+/// The names of a caller's tools, listed on first use rather than on every call.
+#[derive(Clone, Default)]
+struct ToolNames(Arc<OnceLock<HashSet<String>>>);
+
+impl ToolNames {
+    fn offers(&self, caller: &impl ToolCaller, function_name: &str) -> bool {
+        self.0
+            .get_or_init(|| {
+                caller
+                    .get_tools(&vec![])
+                    .into_iter()
+                    .map(|tool| tool.name)
+                    .collect()
+            })
+            .contains(function_name)
+    }
+}
+
+/// Reports the errors of both callers when neither could serve a request.
+fn merge_errors(first: String, second: String) -> String {
+    format!("{}; {}", first, second)
+}
+
+/// Convenience constructors for wrapping a [`ToolCaller`] in the combinators below.
+pub trait ToolCallerExt: ToolCaller + Sized {
+    fn merged<B: ToolCaller>(self, second: B) -> Merged<Self, B> {
+        Merged::new(self, second)
+    }
+
+    fn prefixed(self, prefix: impl ToString) -> Prefixed<Self> {
+        Prefixed::new(self, prefix)
+    }
+
+    fn renamed(self, renames: HashMap<String, String>) -> Renamed<Self> {
+        Renamed::new(self, renames)
+    }
+
+    fn filtered(self, filters: Vec<String>) -> Filtered<Self> {
+        Filtered::new(self, filters)
+    }
+
+    fn logged(self, label: impl ToString) -> Logged<Self> {
+        Logged::new(self, label)
+    }
+
+    fn cached(self) -> Cached<Self> {
+        Cached::new(self, vec![])
+    }
+}
+
+impl<T: ToolCaller> ToolCallerExt for T {}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct MergedConfig<A, B> {
+    pub first: A,
+    pub second: B,
+    /// Prefixes the first caller's tools and services with `namespace__`.
+    #[serde(default)]
+    pub first_namespace: Option<String>,
+    /// Prefixes the second caller's tools and services with `namespace__`.
+    #[serde(default)]
+    pub second_namespace: Option<String>,
+}
+
+/// Offers the tools of two callers. Calls go to whichever caller offers the tool, the first one winning.
+#[derive(Clone)]
+pub struct Merged<A, B> {
+    first: A,
+    second: B,
+    first_namespace: Option<String>,
+    second_namespace: Option<String>,
+    first_tools: ToolNames,
+    second_tools: ToolNames,
+}
+
+impl<A: ToolCaller, B: ToolCaller> Merged<A, B> {
+    pub fn new(first: A, second: B) -> Self {
+        Self {
+            first,
+            second,
+            first_namespace: None,
+            second_namespace: None,
+            first_tools: ToolNames::default(),
+            second_tools: ToolNames::default(),
+        }
+    }
+
+    pub fn with_namespaces(mut self, first: Option<String>, second: Option<String>) -> Self {
+        self.first_namespace = first;
+        self.second_namespace = second;
+        self
+    }
+}
+
+impl<A: ToolCaller, B: ToolCaller> ToolCaller for Merged<A, B> {
+    type Config = MergedConfig<A::Config, B::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        let first = A::from_config(&config.first).await;
+        let second = B::from_config(&config.second).await;
+        Self::new(first, second).with_namespaces(
+            config.first_namespace.clone(),
+            config.second_namespace.clone(),
+        )
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        let mut tools = vec![];
+        for (caller_tools, namespace) in [
+            (self.first.get_tools(&vec![]), &self.first_namespace),
+            (self.second.get_tools(&vec![]), &self.second_namespace),
+        ] {
+            for tool in caller_tools {
+                let name = add_namespace(namespace, &tool.name);
+                if apply_tool_filters(filters, &name)
+                    && !tools.iter().any(|tool: &BarkTool| tool.name == name)
+                {
+                    tools.push(rename_tool(tool, name));
+                }
+            }
+        }
+        tools
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        let function_name = tool_call.function_name.as_str();
+        if let Some(name) = strip_namespace(&self.first_namespace, function_name) {
+            if self.first_tools.offers(&self.first, name) {
+                let inner_call = rename_call(tool_call, name);
+                return self
+                    .first
+                    .call_tool(&inner_call, messages)
+                    .await
+                    .map(|response| rename_response(response, function_name));
+            }
+        }
+        if let Some(name) = strip_namespace(&self.second_namespace, function_name) {
+            if self.second_tools.offers(&self.second, name) {
+                let inner_call = rename_call(tool_call, name);
+                return self
+                    .second
+                    .call_tool(&inner_call, messages)
+                    .await
+                    .map(|response| rename_response(response, function_name));
+            }
+        }
+        Err(format!("Tool {} not found", function_name))
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        let first = match strip_namespace(&self.first_namespace, service) {
+            Some(service) => {
+                self.first
+                    .get_prompt(service, prompt_name, arguments.clone())
+                    .await
+            }
+            None => Err(format!(
+                "Prompt {}/{} is not available",
+                service, prompt_name
+            )),
+        };
+        match (first, strip_namespace(&self.second_namespace, service)) {
+            (Ok(messages), _) => Ok(messages),
+            (Err(first), Some(service)) => self
+                .second
+                .get_prompt(service, prompt_name, arguments)
+                .await
+                .map_err(|second| merge_errors(first, second)),
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        let first = match strip_namespace(&self.first_namespace, service) {
+            Some(service) => self.first.read_resource(service, uri).await,
+            None => Err(format!("Resource {}/{} is not available", service, uri)),
+        };
+        match (first, strip_namespace(&self.second_namespace, service)) {
+            (Ok(text), _) => Ok(text),
+            (Err(first), Some(service)) => self
+                .second
+                .read_resource(service, uri)
+                .await
+                .map_err(|second| merge_errors(first, second)),
+            (Err(e), None) => Err(e),
+        }
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        if let Some(name) = strip_namespace(&self.first_namespace, tool_name) {
+            if self.first_tools.offers(&self.first, name) {
+                return self.first.output_policy(name);
+            }
+        }
+        let name = strip_namespace(&self.second_namespace, tool_name)?;
+        self.second.output_policy(name)
+    }
+
+    fn debug(&self) -> String {
+        format!(
+            "Merged({:?}: {}, {:?}: {})",
+            self.first_namespace,
+            self.first.debug(),
+            self.second_namespace,
+            self.second.debug()
+        )
+    }
+}
+
+fn rename_response(response: BarkToolCallResponse, function_name: &str) -> BarkToolCallResponse {
+    BarkToolCallResponse {
+        function_name: function_name.to_string(),
+        ..response
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct PrefixedConfig<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    pub prefix: String,
+}
+
+/// Namespaces every tool and service of a caller as `prefix__name`.
+#[derive(Clone)]
+pub struct Prefixed<T> {
+    inner: T,
+    prefix: Option<String>,
+}
+
+impl<T: ToolCaller> Prefixed<T> {
+    pub fn new(inner: T, prefix: impl ToString) -> Self {
+        Self {
+            inner,
+            prefix: Some(prefix.to_string()),
+        }
+    }
+}
+
+impl<T: ToolCaller> ToolCaller for Prefixed<T> {
+    type Config = PrefixedConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        Self::new(T::from_config(&config.inner).await, &config.prefix)
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        self.inner
+            .get_tools(&vec![])
+            .into_iter()
+            .map(|tool| {
+                let name = add_namespace(&self.prefix, &tool.name);
+                rename_tool(tool, name)
+            })
+            .filter(|tool| apply_tool_filters(filters, &tool.name))
+            .collect()
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        let Some(name) = strip_namespace(&self.prefix, &tool_call.function_name) else {
+            return Err(format!("Tool {} not found", tool_call.function_name));
+        };
+        let inner_call = rename_call(tool_call, name);
+        self.inner
+            .call_tool(&inner_call, messages)
+            .await
+            .map(|response| rename_response(response, &tool_call.function_name))
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        let Some(service) = strip_namespace(&self.prefix, service) else {
+            return Err(format!(
+                "Prompt {}/{} is not available",
+                service, prompt_name
+            ));
+        };
+        self.inner.get_prompt(service, prompt_name, arguments).await
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        let Some(service) = strip_namespace(&self.prefix, service) else {
+            return Err(format!("Resource {}/{} is not available", service, uri));
+        };
+        self.inner.read_resource(service, uri).await
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        self.inner
+            .output_policy(strip_namespace(&self.prefix, tool_name)?)
+    }
+
+    fn debug(&self) -> String {
+        format!("Prefixed({:?}, {})", self.prefix, self.inner.debug())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct RenamedConfig<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    /// Maps the inner caller's tool names to the names offered to the model.
+    pub renames: HashMap<String, String>,
+}
+
+/// Offers some of a caller's tools under different names.
+#[derive(Clone)]
+pub struct Renamed<T> {
+    inner: T,
+    renames: HashMap<String, String>,
+}
+
+impl<T: ToolCaller> Renamed<T> {
+    pub fn new(inner: T, renames: HashMap<String, String>) -> Self {
+        Self { inner, renames }
+    }
+
+    fn outer_name(&self, name: &str) -> String {
+        self.renames
+            .get(name)
+            .cloned()
+            .unwrap_or_else(|| name.to_string())
+    }
+
+    fn inner_name(&self, name: &str) -> Option<String> {
+        match self
+            .renames
+            .iter()
+            .find(|(_inner, outer)| outer.as_str() == name)
+        {
+            Some((inner, _outer)) => Some(inner.clone()),
+            // A renamed tool is only reachable through its new name.
+            None if self.renames.contains_key(name) => None,
+            None => Some(name.to_string()),
+        }
+    }
+}
+
+impl<T: ToolCaller> ToolCaller for Renamed<T> {
+    type Config = RenamedConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        Self::new(T::from_config(&config.inner).await, config.renames.clone())
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        self.inner
+            .get_tools(&vec![])
+            .into_iter()
+            .map(|tool| {
+                let name = self.outer_name(&tool.name);
+                rename_tool(tool, name)
+            })
+            .filter(|tool| apply_tool_filters(filters, &tool.name))
+            .collect()
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        let Some(name) = self.inner_name(&tool_call.function_name) else {
+            return Err(format!("Tool {} not found", tool_call.function_name));
+        };
+        let inner_call = rename_call(tool_call, &name);
+        self.inner
+            .call_tool(&inner_call, messages)
+            .await
+            .map(|response| rename_response(response, &tool_call.function_name))
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        self.inner.get_prompt(service, prompt_name, arguments).await
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        self.inner.read_resource(service, uri).await
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        self.inner.output_policy(&self.inner_name(tool_name)?)
+    }
+
+    fn debug(&self) -> String {
+        format!("Renamed({:?}, {})", self.renames, self.inner.debug())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct FilteredConfig<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    /// Filters in the same syntax as agent tool filters.
+    pub filters: Vec<String>,
+}
+
+/// Hides the tools of a caller which do not pass the given filters. Unlike agent tool filters,
+/// a list of only `!` exclusions hides just the excluded tools.
+#[derive(Clone)]
+pub struct Filtered<T> {
+    inner: T,
+    filters: Vec<String>,
+}
+
+impl<T: ToolCaller> Filtered<T> {
+    pub fn new(inner: T, filters: Vec<String>) -> Self {
+        Self { inner, filters }
+    }
+
+    fn passes(&self, tool_name: &String) -> bool {
+        if self.filters.iter().all(|filter| filter.starts_with('!')) {
+            !self
+                .filters
+                .iter()
+                .any(|filter| tool_name.contains(&filter[1..]))
+        } else {
+            apply_tool_filters(&self.filters, tool_name)
+        }
+    }
+}
+
+impl<T: ToolCaller> ToolCaller for Filtered<T> {
+    type Config = FilteredConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        Self::new(T::from_config(&config.inner).await, config.filters.clone())
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        self.inner
+            .get_tools(filters)
+            .into_iter()
+            .filter(|tool| self.passes(&tool.name))
+            .collect()
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        if !self.passes(&tool_call.function_name) {
+            return Err(format!("Tool {} not found", tool_call.function_name));
+        }
+        self.inner.call_tool(tool_call, messages).await
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        self.inner.get_prompt(service, prompt_name, arguments).await
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        self.inner.read_resource(service, uri).await
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        self.inner.output_policy(tool_name)
+    }
+
+    fn debug(&self) -> String {
+        format!("Filtered({:?}, {})", self.filters, self.inner.debug())
+    }
+}
+
+fn default_log_label() -> String {
+    "tools".to_string()
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct LoggedConfig<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    #[serde(default = "default_log_label")]
+    pub log_label: String,
+}
+
+/// Logs every tool call of a caller, with its duration and outcome.
+#[derive(Clone)]
+pub struct Logged<T> {
+    inner: T,
+    label: String,
+}
+
+impl<T: ToolCaller> Logged<T> {
+    pub fn new(inner: T, label: impl ToString) -> Self {
+        Self {
+            inner,
+            label: label.to_string(),
+        }
+    }
+}
+
+impl<T: ToolCaller> ToolCaller for Logged<T> {
+    type Config = LoggedConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        Self::new(T::from_config(&config.inner).await, &config.log_label)
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        self.inner.get_tools(filters)
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        let label = self.label.clone();
+        log::info!(
+            "[{}] Calling {} with {:?}",
+            label,
+            tool_call.function_name,
+            tool_call.arguments
+        );
+        let start = std::time::Instant::now();
+        let response = self.inner.call_tool(tool_call, messages).await;
+        match &response {
+            Ok(response) => log::info!(
+                "[{}] {} returned {} characters in {:?}",
+                label,
+                tool_call.function_name,
+                response.result.as_ref().map(String::len).unwrap_or(0),
+                start.elapsed()
+            ),
+            Err(e) => log::warn!(
+                "[{}] {} failed in {:?}: {}",
+                label,
+                tool_call.function_name,
+                start.elapsed(),
+                e
+            ),
+        }
+        response
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        log::info!(
+            "[{}] Getting prompt {}/{}",
+            self.label,
+            service,
+            prompt_name
+        );
+        self.inner.get_prompt(service, prompt_name, arguments).await
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        log::info!("[{}] Reading resource {}/{}", self.label, service, uri);
+        self.inner.read_resource(service, uri).await
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        self.inner.output_policy(tool_name)
+    }
+
+    fn debug(&self) -> String {
+        format!("Logged({}, {})", self.label, self.inner.debug())
+    }
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CachedConfig<C> {
+    #[serde(flatten)]
+    pub inner: C,
+    /// Filters selecting the tools whose results are cached. Empty caches every tool.
+    #[serde(default)]
+    pub cached_tools: Vec<String>,
+}
+
+/// Remembers successful tool results, keyed by tool name and arguments.
+/// Only suitable for tools without side effects.
+#[derive(Clone)]
+pub struct Cached<T> {
+    inner: T,
+    cached_tools: Vec<String>,
+    cache: Arc<Mutex<HashMap<String, BarkToolCallResponse>>>,
+}
+
+impl<T: ToolCaller> Cached<T> {
+    pub fn new(inner: T, cached_tools: Vec<String>) -> Self {
+        Self {
+            inner,
+            cached_tools,
+            cache: Default::default(),
+        }
+    }
+
+    fn cache_key(tool_call: &BarkToolCall) -> String {
+        // Parse and re-serialize, so that formatting differences still hit the cache.
+        let arguments = tool_call
+            .arguments
+            .as_ref()
+            .and_then(|arguments| serde_json::from_str::<serde_json::Value>(arguments).ok())
+            .map(|arguments| arguments.to_string())
+            .or_else(|| tool_call.arguments.clone())
+            .unwrap_or_default();
+        format!("{}({})", tool_call.function_name, arguments)
+    }
+}
+
+impl<T: ToolCaller> ToolCaller for Cached<T> {
+    type Config = CachedConfig<T::Config>;
+
+    async fn from_config(config: &Self::Config) -> Self {
+        Self::new(
+            T::from_config(&config.inner).await,
+            config.cached_tools.clone(),
+        )
+    }
+
+    fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+        self.inner.get_tools(filters)
+    }
+
+    async fn call_tool(
+        self,
+        tool_call: &BarkToolCall,
+        messages: &Vec<BarkMessage>,
+    ) -> Result<BarkToolCallResponse, String> {
+        if !apply_tool_filters(&self.cached_tools, &tool_call.function_name) {
+            return self.inner.call_tool(tool_call, messages).await;
+        }
+        let key = Self::cache_key(tool_call);
+        let cached = self.cache.lock().unwrap().get(&key).cloned();
+        if let Some(response) = cached {
+            return Ok(BarkToolCallResponse {
+                id: tool_call.id.clone(),
+                ..response
+            });
+        }
+        let cache = self.cache.clone();
+        let response = self.inner.call_tool(tool_call, messages).await?;
+        cache.lock().unwrap().insert(key, response.clone());
+        Ok(response)
+    }
+
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        self.inner.get_prompt(service, prompt_name, arguments).await
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        self.inner.read_resource(service, uri).await
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        self.inner.output_policy(tool_name)
+    }
+
+    fn debug(&self) -> String {
+        format!("Cached({:?}, {})", self.cached_tools, self.inner.debug())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use super::*;
+
+    #[derive(Debug, Clone, Deserialize, Serialize)]
+    struct EchoConfig {
+        echo_tools: Vec<String>,
+    }
+
+    /// Echoes its arguments back, counting calls.
+    #[derive(Clone)]
+    struct EchoTools {
+        names: Vec<String>,
+        calls: Arc<AtomicUsize>,
+    }
+
+    impl ToolCaller for EchoTools {
+        type Config = EchoConfig;
+
+        async fn from_config(config: &Self::Config) -> Self {
+            Self {
+                names: config.echo_tools.clone(),
+                calls: Default::default(),
+            }
+        }
+
+        fn get_tools(&self, filters: &Vec<String>) -> Vec<BarkTool> {
+            self.names
+                .iter()
+                .filter(|name| apply_tool_filters(filters, name))
+                .map(|name| BarkTool {
+                    name: name.clone(),
+                    description: format!("Echo {}", name),
+                    parameters: serde_json::json!({ "type": "object" }),
+                })
+                .collect()
+        }
+
+        async fn call_tool(
+            self,
+            tool_call: &BarkToolCall,
+            _messages: &Vec<BarkMessage>,
+        ) -> Result<BarkToolCallResponse, String> {
+            if !self.names.contains(&tool_call.function_name) {
+                return Err(format!("Tool {} not found", tool_call.function_name));
+            }
+            self.calls.fetch_add(1, Ordering::SeqCst);
+            Ok(BarkToolCallResponse {
+                id: tool_call.id.clone(),
+                function_name: tool_call.function_name.clone(),
+                arguments: tool_call.arguments.clone(),
+                result: Some(format!(
+                    "{}:{}",
+                    tool_call.function_name,
+                    tool_call.arguments.clone().unwrap_or_default()
+                )),
+                images: vec![],
+            })
+        }
+
+        fn debug(&self) -> String {
+            format!("EchoTools({:?})", self.names)
+        }
+    }
+
+    async fn echo(names: &[&str]) -> EchoTools {
+        EchoTools::from_config(&EchoConfig {
+            echo_tools: names.iter().map(|name| name.to_string()).collect(),
+        })
+        .await
+    }
+
+    fn call(name: &str, arguments: &str) -> BarkToolCall {
+        BarkToolCall {
+            id: "call_1".to_string(),
+            function_name: name.to_string(),
+            arguments: Some(arguments.to_string()),
+        }
+    }
+
+    fn tool_names(caller: &impl ToolCaller, filters: &[&str]) -> Vec<String> {
+        let mut names = caller
+            .get_tools(&filters.iter().map(|filter| filter.to_string()).collect())
+            .into_iter()
+            .map(|tool| tool.name)
+            .collect::<Vec<String>>();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn test_prefixed() {
+        let caller = echo(&["search"]).await.prefixed("web");
+        assert_eq!(tool_names(&caller, &[]), vec!["web__search"]);
+        let response = caller
+            .clone()
+            .call_tool(&call("web__search", "{}"), &vec![])
+            .await
+            .unwrap();
+        assert_eq!(response.function_name, "web__search");
+        assert_eq!(response.result, Some("search:{}".to_string()));
+        assert!(caller
+            .call_tool(&call("search", "{}"), &vec![])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_merged_routes_calls() {
+        let caller = echo(&["a", "shared"])
+            .await
+            .merged(echo(&["b", "shared"]).await);
+        assert_eq!(tool_names(&caller, &[]), vec!["a", "b", "shared"]);
+        assert_eq!(tool_names(&caller, &["!shared", "*"]), vec!["a", "b"]);
+        assert_eq!(tool_names(&caller, &["=b"]), vec!["b"]);
+        let response = caller.call_tool(&call("b", "{}"), &vec![]).await.unwrap();
+        assert_eq!(response.result, Some("b:{}".to_string()));
+    }
+
+    #[tokio::test]
+    async fn test_merged_namespaces() {
+        let first = echo(&["shared"]).await;
+        let second = echo(&["shared"]).await;
+        let caller = first
+            .clone()
+            .merged(second.clone())
+            .with_namespaces(Some("one".to_string()), Some("two".to_string()));
+        assert_eq!(tool_names(&caller, &[]), vec!["one__shared", "two__shared"]);
+        caller
+            .call_tool(&call("two__shared", "{}"), &vec![])
+            .await
+            .unwrap();
+        assert_eq!(first.calls.load(Ordering::SeqCst), 0);
+        assert_eq!(second.calls.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn test_renamed_and_filtered() {
+        let caller = echo(&["lookup", "delete"])
+            .await
+            .renamed(HashMap::from([("lookup".to_string(), "find".to_string())]))
+            .filtered(vec!["!delete".to_string()]);
+        assert_eq!(tool_names(&caller, &[]), vec!["find"]);
+        let response = caller
+            .clone()
+            .call_tool(&call("find", "{}"), &vec![])
+            .await
+            .unwrap();
+        assert_eq!(response.function_name, "find");
+        assert_eq!(response.result, Some("lookup:{}".to_string()));
+        assert!(caller
+            .clone()
+            .call_tool(&call("lookup", "{}"), &vec![])
+            .await
+            .is_err());
+        assert!(caller
+            .call_tool(&call("delete", "{}"), &vec![])
+            .await
+            .is_err());
+    }
+
+    #[tokio::test]
+    async fn test_cached() {
+        let inner = echo(&["lookup"]).await;
+        let caller = inner.clone().logged("test").cached();
+        let first = caller
+            .clone()
+            .call_tool(&call("lookup", "{\"q\": 1}"), &vec![])
+            .await
+            .unwrap();
+        let mut second_call = call("lookup", "{\"q\":1}");
+        second_call.id = "call_2".to_string();
+        let second = caller
+            .clone()
+            .call_tool(&second_call, &vec![])
+            .await
+            .unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 1);
+        assert_eq!(first.result, second.result);
+        assert_eq!(second.id, "call_2");
+        caller
+            .call_tool(&call("lookup", "{\"q\":2}"), &vec![])
+            .await
+            .unwrap();
+        assert_eq!(inner.calls.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_config_flattens() {
+        let config: PrefixedConfig<FilteredConfig<EchoConfig>> =
+            serde_json::from_value(serde_json::json!({
+                "prefix": "web",
+                "filters": ["!delete"],
+                "echo_tools": ["search", "delete"],
+            }))
+            .unwrap();
+        assert_eq!(config.prefix, "web");
+        assert_eq!(config.inner.filters, vec!["!delete"]);
+        assert_eq!(config.inner.inner.echo_tools, vec!["search", "delete"]);
+    }
+}
@@ -1,20 +1,25 @@
 use rmcp::{
     model::{
-        CallToolRequestParam, CallToolResult, ClientCapabilities, ClientInfo, Implementation,
-        ListToolsResult, Tool,
+        CallToolRequestParam, CallToolResult, ClientCapabilities, ClientInfo,
+        GetPromptRequestParam, GetPromptResult, Implementation, ListToolsResult,
+        PromptMessageContent, PromptMessageRole, RawContent, ReadResourceRequestParam,
+        ReadResourceResult, ResourceContents, Tool,
     },
     service::RunningService,
     transport::{SseTransport, TokioChildProcess},
     RoleClient, ServiceExt,
 };
 use serde_json::Value;
-use std::{collections::HashMap, sync::Arc};
+use std::{collections::HashMap, future::Future, sync::Arc, time::Duration};
 use tokio::{process::Command, sync::Mutex, task::JoinHandle};
 
 use anyhow::{anyhow, Error, Result};
 use serde::{Deserialize, Serialize};
 
-use super::{apply_tool_filters, BarkTool, BarkToolCall, BarkToolCallResponse};
+use super::{
+    apply_tool_filters, streamable_http_transport, BarkContent, BarkImage, BarkMessage, BarkRole,
+    BarkTool, BarkToolCall, BarkToolCallResponse, ToolOutputPolicy,
+};
 
 pub trait McpServiceClient: Send + Sync {
     fn call_mcp(
@@ -24,11 +29,47 @@ pub trait McpServiceClient: Send + Sync {
     ) -> JoinHandle<core::result::Result<CallToolResult, Error>>;
 
     fn list_mcp_tools(&self) -> JoinHandle<Result<ListToolsResult, Error>>;
+
+    fn get_mcp_prompt(
+        &self,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> JoinHandle<Result<GetPromptResult, Error>>;
+
+    fn read_mcp_resource(&self, uri: &str) -> JoinHandle<Result<ReadResourceResult, Error>>;
 }
 
 #[derive(Clone)]
 pub struct RunningServiceClient {
     service: Arc<Mutex<RunningService<RoleClient, rmcp::model::InitializeRequestParam>>>,
+    timeout: Option<Duration>,
+}
+
+impl RunningServiceClient {
+    /// Bounds every request made through this client.
+    pub fn with_timeout(mut self, timeout_seconds: Option<f32>) -> Self {
+        self.timeout = timeout_duration(timeout_seconds);
+        self
+    }
+}
+
+/// A configured timeout, where a missing, non-positive or unrepresentable one means none.
+fn timeout_duration(timeout_seconds: Option<f32>) -> Option<Duration> {
+    timeout_seconds
+        .filter(|seconds| *seconds > 0.)
+        .and_then(|seconds| Duration::try_from_secs_f32(seconds).ok())
+}
+
+async fn with_timeout<T>(
+    timeout: Option<Duration>,
+    future: impl Future<Output = Result<T, Error>>,
+) -> Result<T, Error> {
+    match timeout {
+        Some(timeout) => tokio::time::timeout(timeout, future)
+            .await
+            .unwrap_or_else(|_| Err(anyhow!("MCP request timed out after {:?}", timeout))),
+        None => future.await,
+    }
 }
 
 impl From<RunningService<RoleClient, rmcp::model::InitializeRequestParam>>
@@ -37,6 +78,7 @@ impl From<RunningService<RoleClient, rmcp::model::InitializeRequestParam>>
     fn from(service: RunningService<RoleClient, rmcp::model::InitializeRequestParam>) -> Self {
         Self {
             service: Arc::new(Mutex::new(service)),
+            timeout: None,
         }
     }
 }
@@ -52,29 +94,83 @@ impl McpServiceClient for RunningServiceClient {
             arguments: arguments.as_object().cloned(),
         };
         let service = self.service.clone();
+        let timeout = self.timeout;
         tokio::spawn(async move {
             let service = service.lock().await;
-            service
-                .call_tool(tool_request)
-                .await
-                .map_err(|e| anyhow!("Failed to call tool: {}", e))
+            with_timeout(timeout, async {
+                service
+                    .call_tool(tool_request)
+                    .await
+                    .map_err(|e| anyhow!("Failed to call tool: {}", e))
+            })
+            .await
         })
     }
 
     fn list_mcp_tools(&self) -> JoinHandle<Result<ListToolsResult, Error>> {
         let service = self.service.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let service = service.lock().await;
+            with_timeout(timeout, async {
+                service
+                    .list_tools(None)
+                    .await
+                    .map_err(|e| anyhow!("Failed to call tool: {}", e))
+            })
+            .await
+        })
+    }
+
+    fn get_mcp_prompt(
+        &self,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> JoinHandle<Result<GetPromptResult, Error>> {
+        let prompt_request = GetPromptRequestParam {
+            name: prompt_name.to_string(),
+            arguments: Some(
+                arguments
+                    .into_iter()
+                    .map(|(key, value)| (key, Value::String(value)))
+                    .collect(),
+            ),
+        };
+        let service = self.service.clone();
+        let timeout = self.timeout;
         tokio::spawn(async move {
             let service = service.lock().await;
-            service
-                .list_tools(None)
-                .await
-                .map_err(|e| anyhow!("Failed to call tool: {}", e))
+            with_timeout(timeout, async {
+                service
+                    .get_prompt(prompt_request)
+                    .await
+                    .map_err(|e| anyhow!("Failed to get prompt: {}", e))
+            })
+            .await
+        })
+    }
+
+    fn read_mcp_resource(&self, uri: &str) -> JoinHandle<Result<ReadResourceResult, Error>> {
+        let resource_request = ReadResourceRequestParam {
+            uri: uri.to_string(),
+        };
+        let service = self.service.clone();
+        let timeout = self.timeout;
+        tokio::spawn(async move {
+            let service = service.lock().await;
+            with_timeout(timeout, async {
+                service
+                    .read_resource(resource_request)
+                    .await
+                    .map_err(|e| anyhow!("Failed to read resource: {}", e))
+            })
+            .await
         })
     }
 }
 
-fn default_timeout_seconds() -> f32 {
-    30.0
+fn default_timeout_seconds() -> Option<f32> {
+    Some(30.0)
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -83,10 +179,17 @@ pub struct McpServiceConfig {
     pub args: Vec<String>,
     #[serde(default)]
     pub env: HashMap<String, String>,
-    #[serde(default = "default_timeout_seconds")]
-    pub timeout_seconds: f32,
+    /// Bounds every request to the service. Unset, requests may take as long as they need.
+    #[serde(default)]
+    pub timeout_seconds: Option<f32>,
     #[serde(default)]
     pub tool_filters: Vec<String>,
+    /// Applied to the results of every tool of the service.
+    #[serde(default)]
+    pub output_policy: Option<ToolOutputPolicy>,
+    /// Overrides `output_policy` for single tools, keyed by tool name without the service prefix.
+    #[serde(default)]
+    pub tool_output_policies: HashMap<String, ToolOutputPolicy>,
 }
 
 pub async fn initialize_stdio_mcp_service(
@@ -109,11 +212,85 @@ pub async fn initialize_stdio_mcp_service(
     };
     let client = client_info.serve(transport).await?;
 
-    Ok(client.into())
+    Ok(RunningServiceClient::from(client).with_timeout(config.timeout_seconds))
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum McpRemoteTransport {
+    #[default]
+    Sse,
+    StreamableHttp,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct McpRemoteServiceConfig {
+    pub url: String,
+    #[serde(default)]
+    pub transport: McpRemoteTransport,
+    #[serde(default)]
+    pub headers: HashMap<String, String>,
+    /// Name of an environment variable holding a bearer token, sent as the `Authorization` header.
+    #[serde(default)]
+    pub bearer_token_env: Option<String>,
+    /// Bounds connecting and every request to the service, 30 seconds unless set.
+    /// `null` or a non-positive value disables it.
+    #[serde(default = "default_timeout_seconds")]
+    pub timeout_seconds: Option<f32>,
+    #[serde(default)]
+    pub tool_filters: Vec<String>,
+    /// Applied to the results of every tool of the service.
+    #[serde(default)]
+    pub output_policy: Option<ToolOutputPolicy>,
+    /// Overrides `output_policy` for single tools, keyed by tool name without the service prefix.
+    #[serde(default)]
+    pub tool_output_policies: HashMap<String, ToolOutputPolicy>,
+}
+
+impl From<&String> for McpRemoteServiceConfig {
+    fn from(url: &String) -> Self {
+        Self {
+            url: url.clone(),
+            transport: McpRemoteTransport::Sse,
+            headers: HashMap::new(),
+            bearer_token_env: None,
+            // Legacy hosts never had a timeout.
+            timeout_seconds: None,
+            tool_filters: vec![],
+            output_policy: None,
+            tool_output_policies: HashMap::new(),
+        }
+    }
+}
+
+impl McpRemoteServiceConfig {
+    fn http_client(&self) -> Result<reqwest::Client> {
+        let mut headers = reqwest::header::HeaderMap::new();
+        for (name, value) in self.headers.iter() {
+            headers.insert(
+                reqwest::header::HeaderName::from_bytes(name.as_bytes())?,
+                reqwest::header::HeaderValue::from_str(value)?,
+            );
+        }
+        if let Some(env) = &self.bearer_token_env {
+            let token = std::env::var(env)
+                .map_err(|_| anyhow!("Bearer token variable {} is not set", env))?;
+            let mut value = reqwest::header::HeaderValue::from_str(&format!("Bearer {}", token))?;
+            value.set_sensitive(true);
+            headers.insert(reqwest::header::AUTHORIZATION, value);
+        }
+        let mut builder = reqwest::Client::builder().default_headers(headers);
+        if let Some(timeout) = timeout_duration(self.timeout_seconds) {
+            builder = builder.connect_timeout(timeout);
+        }
+        Ok(builder.build()?)
+    }
 }
 
-async fn initialize_sse_mcp_service(name: &str, host: &str) -> Result<RunningServiceClient> {
-    let transport = SseTransport::start(host).await?;
+pub async fn initialize_remote_mcp_service(
+    name: &str,
+    config: &McpRemoteServiceConfig,
+) -> Result<RunningServiceClient> {
+    let http_client = config.http_client()?;
     let client_info = ClientInfo {
         protocol_version: Default::default(),
         capabilities: ClientCapabilities::default(),
@@ -122,8 +299,69 @@ async fn initialize_sse_mcp_service(name: &str, host: &str) -> Result<RunningSer
             version: "1.0.0".to_string(),
         },
     };
-    let client = client_info.serve(transport).await?;
-    Ok(client.into())
+    let client = match config.transport {
+        McpRemoteTransport::Sse => {
+            let transport = SseTransport::start_with_client(&config.url, http_client).await?;
+            client_info.serve(transport).await?
+        }
+        McpRemoteTransport::StreamableHttp => {
+            let transport = streamable_http_transport(
+                http_client,
+                reqwest::Url::parse(&config.url)?,
+                timeout_duration(config.timeout_seconds),
+            );
+            client_info.serve(transport).await?
+        }
+    };
+    Ok(RunningServiceClient::from(client).with_timeout(config.timeout_seconds))
+}
+
+pub async fn initialize_remote_mcp_service_map(
+    config: &HashMap<String, McpRemoteServiceConfig>,
+) -> HashMap<String, RunningServiceClient> {
+    let mut mcp_services = HashMap::new();
+    for (name, service_config) in config.iter() {
+        match initialize_remote_mcp_service(name, service_config).await {
+            Ok(client) => {
+                mcp_services.insert(name.clone(), client);
+            }
+            Err(e) => {
+                eprintln!("Failed to initialize service {name}: {e}");
+            }
+        }
+    }
+    mcp_services
+}
+
+/// The output policies of one MCP service.
+#[derive(Debug, Clone, Default)]
+pub struct McpOutputPolicies {
+    pub service: Option<ToolOutputPolicy>,
+    pub tools: HashMap<String, ToolOutputPolicy>,
+}
+
+impl McpOutputPolicies {
+    pub fn get(&self, tool_name: &str) -> Option<&ToolOutputPolicy> {
+        self.tools.get(tool_name).or(self.service.as_ref())
+    }
+}
+
+impl From<&McpServiceConfig> for McpOutputPolicies {
+    fn from(config: &McpServiceConfig) -> Self {
+        Self {
+            service: config.output_policy.clone(),
+            tools: config.tool_output_policies.clone(),
+        }
+    }
+}
+
+impl From<&McpRemoteServiceConfig> for McpOutputPolicies {
+    fn from(config: &McpRemoteServiceConfig) -> Self {
+        Self {
+            service: config.output_policy.clone(),
+            tools: config.tool_output_policies.clone(),
+        }
+    }
 }
 
 pub async fn initialize_mcp_service_map(
@@ -148,7 +386,7 @@ pub async fn initialize_sse_mcp_service_map(
 ) -> HashMap<String, RunningServiceClient> {
     let mut mcp_services = HashMap::new();
     for (name, host) in hosts.iter() {
-        match initialize_sse_mcp_service(name, host).await {
+        match initialize_remote_mcp_service(name, &host.into()).await {
             Ok(client) => {
                 mcp_services.insert(name.clone(), client);
             }
@@ -221,25 +459,183 @@ impl From<BarkTool> for Tool {
 }
 
 impl BarkToolCallResponse {
+    /// Collects every content part of an MCP tool result.
+    /// Text is concatenated, resources are inlined and images are kept for multimodal backends.
+    /// Error results are returned to the model as text rather than failing the call.
     pub fn try_parse(
         call: &BarkToolCall,
-        mut value: CallToolResult,
+        value: CallToolResult,
     ) -> std::result::Result<Self, String> {
-        if value.is_error.unwrap_or(false) {
-            Err(format!("Tool call error: {:?}", value.content))
+        let mut texts = vec![];
+        let mut images = vec![];
+        for content in value.content {
+            match content.raw {
+                RawContent::Text(text) => texts.push(text.text),
+                RawContent::Image(image) => {
+                    texts.push(format!("[image {}: {}]", images.len(), image.mime_type));
+                    images.push(BarkImage {
+                        data: image.data,
+                        mime_type: image.mime_type,
+                    });
+                }
+                RawContent::Resource(resource) => match resource.resource {
+                    ResourceContents::TextResourceContents { uri, text, .. } => {
+                        texts.push(format!("[resource: {}]\n{}", uri, text));
+                    }
+                    ResourceContents::BlobResourceContents {
+                        uri,
+                        mime_type: Some(mime_type),
+                        blob,
+                    } if mime_type.starts_with("image/") => {
+                        texts.push(format!("[image {}: {} ({})]", images.len(), mime_type, uri));
+                        images.push(BarkImage {
+                            data: blob,
+                            mime_type,
+                        });
+                    }
+                    ResourceContents::BlobResourceContents { uri, mime_type, .. } => {
+                        texts.push(format!(
+                            "[binary resource: {} ({})]",
+                            uri,
+                            mime_type.unwrap_or("unknown type".to_string())
+                        ));
+                    }
+                },
+            }
+        }
+        let text = if texts.is_empty() {
+            "Tool returned no content".to_string()
+        } else {
+            texts.join("\n")
+        };
+        let result = if value.is_error.unwrap_or(false) {
+            format!("Tool call error: {}", text)
         } else {
-            let Some(top) = value.content.pop() else {
-                return Err(format!("Empty tool call response"));
+            text
+        };
+        Ok(BarkToolCallResponse {
+            id: call.id.clone(),
+            function_name: call.function_name.clone(),
+            arguments: call.arguments.clone(),
+            result: Some(result),
+            images,
+        })
+    }
+}
+
+/// Converts the messages of an MCP prompt into chat messages.
+/// Images cannot be represented in a prompt, so they are replaced by a placeholder.
+pub fn mcp_prompt_messages(result: GetPromptResult) -> Vec<BarkMessage> {
+    result
+        .messages
+        .into_iter()
+        .map(|message| {
+            let text = match message.content {
+                PromptMessageContent::Text { text } => text,
+                PromptMessageContent::Image { image } => format!("[image: {}]", image.mime_type),
+                PromptMessageContent::Resource { resource } => resource.get_text(),
             };
-            match top.as_text() {
-                Some(text) => Ok(BarkToolCallResponse {
-                    id: call.id.clone(),
-                    function_name: call.function_name.clone(),
-                    arguments: call.arguments.clone(),
-                    result: Some(text.text.clone()),
-                }),
-                _ => Err(format!("Unsupported tool response type")),
+            BarkMessage {
+                role: match message.role {
+                    PromptMessageRole::User => BarkRole::User,
+                    PromptMessageRole::Assistant => BarkRole::Assistant,
+                },
+                content: BarkContent::Text(text),
             }
-        }
+        })
+        .collect()
+}
+
+/// Joins the text contents of an MCP resource. Binary contents are replaced by a placeholder.
+pub fn mcp_resource_text(result: ReadResourceResult) -> String {
+    result
+        .contents
+        .into_iter()
+        .map(|contents| match contents {
+            ResourceContents::TextResourceContents { text, .. } => text,
+            ResourceContents::BlobResourceContents { uri, mime_type, .. } => format!(
+                "[binary resource: {} ({})]",
+                uri,
+                mime_type.unwrap_or("unknown type".to_string())
+            ),
+        })
+        .collect::<Vec<String>>()
+        .join("\n")
+}
+
+/// Access to MCP prompts and resources, used while resolving
+/// [`PromptValue`](crate::bt::values::PromptValue)s and [`TextValue`](crate::bt::values::TextValue)s.
+/// Lookups never wait on the network: one which misses is recorded and fails, and
+/// [`McpSource::fetch_pending`] fetches what was missed in the background.
+pub trait McpSource: std::fmt::Debug + Send + Sync {
+    fn fetch_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> std::result::Result<Vec<BarkMessage>, String>;
+
+    fn fetch_resource(&self, service: &str, uri: &str) -> std::result::Result<String, String>;
+
+    /// Starts fetching the prompts and resources which lookups missed, if there are any.
+    fn fetch_pending(&self) -> Option<JoinHandle<std::result::Result<(), String>>>;
+}
+
+/// A prompt or resource which was looked up before it was fetched.
+#[derive(Debug, Clone, PartialEq)]
+pub enum McpRequest {
+    Prompt {
+        service: String,
+        prompt_name: String,
+        arguments: HashMap<String, String>,
+    },
+    Resource {
+        service: String,
+        uri: String,
+    },
+}
+
+/// MCP prompts and resources fetched during one run.
+#[derive(Clone, Default)]
+pub struct McpCache {
+    prompts: Arc<std::sync::Mutex<HashMap<String, Vec<BarkMessage>>>>,
+    resources: Arc<std::sync::Mutex<HashMap<String, String>>>,
+}
+
+impl McpCache {
+    pub fn prompt_key(
+        service: &str,
+        prompt_name: &str,
+        arguments: &HashMap<String, String>,
+    ) -> String {
+        let arguments = arguments
+            .iter()
+            .collect::<std::collections::BTreeMap<&String, &String>>();
+        format!(
+            "{}/{}?{}",
+            service,
+            prompt_name,
+            serde_json::to_string(&arguments).unwrap_or_default()
+        )
+    }
+
+    pub fn resource_key(service: &str, uri: &str) -> String {
+        format!("{}/{}", service, uri)
+    }
+
+    pub fn get_prompt(&self, key: &str) -> Option<Vec<BarkMessage>> {
+        self.prompts.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert_prompt(&self, key: String, messages: Vec<BarkMessage>) {
+        self.prompts.lock().unwrap().insert(key, messages);
+    }
+
+    pub fn get_resource(&self, key: &str) -> Option<String> {
+        self.resources.lock().unwrap().get(key).cloned()
+    }
+
+    pub fn insert_resource(&self, key: String, text: String) {
+        self.resources.lock().unwrap().insert(key, text);
     }
 }
@@ -8,6 +8,16 @@ mod mcp;
 pub use mcp::*;
 mod tools;
 pub use tools::*;
+mod streamable_http;
+pub use streamable_http::*;
+mod native;
+pub use native::*;
+mod combinators;
+pub use combinators::*;
+mod schema;
+pub use schema::*;
+mod output;
+pub use output::*;
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct BarkMessage {
@@ -31,6 +41,13 @@ impl BarkMessage {
         }
     }
 
+    pub fn images(&self) -> &[BarkImage] {
+        match &self.content {
+            BarkContent::ToolResponse { images, .. } => images,
+            BarkContent::Text(_) | BarkContent::ToolCall(_) => &[],
+        }
+    }
+
     pub fn tool_id(&self) -> Option<&String> {
         match &self.content {
             BarkContent::Text(_) => None,
@@ -44,7 +61,25 @@ impl BarkMessage {
 pub enum BarkContent {
     Text(String),
     ToolCall(BarkToolCall),
-    ToolResponse { response: String, id: String },
+    ToolResponse {
+        response: String,
+        id: String,
+        #[serde(default)]
+        images: Vec<BarkImage>,
+    },
+}
+
+/// A base64-encoded image, passed to backends which accept multimodal content.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct BarkImage {
+    pub data: String,
+    pub mime_type: String,
+}
+
+impl BarkImage {
+    pub fn data_url(&self) -> String {
+        format!("data:{};base64,{}", self.mime_type, self.data)
+    }
 }
 
 #[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq)]
@@ -74,6 +109,8 @@ pub struct BarkToolCallResponse {
     pub function_name: String,
     pub arguments: Option<String>,
     pub result: Option<String>,
+    #[serde(default)]
+    pub images: Vec<BarkImage>,
 }
 
 #[derive(Debug, Clone, Deserialize, Serialize)]
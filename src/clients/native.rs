@@ -0,0 +1,243 @@
+use std::{
+    collections::HashMap,
+    future::Future,
+    pin::Pin,
+    sync::{Arc, Mutex},
+};
+
+use serde_json::Value;
+
+use super::{BarkTool, BarkToolCall, BarkToolCallResponse};
+
+pub type NativeToolFuture = Pin<Box<dyn Future<Output = Result<String, String>> + Send>>;
+
+pub type NativeToolHandler =
+    Arc<dyn Fn(Value, NativeToolContext) -> NativeToolFuture + Send + Sync>;
+
+/// The caller's user variables, as seen by native tools.
+/// Tools read a snapshot taken when the agent started, and their writes are
+/// applied back to the controller once the agent finishes.
+#[derive(Debug, Clone, Default)]
+pub struct NativeToolContext {
+    variables: Arc<HashMap<String, String>>,
+    updates: Arc<Mutex<HashMap<String, String>>>,
+}
+
+impl NativeToolContext {
+    pub fn new(variables: HashMap<String, String>) -> Self {
+        Self {
+            variables: Arc::new(variables),
+            updates: Default::default(),
+        }
+    }
+
+    pub fn get_variable(&self, name: &str) -> Option<String> {
+        self.updates
+            .lock()
+            .unwrap()
+            .get(name)
+            .or_else(|| self.variables.get(name))
+            .cloned()
+    }
+
+    pub fn set_variable(&self, name: impl ToString, value: impl ToString) {
+        self.updates
+            .lock()
+            .unwrap()
+            .insert(name.to_string(), value.to_string());
+    }
+
+    pub fn take_updates(&self) -> HashMap<String, String> {
+        std::mem::take(&mut *self.updates.lock().unwrap())
+    }
+}
+
+/// A tool implemented by an async Rust closure, registered on the model next to its MCP tools.
+#[derive(Clone)]
+pub struct NativeTool {
+    pub tool: BarkTool,
+    handler: NativeToolHandler,
+}
+
+impl std::fmt::Debug for NativeTool {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("NativeTool")
+            .field("tool", &self.tool)
+            .finish()
+    }
+}
+
+impl NativeTool {
+    pub fn new<F, Fut>(
+        name: impl ToString,
+        description: impl ToString,
+        parameters: Value,
+        handler: F,
+    ) -> Self
+    where
+        F: Fn(Value, NativeToolContext) -> Fut + Send + Sync + 'static,
+        Fut: Future<Output = Result<String, String>> + Send + 'static,
+    {
+        Self {
+            tool: BarkTool {
+                name: name.to_string(),
+                description: description.to_string(),
+                parameters,
+            },
+            handler: Arc::new(move |arguments, context| Box::pin(handler(arguments, context))),
+        }
+    }
+
+    /// Runs the handler. Bad arguments and handler errors are mistakes the model can correct,
+    /// so they are returned to it as the tool's result rather than failing the agent.
+    pub async fn call(
+        &self,
+        tool_call: &BarkToolCall,
+        context: NativeToolContext,
+    ) -> BarkToolCallResponse {
+        let arguments = match &tool_call.arguments {
+            Some(arguments) if !arguments.trim().is_empty() => serde_json::from_str(arguments)
+                .map_err(|e| format!("Failed to parse arguments: {}", e)),
+            _ => Ok(Value::Object(Default::default())),
+        };
+        let result = match arguments {
+            Ok(arguments) => (self.handler)(arguments, context).await,
+            Err(err) => Err(err),
+        };
+        BarkToolCallResponse {
+            id: tool_call.id.clone(),
+            function_name: tool_call.function_name.clone(),
+            arguments: tool_call.arguments.clone(),
+            result: Some(result.unwrap_or_else(|err| format!("Error: {}", err))),
+            images: vec![],
+        }
+    }
+
+    /// Lets the model store a value in one of the caller's variables.
+    pub fn remember_variable() -> Self {
+        Self::new(
+            "remember_variable",
+            "Remember a fact for later by storing it in a named variable.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "description": "The variable to store the fact in." },
+                    "value": { "type": "string", "description": "The fact to remember." }
+                },
+                "required": ["name", "value"]
+            }),
+            |arguments, context| async move {
+                let name = string_argument(&arguments, "name")?;
+                let value = string_argument(&arguments, "value")?;
+                context.set_variable(&name, value);
+                Ok(format!("Stored {}", name))
+            },
+        )
+    }
+
+    /// Lets the model read one of the caller's variables.
+    pub fn lookup_variable() -> Self {
+        Self::new(
+            "lookup_variable",
+            "Look up the value of a named variable.",
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "name": { "type": "string", "description": "The variable to look up." }
+                },
+                "required": ["name"]
+            }),
+            |arguments, context| async move {
+                let name = string_argument(&arguments, "name")?;
+                Ok(context
+                    .get_variable(&name)
+                    .unwrap_or_else(|| format!("Variable {} is not set", name)))
+            },
+        )
+    }
+}
+
+fn string_argument(arguments: &Value, name: &str) -> Result<String, String> {
+    arguments
+        .get(name)
+        .and_then(Value::as_str)
+        .map(str::to_string)
+        .ok_or_else(|| format!("Missing string argument: {}", name))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn call(name: &str, arguments: Value) -> BarkToolCall {
+        BarkToolCall {
+            id: "call_1".to_string(),
+            function_name: name.to_string(),
+            arguments: Some(arguments.to_string()),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_variable_tools() {
+        let context =
+            NativeToolContext::new(HashMap::from([("city".to_string(), "Paris".to_string())]));
+        let lookup = NativeTool::lookup_variable();
+        let remember = NativeTool::remember_variable();
+
+        let response = lookup
+            .call(
+                &call("lookup_variable", serde_json::json!({ "name": "city" })),
+                context.clone(),
+            )
+            .await;
+        assert_eq!(response.result, Some("Paris".to_string()));
+
+        remember
+            .call(
+                &call(
+                    "remember_variable",
+                    serde_json::json!({ "name": "city", "value": "Rome" }),
+                ),
+                context.clone(),
+            )
+            .await;
+        let response = lookup
+            .call(
+                &call("lookup_variable", serde_json::json!({ "name": "city" })),
+                context.clone(),
+            )
+            .await;
+        assert_eq!(response.result, Some("Rome".to_string()));
+
+        assert_eq!(
+            context.take_updates(),
+            HashMap::from([("city".to_string(), "Rome".to_string())])
+        );
+        assert!(context.take_updates().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_errors_are_returned_to_the_model() {
+        let context = NativeToolContext::default();
+        let response = NativeTool::remember_variable()
+            .call(
+                &call("remember_variable", serde_json::json!({ "name": "city" })),
+                context.clone(),
+            )
+            .await;
+        assert_eq!(
+            response.result,
+            Some("Error: Missing string argument: value".to_string())
+        );
+        let mut malformed = call("remember_variable", Value::Null);
+        malformed.arguments = Some("{\"name\": ".to_string());
+        let response = NativeTool::remember_variable()
+            .call(&malformed, context.clone())
+            .await;
+        assert!(response
+            .result
+            .unwrap()
+            .starts_with("Error: Failed to parse arguments"));
+        assert!(context.take_updates().is_empty());
+    }
+}
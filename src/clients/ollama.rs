@@ -1,21 +1,31 @@
-use std::collections::HashMap;
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicUsize, Ordering},
+};
 
 use ollama_rs::{
     generation::{
         chat::{request::ChatMessageRequest, ChatMessage, MessageRole},
+        images::Image,
         tools::{ToolCall, ToolInfo},
     },
     models::ModelOptions,
 };
-use openai_api_rs::v1::chat_completion::Tool;
+use once_cell::sync::Lazy;
 use serde::{Deserialize, Serialize};
 
 use crate::{
-    bt::{AiModelConfig, BarkModelConfig},
+    bt::{
+        AiModelConfig, BarkModelConfig, EmbeddingModelConfig, EmbeddingProvider,
+        DEFAULT_EMBEDDING_MODEL,
+    },
     clients::{BarkToolCall, McpAndTreeConfig},
 };
 
-use super::{BarkChat, BarkResponse, BarkRole, BarkTool};
+use super::{
+    tool_definition, BarkChat, BarkContent, BarkImage, BarkMessage, BarkResponse, BarkRole,
+    BarkTool,
+};
 
 pub fn ollama_get_from_env() -> Option<BarkModelConfig> {
     if let Ok(host) = std::env::var("OLLAMA_HOST") {
@@ -30,17 +40,28 @@ pub fn ollama_get_from_env() -> Option<BarkModelConfig> {
                 temperature: None,
             },
         );
-        let embedding_model = (
-            std::env::var("EMBEDDING_MODEL_NAME").unwrap_or("BAAI/bge-small-en-v1.5".to_string()),
-            host,
-            None,
-        );
+        let embedding_model = EmbeddingModelConfig {
+            provider: EmbeddingProvider::Ollama,
+            model_name: std::env::var("EMBEDDING_MODEL_NAME")
+                .unwrap_or("BAAI/bge-small-en-v1.5".to_string()),
+            url: host,
+            api_key: None,
+            metric: std::env::var("EMBEDDING_METRIC")
+                .ok()
+                .and_then(|metric| metric.parse().ok())
+                .unwrap_or_default(),
+        };
 
         Some(BarkModelConfig {
             openai_models: HashMap::new(),
             ollama_models: models,
             tools: McpAndTreeConfig::default(),
-            embedding_model,
+            embedding_models: HashMap::from([(
+                DEFAULT_EMBEDDING_MODEL.to_string(),
+                embedding_model,
+            )]),
+            embedding_model: None,
+            embedding_cache: std::env::var("EMBEDDING_CACHE").ok(),
             strip_thoughts_in_chat: true,
         })
     } else {
@@ -104,69 +125,115 @@ impl From<BarkRole> for MessageRole {
     }
 }
 
+fn text_message(role: MessageRole, content: String) -> ChatMessage {
+    ChatMessage {
+        role,
+        content,
+        tool_calls: vec![],
+        images: None,
+    }
+}
+
+fn push_images(message: &mut ChatMessage, images: &[BarkImage]) {
+    if !images.is_empty() {
+        message
+            .images
+            .get_or_insert_with(Vec::new)
+            .extend(images.iter().map(|image| Image::from_base64(&image.data)));
+    }
+}
+
+/// Ollama pairs tool responses with calls by position, so the calls of one turn go into a single
+/// assistant message, followed by one tool message per call in the same order.
+fn push_tool_turn(combined: &mut Vec<ChatMessage>, turn: &[BarkMessage]) {
+    let calls = turn
+        .iter()
+        .filter_map(|message| message.tool_call())
+        .collect::<Vec<&BarkToolCall>>();
+    combined.push(ChatMessage {
+        role: MessageRole::Assistant,
+        content: "".to_string(),
+        tool_calls: calls
+            .iter()
+            .filter_map(|call| {
+                MyToolCall::tool_call(call.function_name.clone(), call_arguments(call))
+            })
+            .collect(),
+        images: None,
+    });
+    let mut responses = turn
+        .iter()
+        .filter(|message| message.tool_call().is_none())
+        .collect::<Vec<&BarkMessage>>();
+    responses.sort_by_key(|message| {
+        calls
+            .iter()
+            .position(|call| Some(&call.id) == message.tool_id())
+            .unwrap_or(calls.len())
+    });
+    for response in responses {
+        let mut message = text_message(
+            MessageRole::Tool,
+            response.text_content().cloned().unwrap_or_default(),
+        );
+        push_images(&mut message, response.images());
+        combined.push(message);
+    }
+}
+
+fn call_arguments(call: &BarkToolCall) -> serde_json::Value {
+    match &call.arguments {
+        Some(args) if !args.trim().is_empty() => serde_json::from_str(args).unwrap_or_else(|e| {
+            eprintln!("Invalid arguments for tool call {}: {}", call.id, e);
+            serde_json::Value::Object(Default::default())
+        }),
+        _ => serde_json::Value::Object(Default::default()),
+    }
+}
+
 impl From<BarkChat> for ollama_rs::generation::chat::request::ChatMessageRequest {
     fn from(chat: BarkChat) -> Self {
         let mut combined: Vec<ChatMessage> = vec![];
-        for message in chat.messages {
-            if let Some(text_content) = message.text_content() {
-                if combined.is_empty() {
-                    combined.push(ChatMessage {
-                        role: message.role.into(),
-                        content: text_content.clone(),
-                        tool_calls: vec![],
-                        images: None,
-                    });
-                } else {
-                    let Some(top) = combined.last_mut() else {
-                        panic!("Expected at least one message in combined");
-                    };
-                    if !top.tool_calls.is_empty() {
-                        combined.push(ChatMessage {
-                            role: message.role.into(),
-                            content: text_content.clone(),
-                            tool_calls: vec![],
-                            images: None,
-                        });
-                    } else if matches!(top.role, MessageRole::User)
-                        && matches!(message.role, BarkRole::User)
-                    {
-                        push_content(&mut top.content, text_content);
-                    } else if matches!(top.role, MessageRole::Assistant)
-                        && matches!(message.role, BarkRole::Assistant)
-                    {
-                        push_content(&mut top.content, text_content);
-                    } else if matches!(top.role, MessageRole::System)
-                        && matches!(message.role, BarkRole::System)
-                    {
-                        push_content(&mut top.content, text_content);
-                    } else if matches!(top.role, MessageRole::Tool)
-                        && matches!(message.role, BarkRole::Tool)
-                    {
-                        push_content(&mut top.content, text_content);
-                    } else {
-                        combined.push(ChatMessage {
-                            role: message.role.into(),
-                            content: text_content.clone(),
-                            tool_calls: vec![],
-                            images: None,
-                        });
-                    }
-                }
-            } else if let Some(tool_call) = message.tool_call() {
-                combined.push(ChatMessage {
-                    role: message.role.into(),
-                    content: "".to_string(),
-                    tool_calls: vec![MyToolCall::tool_call(
-                        tool_call.function_name.clone(),
-                        if let Some(args) = &tool_call.arguments {
-                            serde_json::from_str(args).expect("Failed to parse tool call arguments")
+        let mut index = 0;
+        while index < chat.messages.len() {
+            let message = &chat.messages[index];
+            if message.tool_call().is_some() {
+                // A turn is a run of tool calls and the responses to them. A call after a
+                // response starts the next turn.
+                let is_response = |message: &BarkMessage| {
+                    matches!(message.content, BarkContent::ToolResponse { .. })
+                };
+                let end = (index + 1..chat.messages.len())
+                    .find(|&next| {
+                        let message = &chat.messages[next];
+                        if message.tool_call().is_some() {
+                            is_response(&chat.messages[next - 1])
                         } else {
-                            serde_json::Value::Null
-                        },
-                    )],
-                    images: None,
-                });
+                            !is_response(message)
+                        }
+                    })
+                    .unwrap_or(chat.messages.len());
+                push_tool_turn(&mut combined, &chat.messages[index..end]);
+                index = end;
+                continue;
             }
+            index += 1;
+            let Some(text_content) = message.text_content() else {
+                continue;
+            };
+            let mergeable = combined.last().map_or(false, |top| {
+                top.tool_calls.is_empty()
+                    && !matches!(top.role, MessageRole::Tool)
+                    && std::mem::discriminant(&top.role)
+                        == std::mem::discriminant(&MessageRole::from(message.role))
+            });
+            if mergeable {
+                let top = combined.last_mut().unwrap();
+                push_content(&mut top.content, text_content);
+            } else {
+                combined.push(text_message(message.role.into(), text_content.clone()));
+            }
+            push_images(combined.last_mut().unwrap(), message.images());
         }
         let mut result = ChatMessageRequest::new(chat.model, combined);
         if let Some(temperature) = chat.temperature {
@@ -178,12 +245,23 @@ impl From<BarkChat> for ollama_rs::generation::chat::request::ChatMessageRequest
 
 impl From<BarkTool> for ToolInfo {
     fn from(value: BarkTool) -> Self {
-        let open_ai: Tool = value.into();
-        let mut serialized = serde_json::to_string(&open_ai).expect("Failed to serialize tool");
-        serialized = serialized.replace("\"type\":\"function\"", "\"type\":\"Function\"");
-        let result = serde_json::from_str(&serialized)
-            .expect("Failed to convert OpenAI tool to Ollama ToolInfo");
-        result
+        let mut definition = tool_definition(&value);
+        definition["type"] = "Function".into();
+        serde_json::from_value(definition).unwrap_or_else(|e| {
+            eprintln!(
+                "Tool {} has a schema Ollama cannot accept: {}",
+                value.name, e
+            );
+            serde_json::from_value(serde_json::json!({
+                "type": "Function",
+                "function": {
+                    "name": value.name,
+                    "description": value.description,
+                    "parameters": { "type": "object", "properties": {} },
+                }
+            }))
+            .expect("Failed to convert tool to Ollama ToolInfo")
+        })
     }
 }
 
@@ -195,28 +273,147 @@ pub struct MyToolCall {
 #[derive(Serialize, Deserialize)]
 pub struct MyToolCallFunction {
     name: String,
+    #[serde(default)]
     arguments: serde_json::Value,
 }
 
 impl MyToolCall {
-    pub fn tool_call(name: String, arguments: serde_json::Value) -> ToolCall {
+    pub fn tool_call(name: String, arguments: serde_json::Value) -> Option<ToolCall> {
         let my_tool_call = MyToolCall {
             function: MyToolCallFunction { name, arguments },
         };
-        let tool_call_string = serde_json::to_string(&my_tool_call).unwrap();
-        serde_json::from_str(&tool_call_string).unwrap()
+        serde_json::to_value(my_tool_call)
+            .and_then(serde_json::from_value)
+            .map_err(|e| eprintln!("Failed to build Ollama tool call: {}", e))
+            .ok()
     }
 }
 
+static TOOL_CALL_IDS: AtomicUsize = AtomicUsize::new(0);
+
+static RUN_ID: Lazy<u128> = Lazy::new(|| {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map(|duration| duration.as_nanos())
+        .unwrap_or_default()
+});
+
+/// Ollama does not identify tool calls, so each one gets an id unique across runs.
+fn next_tool_call_id() -> String {
+    format!(
+        "ollama_call_{:x}_{}",
+        *RUN_ID,
+        TOOL_CALL_IDS.fetch_add(1, Ordering::Relaxed)
+    )
+}
+
 impl From<&ToolCall> for BarkToolCall {
     fn from(tool_call: &ToolCall) -> Self {
-        let tool_call_string: String = serde_json::to_string(tool_call).unwrap();
-        let MyToolCall { function }: MyToolCall = serde_json::from_str(&tool_call_string).unwrap();
-        let MyToolCallFunction { name, arguments } = function;
-        BarkToolCall {
-            id: "OLLAMA_TOOL_CALL".to_string(),
-            function_name: name.clone(),
-            arguments: Some(arguments.to_string()),
+        let id = next_tool_call_id();
+        match serde_json::to_value(tool_call).and_then(serde_json::from_value::<MyToolCall>) {
+            Ok(MyToolCall {
+                function: MyToolCallFunction { name, arguments },
+            }) => BarkToolCall {
+                id,
+                function_name: name,
+                arguments: Some(match arguments {
+                    // Some models send their arguments as an encoded string.
+                    serde_json::Value::String(arguments) => arguments,
+                    serde_json::Value::Null => "{}".to_string(),
+                    arguments => arguments.to_string(),
+                }),
+            },
+            Err(e) => {
+                eprintln!("Failed to read Ollama tool call: {}", e);
+                BarkToolCall {
+                    id,
+                    function_name: "UNNAMED".to_string(),
+                    arguments: None,
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::prelude::*;
+
+    fn call(id: &str, name: &str, arguments: &str) -> BarkMessage {
+        BarkMessage {
+            role: BarkRole::Assistant,
+            content: BarkContent::ToolCall(BarkToolCall {
+                id: id.to_string(),
+                function_name: name.to_string(),
+                arguments: Some(arguments.to_string()),
+            }),
+        }
+    }
+
+    fn response(id: &str, text: &str) -> BarkMessage {
+        BarkMessage {
+            role: BarkRole::Tool,
+            content: BarkContent::ToolResponse {
+                response: text.to_string(),
+                id: id.to_string(),
+                images: vec![],
+            },
+        }
+    }
+
+    fn request(messages: Vec<BarkMessage>) -> ChatMessageRequest {
+        BarkChat {
+            messages,
+            model: "llama3".to_string(),
+            temperature: None,
         }
+        .into()
+    }
+
+    #[test]
+    fn test_unique_tool_call_ids() {
+        let tool_call =
+            MyToolCall::tool_call("search".to_string(), serde_json::json!({ "q": "bark" }))
+                .unwrap();
+        let first: BarkToolCall = (&tool_call).into();
+        let second: BarkToolCall = (&tool_call).into();
+        assert_ne!(first.id, second.id);
+        assert_eq!(first.function_name, "search");
+        assert_eq!(first.arguments, Some("{\"q\":\"bark\"}".to_string()));
+    }
+
+    #[test]
+    fn test_tool_turn_pairing() {
+        let request = request(vec![
+            user(&"Look both up"),
+            call("a", "lookup", "{\"key\":\"a\"}"),
+            call("b", "lookup", "{\"key\":\"b\"}"),
+            response("b", "second"),
+            response("a", "first"),
+            call("c", "lookup", "{\"key\":\"c\"}"),
+            response("c", "third"),
+            assistant(&"Done"),
+        ]);
+        let messages = request.messages;
+        assert_eq!(messages.len(), 7);
+        assert_eq!(messages[1].tool_calls.len(), 2);
+        assert_eq!(messages[2].content, "first");
+        assert_eq!(messages[3].content, "second");
+        assert_eq!(messages[4].tool_calls.len(), 1);
+        assert_eq!(messages[5].content, "third");
+        assert!(matches!(messages[5].role, MessageRole::Tool));
+        assert_eq!(messages[6].content, "Done");
+    }
+
+    #[test]
+    fn test_bad_arguments_do_not_panic() {
+        let request = request(vec![
+            user(&"Call it"),
+            call("a", "lookup", "{not json"),
+            response("a", "ok"),
+        ]);
+        let arguments = serde_json::to_value(&request.messages[1].tool_calls[0]).unwrap();
+        assert_eq!(arguments["function"]["arguments"], serde_json::json!({}));
     }
 }
@@ -3,8 +3,8 @@ use std::{collections::HashMap, sync::Arc};
 use openai_api_rs::v1::{
     api::OpenAIClient,
     chat_completion::{
-        ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, Content, MessageRole,
-        Tool, ToolCall, ToolCallFunction, ToolType,
+        ChatCompletionMessage, ChatCompletionRequest, ChatCompletionResponse, Content, ContentType,
+        ImageUrl, ImageUrlType, MessageRole, Tool, ToolCall, ToolCallFunction, ToolType,
     },
     embedding::EmbeddingRequest,
     types::{Function, FunctionParameters, JSONSchemaDefine, JSONSchemaType},
@@ -13,14 +13,24 @@ use serde_json::Value;
 use tokio::sync::Mutex;
 
 use crate::{
-    bt::{AiModelConfig, BarkModelConfig},
+    bt::{
+        AiModelConfig, BarkModelConfig, EmbeddingModelConfig, EmbeddingProvider,
+        DEFAULT_EMBEDDING_MODEL,
+    },
     clients::McpAndTreeConfig,
 };
 
-use super::{BarkChat, BarkResponse, BarkRole, BarkTool, BarkToolCall};
+use super::{
+    normalize_tool_schema, BarkChat, BarkImage, BarkResponse, BarkRole, BarkTool, BarkToolCall,
+};
 
 #[derive(Clone)]
-pub struct OpenAI(Arc<Mutex<openai_api_rs::v1::api::OpenAIClient>>);
+pub struct OpenAI {
+    client: Arc<Mutex<openai_api_rs::v1::api::OpenAIClient>>,
+    http: reqwest::Client,
+    api_key: String,
+    url: String,
+}
 
 impl OpenAI {
     pub fn new(api_key: &String, url: &String) -> Self {
@@ -29,7 +39,41 @@ impl OpenAI {
             .with_endpoint(url.clone())
             .build()
             .unwrap();
-        Self(Arc::new(Mutex::new(client)))
+        Self {
+            client: Arc::new(Mutex::new(client)),
+            http: reqwest::Client::new(),
+            api_key: api_key.clone(),
+            url: url.clone(),
+        }
+    }
+
+    /// Sends a chat completion with tools as raw JSON.
+    /// The typed tool definitions of openai-api-rs cannot represent most JSON schemas,
+    /// so the parameters are forwarded as published instead.
+    async fn chat_completion_with_tools(
+        &self,
+        request: Value,
+    ) -> Result<ChatCompletionResponse, String> {
+        let response = self
+            .http
+            .post(format!(
+                "{}/chat/completions",
+                self.url.trim_end_matches('/')
+            ))
+            .bearer_auth(&self.api_key)
+            .json(&request)
+            .send()
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        let status = response.status();
+        let body = response
+            .text()
+            .await
+            .map_err(|e| format!("Error: {:?}", e))?;
+        if !status.is_success() {
+            return Err(format!("Error: {} {}", status, body));
+        }
+        serde_json::from_str(&body).map_err(|e| format!("Error: {:?} in {}", e, body))
     }
 
     pub async fn embeddings_create(
@@ -37,7 +81,7 @@ impl OpenAI {
         model: &str,
         input: Vec<String>,
     ) -> Result<openai_api_rs::v1::embedding::EmbeddingResponse, String> {
-        let mut client = self.0.lock().await;
+        let mut client = self.client.lock().await;
         let request = EmbeddingRequest::new(model.to_string(), input);
         client
             .embedding(request)
@@ -68,17 +112,28 @@ pub fn openai_get_from_env() -> Option<BarkModelConfig> {
                 temperature: None,
             },
         );
-        let embedding_model = (
-            std::env::var("EMBEDDING_MODEL_NAME").unwrap_or("BAAI/bge-small-en-v1.5".to_string()),
-            url.clone(),
-            Some(api_key.clone()),
-        );
+        let embedding_model = EmbeddingModelConfig {
+            provider: EmbeddingProvider::OpenAI,
+            model_name: std::env::var("EMBEDDING_MODEL_NAME")
+                .unwrap_or("BAAI/bge-small-en-v1.5".to_string()),
+            url: url.clone(),
+            api_key: Some(api_key.clone()),
+            metric: std::env::var("EMBEDDING_METRIC")
+                .ok()
+                .and_then(|metric| metric.parse().ok())
+                .unwrap_or_default(),
+        };
 
         Some(BarkModelConfig {
             openai_models: models,
             ollama_models: HashMap::new(),
             tools: McpAndTreeConfig::default(),
-            embedding_model,
+            embedding_models: HashMap::from([(
+                DEFAULT_EMBEDDING_MODEL.to_string(),
+                embedding_model,
+            )]),
+            embedding_model: None,
+            embedding_cache: std::env::var("EMBEDDING_CACHE").ok(),
             strip_thoughts_in_chat: true,
         })
     } else {
@@ -91,20 +146,49 @@ pub async fn openai_get_bark_response(
     chat: BarkChat,
     tools: &Vec<BarkTool>,
 ) -> Result<BarkResponse, String> {
-    let mut client = client.0.lock().await;
     let chat_request: ChatCompletionRequest = chat.into();
-    let chat_request = chat_request.tools(
-        tools
-            .iter()
-            .cloned()
-            .map(|t| t.into())
-            .collect::<Vec<Tool>>(),
-    );
+    if tools.is_empty() {
+        let mut client = client.client.lock().await;
+        return client
+            .chat_completion(chat_request)
+            .await
+            .map(|response| response.into())
+            .map_err(|e| format!("Error: {:?}", e));
+    }
+    let request = chat_request_with_tools(&chat_request, tools)?;
     client
-        .chat_completion(chat_request)
+        .chat_completion_with_tools(request)
         .await
         .map(|response| response.into())
-        .map_err(|e| format!("Error: {:?}", e))
+}
+
+fn chat_request_with_tools(
+    chat_request: &ChatCompletionRequest,
+    tools: &Vec<BarkTool>,
+) -> Result<Value, String> {
+    let mut request = serde_json::to_value(chat_request)
+        .map_err(|e| format!("Failed to serialize chat request: {:?}", e))?;
+    let Some(object) = request.as_object_mut() else {
+        return Err("Chat request is not a JSON object".to_string());
+    };
+    object.retain(|_key, value| !value.is_null());
+    object.insert(
+        "tools".to_string(),
+        Value::Array(tools.iter().map(tool_definition).collect()),
+    );
+    Ok(request)
+}
+
+/// The OpenAI function definition of a tool, with its schema forwarded as published.
+pub fn tool_definition(tool: &BarkTool) -> Value {
+    serde_json::json!({
+        "type": "function",
+        "function": {
+            "name": tool.name,
+            "description": tool.description,
+            "parameters": normalize_tool_schema(&tool.parameters),
+        }
+    })
 }
 
 impl From<ChatCompletionResponse> for BarkResponse {
@@ -143,10 +227,39 @@ impl From<ChatCompletionResponse> for BarkResponse {
 }
 
 fn push_content(content: &mut Content, string: &str) {
-    let Content::Text(ref mut text) = content else {
-        panic!("Expected text content for user message");
-    };
-    text.push_str(string);
+    match content {
+        Content::Text(text) => text.push_str(string),
+        Content::ImageUrl(parts) => parts.push(ImageUrl {
+            r#type: ContentType::text,
+            text: Some(string.to_string()),
+            image_url: None,
+        }),
+    }
+}
+
+/// Tool messages cannot carry images, so they are passed on in a user message instead.
+fn tool_images_message(id: &String, images: &[BarkImage]) -> ChatCompletionMessage {
+    let mut parts = vec![ImageUrl {
+        r#type: ContentType::text,
+        text: Some(format!("Images returned by tool call {}:", id)),
+        image_url: None,
+    }];
+    for image in images {
+        parts.push(ImageUrl {
+            r#type: ContentType::image_url,
+            text: None,
+            image_url: Some(ImageUrlType {
+                url: image.data_url(),
+            }),
+        });
+    }
+    ChatCompletionMessage {
+        role: MessageRole::user,
+        content: Content::ImageUrl(parts),
+        tool_calls: None,
+        name: None,
+        tool_call_id: None,
+    }
 }
 
 impl From<BarkRole> for MessageRole {
@@ -211,6 +324,9 @@ impl From<BarkChat> for ChatCompletionRequest {
                         });
                     }
                 }
+                if let (Some(id), false) = (message.tool_id(), message.images().is_empty()) {
+                    combined.push(tool_images_message(id, message.images()));
+                }
             } else if let Some(tool_call) = message.tool_call() {
                 combined.push(ChatCompletionMessage {
                     role: message.role.into(),
@@ -278,6 +394,7 @@ impl From<&openai_api_rs::v1::chat_completion::ToolCall> for BarkToolCall {
 }
 
 fn get_parameters_from_value(value: Value) -> FunctionParameters {
+    let value = normalize_tool_schema(&value);
     let Some(object) = value.as_object() else {
         return FunctionParameters {
             schema_type: JSONSchemaType::Object,
@@ -326,16 +443,25 @@ fn get_schema_type_from_value(value: &Value) -> JSONSchemaType {
             "object" => JSONSchemaType::Object,
             "array" => JSONSchemaType::Array,
             "string" => JSONSchemaType::String,
-            "number" => JSONSchemaType::Number,
+            "number" | "integer" => JSONSchemaType::Number,
             "boolean" => JSONSchemaType::Boolean,
             _ => JSONSchemaType::Object,
         })
         .unwrap_or(JSONSchemaType::Object)
 }
 
+/// A lossy translation into openai-api-rs' schema types. Requests with tools are sent
+/// through [`tool_definition`] instead, so this only needs to never fail.
 fn get_property_define(value: &Value) -> JSONSchemaDefine {
     let Some(object) = value.as_object() else {
-        panic!("Expected object for property define");
+        return JSONSchemaDefine {
+            schema_type: None,
+            description: None,
+            enum_values: None,
+            properties: None,
+            required: None,
+            items: None,
+        };
     };
     let description = object
         .get("description")
@@ -363,8 +489,10 @@ fn get_property_define(value: &Value) -> JSONSchemaDefine {
         .map(|enum_values| {
             enum_values
                 .iter()
-                .filter_map(|item| item.as_str())
-                .map(|item| item.to_string())
+                .map(|item| match item {
+                    Value::String(item) => item.clone(),
+                    item => item.to_string(),
+                })
                 .collect::<Vec<String>>()
         });
     let properties = object
@@ -469,4 +597,53 @@ mod tests {
             Content::Text("I am fine. Thank you for asking.".to_string())
         );
     }
+
+    #[test]
+    fn test_tools_forwarded_losslessly() {
+        let tool = BarkTool {
+            name: "search".to_string(),
+            description: "Search the web".to_string(),
+            parameters: serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "query": { "$ref": "#/$defs/Query" },
+                    "limit": { "type": "integer", "default": 10 },
+                    "mode": { "enum": [1, "fast"] },
+                    "extra": true
+                },
+                "additionalProperties": false,
+                "$defs": { "Query": { "type": "string", "format": "uri" } }
+            }),
+        };
+        let chat_request: ChatCompletionRequest = BarkChat {
+            messages: vec![user(&"Search for bark-bot")],
+            model: "gpt-4".to_string(),
+            temperature: None,
+        }
+        .into();
+        let request = chat_request_with_tools(&chat_request, &vec![tool.clone()]).unwrap();
+        assert_eq!(request["model"], "gpt-4");
+        assert!(request.get("temperature").is_none());
+        assert_eq!(
+            request["tools"][0]["function"]["parameters"],
+            serde_json::json!({
+                "type": "object",
+                "properties": {
+                    "query": { "type": "string", "format": "uri" },
+                    "limit": { "type": "integer", "default": 10 },
+                    "mode": { "enum": [1, "fast"] },
+                    "extra": {}
+                },
+                "additionalProperties": false
+            })
+        );
+
+        // The typed conversion is lossy, but must not panic.
+        let typed: Tool = tool.into();
+        let properties = typed.function.parameters.properties.unwrap();
+        assert_eq!(
+            properties["mode"].enum_values,
+            Some(vec!["1".to_string(), "fast".to_string()])
+        );
+    }
 }
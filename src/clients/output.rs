@@ -0,0 +1,127 @@
+use std::path::PathBuf;
+
+use serde::{Deserialize, Serialize};
+
+use super::BarkToolCall;
+
+/// Which part of an oversized tool result to keep.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Truncation {
+    Head,
+    Tail,
+    #[default]
+    HeadAndTail,
+}
+
+/// How a tool's result is shortened before it is handed to the model.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ToolOutputPolicy {
+    /// The longest result passed on as is, in characters.
+    #[serde(default)]
+    pub max_length: Option<usize>,
+    #[serde(default)]
+    pub truncation: Truncation,
+    /// A model which summarizes oversized results instead of truncating them.
+    #[serde(default)]
+    pub summarize_model: Option<String>,
+    /// A directory where oversized results are saved in full. The model is told the file's path.
+    #[serde(default)]
+    pub spill_dir: Option<String>,
+}
+
+impl ToolOutputPolicy {
+    pub fn is_oversized(&self, text: &str) -> bool {
+        self.max_length
+            .map(|max_length| text.chars().count() > max_length)
+            .unwrap_or(false)
+    }
+}
+
+/// Cuts `text` down to `max_length` characters, marking how much was left out.
+pub fn truncate_output(text: &str, max_length: usize, truncation: Truncation) -> String {
+    let length = text.chars().count();
+    if length <= max_length {
+        return text.to_string();
+    }
+    let omitted = length - max_length;
+    let marker = format!("[... {} characters omitted ...]", omitted);
+    let head = |count: usize| text.chars().take(count).collect::<String>();
+    let tail = |count: usize| text.chars().skip(length - count).collect::<String>();
+    match truncation {
+        Truncation::Head => format!("{}\n{}", head(max_length), marker),
+        Truncation::Tail => format!("{}\n{}", marker, tail(max_length)),
+        Truncation::HeadAndTail => {
+            let head_length = max_length.div_ceil(2);
+            format!(
+                "{}\n{}\n{}",
+                head(head_length),
+                marker,
+                tail(max_length - head_length)
+            )
+        }
+    }
+}
+
+/// Saves a full tool result under `dir`, returning the path of the new file.
+pub fn spill_output(dir: &str, tool_call: &BarkToolCall, text: &str) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+    let file_name = format!("{}-{}.txt", tool_call.function_name, tool_call.id)
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '-' || c == '_' || c == '.' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect::<String>();
+    let path = PathBuf::from(dir).join(file_name);
+    std::fs::write(&path, text)?;
+    Ok(path)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_truncate_output() {
+        let text = "abcdefghij";
+        assert_eq!(truncate_output(text, 10, Truncation::Head), text);
+        assert_eq!(
+            truncate_output(text, 4, Truncation::Head),
+            "abcd\n[... 6 characters omitted ...]"
+        );
+        assert_eq!(
+            truncate_output(text, 4, Truncation::Tail),
+            "[... 6 characters omitted ...]\nghij"
+        );
+        assert_eq!(
+            truncate_output(text, 5, Truncation::HeadAndTail),
+            "abc\n[... 5 characters omitted ...]\nij"
+        );
+    }
+
+    #[test]
+    fn test_truncate_multibyte() {
+        assert_eq!(
+            truncate_output("héllo wörld", 2, Truncation::HeadAndTail),
+            "h\n[... 9 characters omitted ...]\nd"
+        );
+    }
+
+    #[test]
+    fn test_spill_output() {
+        let dir = std::env::temp_dir().join(format!("bark-spill-{}", std::process::id()));
+        let dir = dir.to_string_lossy().to_string();
+        let call = BarkToolCall {
+            id: "call/1".to_string(),
+            function_name: "web__fetch".to_string(),
+            arguments: None,
+        };
+        let path = spill_output(&dir, &call, "full text").unwrap();
+        assert_eq!(path.file_name().unwrap(), "web__fetch-call_1.txt");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "full text");
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
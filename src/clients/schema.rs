@@ -0,0 +1,235 @@
+use serde_json::{Map, Value};
+
+/// Prepares a tool's parameter schema for a model provider.
+///
+/// Every keyword is kept as published, except that:
+/// - local `$ref`s are inlined and the `$defs`/`definitions` they pointed into are dropped,
+///   with recursive references replaced by an unconstrained schema;
+/// - boolean schemas become their object equivalents (`true` is `{}`, `false` is `{"not": {}}`);
+/// - the root is always an object schema, falling back to an object without properties.
+pub fn normalize_tool_schema(schema: &Value) -> Value {
+    let mut stack = vec![];
+    let normalized = resolve(schema, schema, &mut stack);
+    let Value::Object(mut root) = normalized else {
+        return empty_object_schema();
+    };
+    root.remove("$defs");
+    root.remove("definitions");
+    root.remove("$schema");
+    match root.get("type") {
+        Some(Value::String(schema_type)) if schema_type == "object" => {}
+        None if root.contains_key("properties") => {
+            root.insert("type".to_string(), Value::String("object".to_string()));
+        }
+        _ => return empty_object_schema(),
+    }
+    if !matches!(root.get("properties"), Some(Value::Object(_))) {
+        root.insert("properties".to_string(), Value::Object(Map::new()));
+    }
+    Value::Object(root)
+}
+
+fn empty_object_schema() -> Value {
+    serde_json::json!({ "type": "object", "properties": {} })
+}
+
+fn resolve(root: &Value, schema: &Value, stack: &mut Vec<String>) -> Value {
+    match schema {
+        Value::Bool(true) => Value::Object(Map::new()),
+        Value::Bool(false) => serde_json::json!({ "not": {} }),
+        Value::Object(object) => {
+            if let Some(Value::String(reference)) = object.get("$ref") {
+                if stack.contains(reference) {
+                    // Recursive schemas cannot be inlined; accept anything at this depth.
+                    return keep_annotations(object, Map::new());
+                }
+                let Some(target) = lookup_reference(root, reference) else {
+                    return keep_annotations(object, Map::new());
+                };
+                stack.push(reference.clone());
+                let resolved = resolve(root, target, stack);
+                stack.pop();
+                return match resolved {
+                    Value::Object(resolved) => keep_annotations(object, resolved),
+                    resolved => resolved,
+                };
+            }
+            Value::Object(
+                object
+                    .iter()
+                    .filter(|(key, _)| key.as_str() != "$defs" && key.as_str() != "definitions")
+                    .map(|(key, value)| (key.clone(), resolve_keyword(root, key, value, stack)))
+                    .collect(),
+            )
+        }
+        other => other.clone(),
+    }
+}
+
+/// Only keywords holding subschemas are resolved, so that values such as `default`,
+/// `enum` and `const` pass through untouched even if they look like schemas.
+fn resolve_keyword(root: &Value, key: &str, value: &Value, stack: &mut Vec<String>) -> Value {
+    match key {
+        "properties" | "patternProperties" | "dependentSchemas" => match value {
+            Value::Object(properties) => Value::Object(
+                properties
+                    .iter()
+                    .map(|(name, schema)| (name.clone(), resolve(root, schema, stack)))
+                    .collect(),
+            ),
+            other => other.clone(),
+        },
+        "anyOf" | "oneOf" | "allOf" | "prefixItems" => match value {
+            Value::Array(schemas) => Value::Array(
+                schemas
+                    .iter()
+                    .map(|schema| resolve(root, schema, stack))
+                    .collect(),
+            ),
+            other => other.clone(),
+        },
+        "items"
+        | "additionalProperties"
+        | "additionalItems"
+        | "not"
+        | "if"
+        | "then"
+        | "else"
+        | "contains"
+        | "propertyNames"
+        | "unevaluatedProperties"
+        | "unevaluatedItems" => {
+            match value {
+                Value::Array(schemas) => Value::Array(
+                    schemas
+                        .iter()
+                        .map(|schema| resolve(root, schema, stack))
+                        .collect(),
+                ),
+                // `additionalProperties: false` keeps its meaning as a boolean.
+                Value::Bool(_) if key != "items" && key != "not" => value.clone(),
+                schema => resolve(root, schema, stack),
+            }
+        }
+        _ => value.clone(),
+    }
+}
+
+/// Lets the annotations next to a `$ref` override those of its target.
+fn keep_annotations(object: &Map<String, Value>, mut resolved: Map<String, Value>) -> Value {
+    for (key, value) in object {
+        if key != "$ref" {
+            resolved.insert(key.clone(), value.clone());
+        }
+    }
+    Value::Object(resolved)
+}
+
+fn lookup_reference<'a>(root: &'a Value, reference: &str) -> Option<&'a Value> {
+    let pointer = reference.strip_prefix('#')?;
+    if pointer.is_empty() {
+        return Some(root);
+    }
+    root.pointer(pointer)
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn test_resolves_refs() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "address": { "$ref": "#/$defs/Address", "description": "Where to ship" }
+            },
+            "$defs": {
+                "Address": {
+                    "type": "object",
+                    "properties": { "zip": { "type": "integer", "format": "int32" } },
+                    "additionalProperties": false
+                }
+            }
+        });
+        assert_eq!(
+            normalize_tool_schema(&schema),
+            json!({
+                "type": "object",
+                "properties": {
+                    "address": {
+                        "type": "object",
+                        "description": "Where to ship",
+                        "properties": { "zip": { "type": "integer", "format": "int32" } },
+                        "additionalProperties": false
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_recursive_refs_terminate() {
+        let schema = json!({
+            "type": "object",
+            "properties": { "node": { "$ref": "#/definitions/Node" } },
+            "definitions": {
+                "Node": {
+                    "type": "object",
+                    "properties": { "child": { "$ref": "#/definitions/Node" } }
+                }
+            }
+        });
+        assert_eq!(
+            normalize_tool_schema(&schema),
+            json!({
+                "type": "object",
+                "properties": {
+                    "node": {
+                        "type": "object",
+                        "properties": { "child": {} }
+                    }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_boolean_and_combined_schemas() {
+        let schema = json!({
+            "type": "object",
+            "properties": {
+                "anything": true,
+                "choice": { "anyOf": [{ "type": "string" }, { "type": "null" }], "default": null },
+                "level": { "enum": [1, 2, "max"] }
+            }
+        });
+        assert_eq!(
+            normalize_tool_schema(&schema),
+            json!({
+                "type": "object",
+                "properties": {
+                    "anything": {},
+                    "choice": { "anyOf": [{ "type": "string" }, { "type": "null" }], "default": null },
+                    "level": { "enum": [1, 2, "max"] }
+                }
+            })
+        );
+    }
+
+    #[test]
+    fn test_root_fallback() {
+        assert_eq!(normalize_tool_schema(&json!(true)), empty_object_schema());
+        assert_eq!(
+            normalize_tool_schema(&json!({ "type": "string" })),
+            empty_object_schema()
+        );
+        assert_eq!(normalize_tool_schema(&json!({})), empty_object_schema());
+        assert_eq!(
+            normalize_tool_schema(&json!({ "properties": { "a": { "type": "string" } } })),
+            json!({ "type": "object", "properties": { "a": { "type": "string" } } })
+        );
+    }
+}
@@ -0,0 +1,396 @@
+use std::time::Duration;
+
+use futures::{channel::mpsc, Sink, Stream};
+use reqwest::{
+    header::{ACCEPT, CONTENT_TYPE},
+    StatusCode, Url,
+};
+use rmcp::model::{ClientJsonRpcMessage, ServerJsonRpcMessage};
+use serde_json::Value;
+
+const SESSION_HEADER: &str = "Mcp-Session-Id";
+
+struct StreamableHttpState {
+    client: reqwest::Client,
+    url: Url,
+    timeout: Option<Duration>,
+    session: Option<String>,
+    sender: mpsc::UnboundedSender<ServerJsonRpcMessage>,
+}
+
+fn io_error(error: impl ToString) -> std::io::Error {
+    std::io::Error::other(error.to_string())
+}
+
+impl StreamableHttpState {
+    async fn post(&mut self, message: ClientJsonRpcMessage) -> Result<(), std::io::Error> {
+        let mut request = self
+            .client
+            .post(self.url.clone())
+            .header(ACCEPT, "application/json, text/event-stream")
+            .json(&message);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        if let Some(session) = &self.session {
+            request = request.header(SESSION_HEADER, session);
+        }
+        let response = request
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .map_err(io_error)?;
+        if let Some(session) = response
+            .headers()
+            .get(SESSION_HEADER)
+            .and_then(|session| session.to_str().ok())
+        {
+            self.session = Some(session.to_string());
+        }
+        if response.status() == StatusCode::ACCEPTED {
+            return Ok(());
+        }
+        let event_stream = response
+            .headers()
+            .get(CONTENT_TYPE)
+            .and_then(|content_type| content_type.to_str().ok())
+            .map(|content_type| content_type.starts_with("text/event-stream"))
+            .unwrap_or(false);
+        let body = response.text().await.map_err(io_error)?;
+        let payloads = if event_stream {
+            parse_event_stream(&body)
+        } else {
+            vec![body]
+        };
+        for payload in payloads {
+            if payload.trim().is_empty() {
+                continue;
+            }
+            let values = match serde_json::from_str::<Value>(&payload).map_err(io_error)? {
+                Value::Array(batch) => batch,
+                value => vec![value],
+            };
+            for value in values {
+                match serde_json::from_value::<ServerJsonRpcMessage>(value) {
+                    Ok(message) => self.sender.unbounded_send(message).map_err(io_error)?,
+                    Err(e) => eprintln!("Failed to parse MCP message: {}", e),
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Answers a request whose POST failed with a JSON-RPC error, so that its caller does not
+    /// wait for a response which will never come. Fails only once the transport is closed.
+    fn fail_request(&self, id: Option<Value>, error: std::io::Error) -> Result<(), std::io::Error> {
+        let Some(id) = id else {
+            eprintln!("Failed to send MCP notification: {}", error);
+            return Ok(());
+        };
+        let response = serde_json::json!({
+            "jsonrpc": "2.0",
+            "id": id,
+            "error": { "code": -32603, "message": error.to_string() }
+        });
+        let response =
+            serde_json::from_value::<ServerJsonRpcMessage>(response).map_err(io_error)?;
+        self.sender.unbounded_send(response).map_err(io_error)
+    }
+}
+
+impl Drop for StreamableHttpState {
+    /// Ends the session on the server once the transport is dropped.
+    fn drop(&mut self) {
+        let (Some(session), Ok(runtime)) =
+            (self.session.take(), tokio::runtime::Handle::try_current())
+        else {
+            return;
+        };
+        let mut request = self
+            .client
+            .delete(self.url.clone())
+            .header(SESSION_HEADER, session);
+        if let Some(timeout) = self.timeout {
+            request = request.timeout(timeout);
+        }
+        runtime.spawn(async move {
+            // Servers may not allow clients to end sessions, which is fine.
+            let _ = request.send().await;
+        });
+    }
+}
+
+/// Collects the `data` of each event in a `text/event-stream` body.
+pub fn parse_event_stream(body: &str) -> Vec<String> {
+    let mut events = vec![];
+    let mut data: Vec<&str> = vec![];
+    for line in body.lines() {
+        if line.is_empty() {
+            if !data.is_empty() {
+                events.push(data.join("\n"));
+                data.clear();
+            }
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    if !data.is_empty() {
+        events.push(data.join("\n"));
+    }
+    events
+}
+
+/// Client side of the MCP streamable HTTP transport.
+///
+/// Every message is POSTed to `url`, and the server answers with either a JSON body or an
+/// event stream. Requests are sent one at a time, so the session id returned by the server
+/// on initialization is always in place for the messages which follow it. A request which
+/// fails to send is answered with a JSON-RPC error rather than closing the transport, and the
+/// session is ended with a `DELETE` once the transport is dropped.
+pub fn streamable_http_transport(
+    client: reqwest::Client,
+    url: Url,
+    timeout: Option<Duration>,
+) -> (
+    impl Sink<ClientJsonRpcMessage, Error = std::io::Error> + Send + 'static,
+    impl Stream<Item = ServerJsonRpcMessage> + Send + 'static,
+) {
+    let (sender, receiver) = mpsc::unbounded();
+    let state = StreamableHttpState {
+        client,
+        url,
+        timeout,
+        session: None,
+        sender,
+    };
+    let sink = futures::sink::unfold(state, |mut state, message| async move {
+        let id = serde_json::to_value(&message)
+            .ok()
+            .and_then(|message| message.get("id").cloned());
+        if let Err(error) = state.post(message).await {
+            state.fail_request(id, error)?;
+        }
+        Ok::<_, std::io::Error>(state)
+    });
+    (sink, receiver)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use futures::{SinkExt, StreamExt};
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::{TcpListener, TcpStream},
+    };
+
+    use super::*;
+    use crate::clients::*;
+
+    #[test]
+    fn test_parse_event_stream() {
+        let body = "event: message\ndata: {\"a\":1}\n\ndata: {\"b\":\ndata: 2}\n\n";
+        assert_eq!(
+            parse_event_stream(body),
+            vec!["{\"a\":1}".to_string(), "{\"b\":\n2}".to_string()]
+        );
+    }
+
+    async fn read_request(stream: &mut TcpStream) -> (String, Value) {
+        let mut buffer = vec![];
+        let mut chunk = [0; 1024];
+        let head_end = loop {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buffer.extend_from_slice(&chunk[..read]);
+            if let Some(end) = buffer.windows(4).position(|window| window == b"\r\n\r\n") {
+                break end + 4;
+            }
+        };
+        let head = String::from_utf8_lossy(&buffer[..head_end]).to_string();
+        let content_length = head
+            .lines()
+            .find_map(|line| {
+                let (name, value) = line.split_once(':')?;
+                name.eq_ignore_ascii_case("content-length")
+                    .then(|| value.trim().parse::<usize>().ok())
+                    .flatten()
+            })
+            .unwrap_or(0);
+        while buffer.len() < head_end + content_length {
+            let read = stream.read(&mut chunk).await.unwrap();
+            buffer.extend_from_slice(&chunk[..read]);
+        }
+        let body = serde_json::from_slice(&buffer[head_end..head_end + content_length])
+            .unwrap_or(Value::Null);
+        (head, body)
+    }
+
+    async fn respond(stream: &mut TcpStream, status: &str, content_type: &str, body: &str) {
+        let response = format!(
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nContent-Length: {}\r\n{}: test-session\r\nConnection: close\r\n\r\n{}",
+            status,
+            content_type,
+            body.len(),
+            SESSION_HEADER,
+            body
+        );
+        stream.write_all(response.as_bytes()).await.unwrap();
+        stream.shutdown().await.unwrap();
+    }
+
+    /// A minimal streamable HTTP MCP server with a single `echo` tool, which fails pings.
+    async fn serve(listener: TcpListener, heads: Arc<Mutex<Vec<String>>>) {
+        loop {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            let (head, body) = read_request(&mut stream).await;
+            heads.lock().unwrap().push(head);
+            let id = body.get("id").cloned();
+            let result = match body.get("method").and_then(Value::as_str) {
+                Some("ping") => {
+                    respond(&mut stream, "500 Internal Server Error", "text/plain", "").await;
+                    continue;
+                }
+                Some("initialize") => serde_json::json!({
+                    "protocolVersion": "2024-11-05",
+                    "capabilities": { "tools": {} },
+                    "serverInfo": { "name": "test", "version": "1.0.0" }
+                }),
+                Some("tools/list") => serde_json::json!({
+                    "tools": [{
+                        "name": "echo",
+                        "description": "Echoes its input",
+                        "inputSchema": { "type": "object" }
+                    }]
+                }),
+                Some("tools/call") => serde_json::json!({
+                    "content": [{
+                        "type": "text",
+                        "text": format!("echo: {}", body["params"]["arguments"]["text"].as_str().unwrap_or(""))
+                    }]
+                }),
+                _ => {
+                    respond(&mut stream, "202 Accepted", "application/json", "").await;
+                    continue;
+                }
+            };
+            let message = serde_json::json!({ "jsonrpc": "2.0", "id": id, "result": result });
+            if body.get("method").and_then(Value::as_str) == Some("tools/call") {
+                let body = format!("event: message\ndata: {}\n\n", message);
+                respond(&mut stream, "200 OK", "text/event-stream", &body).await;
+            } else {
+                respond(
+                    &mut stream,
+                    "200 OK",
+                    "application/json",
+                    &message.to_string(),
+                )
+                .await;
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_streamable_http_service() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let heads = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve(listener, heads.clone()));
+
+        std::env::set_var("BARK_TEST_MCP_TOKEN", "secret-token");
+        let config: McpRemoteServiceConfig = serde_json::from_value(serde_json::json!({
+            "url": format!("http://{}/mcp", address),
+            "transport": "StreamableHttp",
+            "headers": { "X-Test": "bark" },
+            "bearer_token_env": "BARK_TEST_MCP_TOKEN",
+            "timeout_seconds": 5.0
+        }))
+        .unwrap();
+        let client = initialize_remote_mcp_service("test", &config)
+            .await
+            .unwrap();
+
+        let tools = client.list_mcp_tools().await.unwrap().unwrap();
+        assert_eq!(tools.tools.len(), 1);
+        assert_eq!(tools.tools[0].name, "echo");
+
+        let call = BarkToolCall {
+            id: "call_1".to_string(),
+            function_name: "test__echo".to_string(),
+            arguments: None,
+        };
+        let result = client
+            .call_mcp("echo", serde_json::json!({ "text": "hi" }))
+            .await
+            .unwrap()
+            .unwrap();
+        let response = BarkToolCallResponse::try_parse(&call, result).unwrap();
+        assert_eq!(response.result, Some("echo: hi".to_string()));
+
+        let heads = heads.lock().unwrap();
+        assert!(heads.len() >= 3);
+        for head in heads.iter() {
+            let head = head.to_ascii_lowercase();
+            assert!(head.contains("authorization: bearer secret-token"));
+            assert!(head.contains("x-test: bark"));
+        }
+        assert!(heads[1..].iter().all(|head| head
+            .to_ascii_lowercase()
+            .contains("mcp-session-id: test-session")));
+    }
+
+    async fn wait_for_delete(heads: &Arc<Mutex<Vec<String>>>) -> Option<String> {
+        for _ in 0..100 {
+            let delete = heads
+                .lock()
+                .unwrap()
+                .iter()
+                .find(|head| head.starts_with("DELETE"))
+                .cloned();
+            if delete.is_some() {
+                return delete;
+            }
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+        None
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn test_failed_request_keeps_transport() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        let heads = Arc::new(Mutex::new(vec![]));
+        tokio::spawn(serve(listener, heads.clone()));
+
+        let url = Url::parse(&format!("http://{}/mcp", address)).unwrap();
+        let (sink, stream) =
+            streamable_http_transport(reqwest::Client::new(), url, Some(Duration::from_secs(5)));
+        let mut sink = Box::pin(sink);
+        let mut stream = Box::pin(stream);
+        let request = |value: Value| serde_json::from_value::<ClientJsonRpcMessage>(value).unwrap();
+
+        sink.send(request(
+            serde_json::json!({ "jsonrpc": "2.0", "id": 1, "method": "ping" }),
+        ))
+        .await
+        .unwrap();
+        let failed = serde_json::to_value(stream.next().await.unwrap()).unwrap();
+        assert_eq!(failed["id"], 1);
+        assert_eq!(failed["error"]["code"], -32603);
+
+        sink.send(request(
+            serde_json::json!({ "jsonrpc": "2.0", "id": 2, "method": "tools/list" }),
+        ))
+        .await
+        .unwrap();
+        let listed = serde_json::to_value(stream.next().await.unwrap()).unwrap();
+        assert_eq!(listed["id"], 2);
+        assert_eq!(listed["result"]["tools"][0]["name"], "echo");
+
+        drop(sink);
+        let delete = wait_for_delete(&heads).await.expect("No DELETE sent");
+        assert!(delete
+            .to_ascii_lowercase()
+            .contains("mcp-session-id: test-session"));
+    }
+}
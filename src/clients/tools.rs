@@ -47,6 +47,32 @@ pub trait ToolCaller: Clone + Send + Sync + 'static {
         messages: &Vec<BarkMessage>,
     ) -> impl std::future::Future<Output = Result<BarkToolCallResponse, String>> + Send;
 
+    /// Fetches a prompt published by one of the caller's services.
+    fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        _arguments: HashMap<String, String>,
+    ) -> impl std::future::Future<Output = Result<Vec<BarkMessage>, String>> + Send {
+        let error = format!("Prompt {}/{} is not available", service, prompt_name);
+        async move { Err(error) }
+    }
+
+    /// Reads a resource published by one of the caller's services.
+    fn read_resource(
+        &self,
+        service: &str,
+        uri: &str,
+    ) -> impl std::future::Future<Output = Result<String, String>> + Send {
+        let error = format!("Resource {}/{} is not available", service, uri);
+        async move { Err(error) }
+    }
+
+    /// How the result of the named tool is shortened before it reaches the model.
+    fn output_policy(&self, _tool_name: &str) -> Option<ToolOutputPolicy> {
+        None
+    }
+
     fn debug(&self) -> String;
 }
 
@@ -56,6 +82,8 @@ pub struct McpAndTreeConfig {
     pub mcp_services: HashMap<String, McpServiceConfig>,
     #[serde(default)]
     pub mcp_sse_hosts: HashMap<String, String>,
+    #[serde(default)]
+    pub mcp_remote_services: HashMap<String, McpRemoteServiceConfig>,
     // #[serde(default)]
     // pub tree_services: HashMap<String, TreeServiceConfig>,
 }
@@ -65,6 +93,7 @@ pub struct McpAndTree {
     mcp_services: HashMap<String, RunningServiceClient>,
     // tree_services: HashMap<String, BarkDef>,
     tools_map: HashMap<String, BarkTool>,
+    output_policies: HashMap<String, McpOutputPolicies>,
 }
 
 impl ToolCaller for McpAndTree {
@@ -73,15 +102,30 @@ impl ToolCaller for McpAndTree {
     async fn from_config(config: &Self::Config) -> Self {
         let mut mcp_services = initialize_mcp_service_map(&config.mcp_services).await;
         mcp_services.extend(initialize_sse_mcp_service_map(&config.mcp_sse_hosts).await);
+        mcp_services.extend(initialize_remote_mcp_service_map(&config.mcp_remote_services).await);
         let service_filters = config
             .mcp_services
             .iter()
-            .map(|(name, config)| {
-                let filters = config.tool_filters.clone();
-                (name.clone(), filters)
-            })
+            .map(|(name, config)| (name.clone(), config.tool_filters.clone()))
+            .chain(
+                config
+                    .mcp_remote_services
+                    .iter()
+                    .map(|(name, config)| (name.clone(), config.tool_filters.clone())),
+            )
             .collect::<HashMap<String, Vec<String>>>();
         let tools_map = initialize_mcp_tool_map(&mcp_services, &service_filters).await;
+        let output_policies = config
+            .mcp_services
+            .iter()
+            .map(|(name, config)| (name.clone(), config.into()))
+            .chain(
+                config
+                    .mcp_remote_services
+                    .iter()
+                    .map(|(name, config)| (name.clone(), config.into())),
+            )
+            .collect::<HashMap<String, McpOutputPolicies>>();
 
         // let tree_services = config
         //     .tree_services
@@ -106,6 +150,7 @@ impl ToolCaller for McpAndTree {
             // tree_services,
             // tree_services: HashMap::new(), // Disable tree services for now
             tools_map,
+            output_policies,
         }
     }
 
@@ -184,6 +229,43 @@ impl ToolCaller for McpAndTree {
         }
     }
 
+    async fn get_prompt(
+        &self,
+        service: &str,
+        prompt_name: &str,
+        arguments: HashMap<String, String>,
+    ) -> Result<Vec<BarkMessage>, String> {
+        let Some(mcp_service) = self.mcp_services.get(service) else {
+            return Err(format!("MCP service {} not found", service));
+        };
+        mcp_service
+            .get_mcp_prompt(prompt_name, arguments)
+            .await
+            .unwrap_or(Err(anyhow!("Failed to get prompt {}", prompt_name)))
+            .map(mcp_prompt_messages)
+            .map_err(|e| e.to_string())
+    }
+
+    async fn read_resource(&self, service: &str, uri: &str) -> Result<String, String> {
+        let Some(mcp_service) = self.mcp_services.get(service) else {
+            return Err(format!("MCP service {} not found", service));
+        };
+        mcp_service
+            .read_mcp_resource(uri)
+            .await
+            .unwrap_or(Err(anyhow!("Failed to read resource {}", uri)))
+            .map(mcp_resource_text)
+            .map_err(|e| e.to_string())
+    }
+
+    fn output_policy(&self, tool_name: &str) -> Option<ToolOutputPolicy> {
+        let (service, function_name) = tool_name.split_once("__")?;
+        self.output_policies
+            .get(service)
+            .and_then(|policies| policies.get(function_name))
+            .cloned()
+    }
+
     fn debug(&self) -> String {
         format!(
             "Tools Map: {:?}",
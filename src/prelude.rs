@@ -1,5 +1,10 @@
 use crate::bt::strip_thoughts;
-pub use crate::bt::values::{MessageValue, PromptValue, TextMatcher, TextValue, VariableId};
+pub use crate::bt::values::{
+    EntryFilter, KeyValueFilter, MessageValue, PromptValue, TextMatcher, TextValue, VariableId,
+};
+pub use crate::bt::vector::{
+    DistanceMetric, HybridWeights, MmrOptions, SearchMode, VectorThreshold, VoteWeighting,
+};
 pub use crate::bt::BarkDef;
 pub use crate::bt::BarkNode;
 pub use crate::bt::{BarkController, BarkFunction, BarkModel, BarkModelConfig, BarkState};
@@ -36,12 +41,15 @@ pub fn assistant(s: &impl ToString) -> BarkMessage {
     }
 }
 
+/// The L2 distance between two embeddings, as vector search ranks collections by default.
+/// See [`score_with`] to match a collection's metric.
 pub fn score(embed_a: &[f32], embed_b: &[f32]) -> f32 {
-    let mut sum = 0.0;
-    for (a, b) in embed_a.iter().zip(embed_b.iter()) {
-        sum += (a - b).powi(2);
-    }
-    sum
+    score_with(DistanceMetric::default(), embed_a, embed_b)
+}
+
+/// The distance between two embeddings under `metric`, as vector search would rank them.
+pub fn score_with(metric: DistanceMetric, embed_a: &[f32], embed_b: &[f32]) -> f32 {
+    metric.distance(embed_a, embed_b)
 }
 
 pub fn read_tree<TC: ToolCaller>(root: impl AsRef<Path>, tree_path: &str) -> BarkDef<TC> {
@@ -99,25 +107,79 @@ pub async fn powered_prompt<TC: ToolCaller>(
     }
 }
 
+/// Limits and finish conditions for the agent loop in [`powered_chat`].
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct AgentLimits {
+    /// The maximum number of model turns which may request tool calls.
+    #[serde(default)]
+    pub max_tool_turns: Option<usize>,
+    /// The maximum wall-clock time for the whole loop, in seconds.
+    #[serde(default)]
+    pub max_seconds: Option<f32>,
+    /// A tool the model calls to finish. Its arguments become the agent's output.
+    #[serde(default)]
+    pub final_answer: Option<BarkTool>,
+}
+
+/// The structured arguments of a final answer tool call.
+pub type FinalAnswer = serde_json::Map<String, serde_json::Value>;
+
+async fn before_deadline<T>(
+    deadline: Option<tokio::time::Instant>,
+    future: impl std::future::Future<Output = T>,
+) -> Option<T> {
+    match deadline {
+        Some(deadline) => tokio::time::timeout_at(deadline, future).await.ok(),
+        None => Some(future.await),
+    }
+}
+
 pub async fn powered_chat<TC: ToolCaller>(
     preferred_model: Option<String>,
     mut prompt: Vec<BarkMessage>,
     model: BarkModel<TC>,
     mut gas: Option<i32>,
-    tools: Vec<BarkTool>,
+    mut tools: Vec<BarkTool>,
+    limits: AgentLimits,
+    context: NativeToolContext,
 ) -> Result<
-    (String, Vec<BarkMessage>, BarkState, Option<i32>),
+    (
+        String,
+        Option<FinalAnswer>,
+        Vec<BarkMessage>,
+        BarkState,
+        Option<i32>,
+    ),
     (String, Vec<BarkMessage>, Option<i32>),
 > {
+    // A limit too far off to represent is no limit at all.
+    let deadline = limits
+        .max_seconds
+        .and_then(|seconds| std::time::Duration::try_from_secs_f32(seconds.max(0.0)).ok())
+        .and_then(|duration| tokio::time::Instant::now().checked_add(duration));
+    let timed_out = || {
+        format!(
+            "Agent exceeded maximum time ({}s)",
+            limits.max_seconds.unwrap_or_default()
+        )
+    };
+    if let Some(final_answer) = &limits.final_answer {
+        tools.push(final_answer.clone());
+    }
+    let mut tool_turns = 0;
     loop {
-        let response = model
-            .clone()
-            .chat_completion_create(
+        let Some(response) = before_deadline(
+            deadline,
+            model.clone().chat_completion_create(
                 preferred_model.clone(),
                 prompt.clone().into(),
                 tools.clone(),
-            )
-            .await;
+            ),
+        )
+        .await
+        else {
+            return Err((timed_out(), prompt, gas));
+        };
         match response {
             Ok(BarkResponse::Chat { mut choices, usage }) => {
                 if let Some(gas) = &mut gas {
@@ -145,26 +207,68 @@ pub async fn powered_chat<TC: ToolCaller>(
                     role: BarkRole::Assistant,
                     content: BarkContent::Text(value),
                 });
-                return Ok((response.value, messages, BarkState::Complete, gas));
+                return Ok((response.value, None, messages, BarkState::Complete, gas));
             }
             Ok(BarkResponse::ToolCalls { calls, usage }) => {
                 if let Some(gas) = &mut gas {
                     *gas = *gas - usage.unwrap_or(1000) as i32;
                 }
                 let mut messages = prompt.clone();
+                if let Some(final_call) = limits.final_answer.as_ref().and_then(|final_answer| {
+                    calls
+                        .iter()
+                        .find(|call| call.function_name == final_answer.name)
+                }) {
+                    messages.push(BarkMessage {
+                        role: BarkRole::Assistant,
+                        content: BarkContent::ToolCall(final_call.clone()),
+                    });
+                    let arguments = final_call.arguments.clone().unwrap_or("{}".to_string());
+                    return match serde_json::from_str::<FinalAnswer>(&arguments) {
+                        Ok(answer) => {
+                            Ok((arguments, Some(answer), messages, BarkState::Complete, gas))
+                        }
+                        Err(e) => Err((
+                            format!("Final answer arguments are not a JSON object: {}", e),
+                            messages,
+                            gas,
+                        )),
+                    };
+                }
+                if let Some(max_tool_turns) = limits.max_tool_turns {
+                    if tool_turns >= max_tool_turns {
+                        return Err((
+                            format!("Agent exceeded maximum tool turns ({})", max_tool_turns),
+                            prompt,
+                            gas,
+                        ));
+                    }
+                }
+                tool_turns += 1;
                 for call in &calls {
                     messages.push(BarkMessage {
                         role: BarkRole::Assistant,
                         content: BarkContent::ToolCall(call.clone()),
                     });
-                    match model.clone().call_tool(&call, &prompt).await {
-                        Ok(BarkToolCallResponse { id, result, .. }) => {
+                    let Some(result) = before_deadline(
+                        deadline,
+                        model.clone().call_tool(&call, &prompt, &context),
+                    )
+                    .await
+                    else {
+                        return Err((timed_out(), messages, gas));
+                    };
+                    match result {
+                        Ok(BarkToolCallResponse {
+                            id, result, images, ..
+                        }) => {
                             if let Some(result) = result {
                                 messages.push(BarkMessage {
                                     role: BarkRole::Tool,
                                     content: BarkContent::ToolResponse {
                                         response: result.clone(),
                                         id: id.clone(),
+                                        images,
                                     },
                                 });
                             } else {